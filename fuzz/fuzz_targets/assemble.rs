@@ -0,0 +1,23 @@
+//! Assemble arbitrary bytes with the bundled config, which must give diagnostics rather than
+//! panicking:
+//!
+//!     cargo fuzz run assemble
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate uasm;
+
+use uasm::config::Config;
+use uasm::parse::Parser;
+
+thread_local! {
+    static CONFIG: Config = Config::new_from_str(include_str!("../../config/u0-32.json"))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let source = String::from_utf8_lossy(data);
+    CONFIG.with(|config| {
+        let mut parser = Parser::new(config);
+        let _ = parser.try_parse_str(&source).and_then(|_| parser.try_link());
+    });
+});
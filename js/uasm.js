@@ -0,0 +1,48 @@
+// Bindings for the assembler compiled to WebAssembly by wasm/.
+//
+// That crate doesn't build for wasm32-unknown-unknown with the dependencies as they are. The
+// serde_macros plugin the assembler derives with only builds on 2016 nightlies, which predate the
+// target, and regex 0.1 depends on thread-id 2, which has no implementation for it. The exports
+// have only been built and run natively, on stable Rust 1.95 with serde_derive 1 in place of the
+// plugin. Once the assembler moves to serde_derive and a regex without thread-id,
+//
+//     cd wasm && cargo build --release --target wasm32-unknown-unknown
+//
+// builds wasm/target/wasm32-unknown-unknown/release/uasm_wasm.wasm.
+//
+// Usage:
+//
+//     const uasm = await loadAssembler('uasm.wasm');
+//     const { ok, segments, diagnostics } = uasm.assemble(configJson, source);
+//
+// Errors in the config or source are in the diagnostics (and ok is false). A trap is a bug in the
+// assembler, after which the module must be loaded again.
+//
+// Words are numbers, so words wider than 53 bits lose precision.
+
+export async function loadAssembler(url) {
+  const response = await fetch(url);
+  const { instance } = await WebAssembly.instantiate(await response.arrayBuffer(), {});
+  const wasm = instance.exports;
+  const encoder = new TextEncoder();
+  const decoder = new TextDecoder();
+
+  function pass(text) {
+    const bytes = encoder.encode(text);
+    const ptr = wasm.uasm_alloc(bytes.length);
+    new Uint8Array(wasm.memory.buffer, ptr, bytes.length).set(bytes);
+    return [ptr, bytes.length];
+  }
+
+  function assemble(config, source) {
+    const [configPtr, configLen] = pass(config);
+    const [sourcePtr, sourceLen] = pass(source);
+    const ok = wasm.uasm_assemble(configPtr, configLen, sourcePtr, sourceLen) !== 0;
+    wasm.uasm_free(configPtr, configLen);
+    wasm.uasm_free(sourcePtr, sourceLen);
+    const result = new Uint8Array(wasm.memory.buffer, wasm.uasm_result_ptr(), wasm.uasm_result_len());
+    return { ok, ...JSON.parse(decoder.decode(result)) };
+  }
+
+  return { assemble };
+}
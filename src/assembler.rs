@@ -0,0 +1,101 @@
+use super::config::Config;
+use super::parse::{Parser, Diagnostic};
+use std::fs::File;
+use std::io::Read;
+
+/// An input to assemble, either a file to read or a source in memory.
+enum Input {
+    File(String),
+    Str(String),
+}
+
+/// Assembles and links inputs with a config in one call.
+///
+/// ```ignore
+/// let parser = try!(Assembler::builder()
+///     .config(&config)
+///     .define("DEBUG", 1)
+///     .input_file("boot.s")
+///     .input_str("halt\n")
+///     .assemble());
+/// parser.output(OutputFormat::BigEndian, 0, &mut out);
+/// ```
+pub struct Assembler<'a> {
+    config: Option<&'a Config>,
+    defines: Vec<(String, i64)>,
+    inputs: Vec<Input>,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn builder() -> Self {
+        Assembler {
+            config: None,
+            defines: Vec::new(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// The config to assemble with, which is required.
+    pub fn config(mut self, config: &'a Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Define a constant symbol before any input is parsed.
+    pub fn define(mut self, name: &str, value: i64) -> Self {
+        self.defines.push((name.to_string(), value));
+        self
+    }
+
+    /// Add a file to assemble after the inputs already added.
+    pub fn input_file(mut self, path: &str) -> Self {
+        self.inputs.push(Input::File(path.to_string()));
+        self
+    }
+
+    /// Add a source to assemble after the inputs already added.
+    pub fn input_str(mut self, source: &str) -> Self {
+        self.inputs.push(Input::Str(source.to_string()));
+        self
+    }
+
+    /// Parse every input in order and link them, returning the parser to write the outputs.
+    pub fn assemble(self) -> Result<Parser<'a>, Vec<Diagnostic>> {
+        let config = try!(self.config.ok_or_else(|| {
+            vec![Diagnostic {
+                     line: 0,
+                     message: String::from("Error: No config was given to the assembler."),
+                 }]
+        }));
+        let mut parser = Parser::new(config);
+        for (name, value) in self.defines {
+            parser.define(&name, value);
+        }
+        for input in self.inputs {
+            match input {
+                Input::File(path) => {
+                    let mut source = String::new();
+                    try!(File::open(&path)
+                        .and_then(|mut f| f.read_to_string(&mut source))
+                        .map_err(|e| {
+                            vec![Diagnostic {
+                                     line: 0,
+                                     message: format!("Error: Failed to read input file \"{}\": \
+                                                       {}",
+                                                      path,
+                                                      e),
+                                 }]
+                        }));
+                    parser.set_file(&path);
+                    try!(parser.try_parse_str(&source));
+                }
+                Input::Str(source) => {
+                    parser.set_file("<input>");
+                    try!(parser.try_parse_str(&source));
+                }
+            }
+        }
+        try!(parser.try_link());
+        Ok(parser)
+    }
+}
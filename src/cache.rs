@@ -0,0 +1,82 @@
+use super::parse::Parsed;
+use serde_json::{from_reader, to_writer};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An on-disk cache of parsed inputs keyed by a hash of the assembler version, the config, the
+/// symbols defined on the command line, and the input's name and contents, so unchanged inputs
+/// don't need to be parsed again.
+pub struct Cache {
+    dir: PathBuf,
+    config_hash: u64,
+}
+
+impl Cache {
+    pub fn new(dir: &str, config_filename: &str, defines: &[(String, i64)]) -> Cache {
+        // Without the directory nothing can be stored, which only makes every input a miss.
+        if let Err(e) = fs::create_dir_all(dir) {
+            let _ = writeln!(io::stderr(),
+                             "Warning: Failed to create cache directory \"{}\": {}",
+                             dir,
+                             e);
+        }
+        let mut config = Vec::new();
+        File::open(config_filename)
+            .and_then(|mut f| f.read_to_end(&mut config))
+            .unwrap_or_else(|e| panic!("Error: Failed to read config file for hashing: {}", e));
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        config.hash(&mut hasher);
+        defines.hash(&mut hasher);
+        Cache {
+            dir: PathBuf::from(dir),
+            config_hash: hasher.finish(),
+        }
+    }
+
+    /// Get the key an input with the given name and contents is cached under. The name is part
+    /// of the key since `__FILE__` and the source map depend on it.
+    pub fn key(&self, name: &str, source: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.config_hash.hash(&mut hasher);
+        name.hash(&mut hasher);
+        source.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", key))
+    }
+
+    /// Load a cached parse, treating anything unreadable as a cache miss.
+    pub fn load(&self, key: u64) -> Option<Parsed> {
+        File::open(self.path(key)).ok().and_then(|f| from_reader(f).ok())
+    }
+
+    /// Store a parse, only warning if it can't be since the input can be parsed again. It is
+    /// written to a temporary file which is renamed into place, so other runs never load part of
+    /// it.
+    pub fn store(&self, key: u64, parsed: &Parsed) {
+        let path = self.path(key);
+        let nanoseconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        let temporary = self.dir.join(format!("{:016x}.{:x}.tmp", key, nanoseconds));
+        let result = File::create(&temporary)
+            .map_err(|e| e.to_string())
+            .and_then(|mut file| to_writer(&mut file, parsed).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&temporary, &path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temporary);
+            let _ = writeln!(io::stderr(),
+                             "Warning: Failed to write cache file \"{}\": {}",
+                             path.display(),
+                             e);
+        }
+    }
+}
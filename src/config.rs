@@ -1,7 +1,14 @@
-use serde_json::from_reader;
+use serde_json::{from_reader, from_str, from_value, Value};
 use itertools::Itertools;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::io::BufReader;
+use std::sync::Mutex;
 use regex::Regex;
+use regex_syntax::Expr;
+use super::grammar::{self, Groups, Failure};
+use super::expr::evaluate;
 
 fn feedback_default_negate() -> bool {
     false
@@ -27,6 +34,49 @@ fn feedback_default_align() -> bool {
     false
 }
 
+fn feedback_default_segment_fill() -> bool {
+    false
+}
+
+fn feedback_default_pattern_words() -> usize {
+    1
+}
+
+fn feedback_default_overflow() -> Overflow {
+    Overflow::Wrap
+}
+
+fn feedback_default_combine() -> Combine {
+    Combine::Add
+}
+
+/// How a value is combined with the word it is inserted into.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Combine {
+    /// Add the value, handling overflow as the feedback says.
+    Add,
+    Or,
+    Xor,
+    /// Clear the bits set in the value.
+    AndNot,
+    /// Replace the bits of the feedback's field with the value, which needs a width.
+    Replace,
+}
+
+/// What happens when adding a value into the field of a word gives more than the field can hold.
+/// Values are taken as unsigned except when wrapping, so negative values only wrap.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Add with 64-bit wrapping, leaving outputs to cut off the bits past the word.
+    Wrap,
+    /// Use the largest value the field can hold instead, which needs a width.
+    Saturate,
+    /// Report an error, which needs a width.
+    Error,
+    /// Combine the value with OR instead of adding it, so nothing carries.
+    Or,
+}
+
 // Default is -1 so that the number specified copies the number this many times and inserts it.
 fn feedback_default_fill_offset() -> isize {
     0
@@ -52,9 +102,54 @@ pub struct NumFeedback {
     /// If this is a fill, are we aligning to a location?
     #[serde(default="feedback_default_align")]
     pub align: bool,
+    /// If this is a fill, use the segment's fill word rather than the base value.
+    #[serde(default="feedback_default_segment_fill")]
+    pub segment_fill: bool,
     /// The offset of the fill amount.
     #[serde(default="feedback_default_fill_offset")]
     pub fill_offset: isize,
+    /// If this is a fill of base values, the amount of them from the index on which are repeated.
+    #[serde(default="feedback_default_pattern_words")]
+    pub pattern_words: usize,
+    /// Set the bit numbered by the value (before shifting) instead of adding the value, like one
+    /// bit per register of a register list.
+    #[serde(default)]
+    pub bit: bool,
+    /// The width in bits of the field the value goes into. The value is negated and cut to the
+    /// field before shifting, so negative values fill only the field with two's complement.
+    /// Values which don't fit are handled like overflowing sums.
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// How the value (after shifting and negating) is combined with the word.
+    #[serde(default="feedback_default_combine")]
+    pub combine: Combine,
+    /// What happens when adding the value overflows the word.
+    #[serde(default="feedback_default_overflow")]
+    pub overflow: Overflow,
+    /// A bit field of the rule's layout which the value is set into in place of `segment`,
+    /// `index`, and `shift`. The value (after negating) must fit in the field.
+    #[serde(default)]
+    pub field: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Rounding {
+    /// Round to the nearest value, with halves away from zero.
+    Nearest,
+    /// Round towards negative infinity.
+    Down,
+    /// Round towards positive infinity.
+    Up,
+    /// Round towards zero.
+    Zero,
+}
+
+fn fixed_default_rounding() -> Rounding {
+    Rounding::Nearest
+}
+
+fn fixed_default_signed() -> bool {
+    true
 }
 
 fn tag_feedback_default_relative() -> bool {
@@ -69,6 +164,39 @@ fn tag_feedback_default_offset() -> isize {
     0
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BankUse {
+    /// The position of the tag in the segment.
+    Position,
+    /// The number of the bank holding the tag, for the bank field of far references.
+    Bank,
+    /// The address of the tag in the bank window, which must be in the bank of the reference.
+    Near,
+    /// The address of the tag in the bank window, from any bank.
+    Far,
+}
+
+fn tag_feedback_default_bank() -> BankUse {
+    BankUse::Position
+}
+
+/// The position a self-reference of a rule adds.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SelfReferenceBase {
+    /// The position the rule's words start at.
+    Start,
+    /// The position after the rule's words.
+    End,
+    /// The position of one of the rule's words, counting from 0.
+    Word(usize),
+    /// The position of the start of the segment, which is its base address in words.
+    SegmentBase,
+}
+
+fn tag_feedback_default_base() -> SelfReferenceBase {
+    SelfReferenceBase::Start
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TagFeedback {
     /// The segment from which to draw the absolute position.
@@ -86,6 +214,218 @@ pub struct TagFeedback {
     /// An offset to add.
     #[serde(default="tag_feedback_default_offset")]
     pub offset: isize,
+    /// What is taken from the position of the tag when its segment is split into banks.
+    #[serde(default="tag_feedback_default_bank")]
+    pub bank: BankUse,
+    /// The width in bits of the field the position (with its offset) goes into, which it is cut
+    /// to before shifting like the width of number feedbacks.
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// How the position (after shifting) is combined with the word.
+    #[serde(default="feedback_default_combine")]
+    pub combine: Combine,
+    /// What happens when adding the position overflows the word.
+    #[serde(default="feedback_default_overflow")]
+    pub overflow: Overflow,
+    /// The position a self-reference adds, since what a relative position is relative to
+    /// differs between architectures. Only self-references have a base.
+    #[serde(default="tag_feedback_default_base")]
+    pub base: SelfReferenceBase,
+    /// A multiple of words the position of the tag must be at (like the start of a page for a
+    /// paged jump), checked when linking.
+    #[serde(default)]
+    pub target_alignment: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Relaxation {
+    /// The name of the rule with the long encoding, which must match everything this rule does.
+    pub rule: String,
+    /// The smallest value the tag references of this rule can hold.
+    pub min: i64,
+    /// The largest value the tag references of this rule can hold.
+    pub max: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Alignment {
+    /// The segment in which the rule's words must start at an aligned position.
+    pub segment: usize,
+    /// The alignment in words.
+    pub words: usize,
+    /// Report an error instead of padding with the segment's fill word.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Layout {
+    /// The segment of the word.
+    #[serde(default)]
+    pub segment: usize,
+    /// The index of the word among the rule's words in the segment.
+    #[serde(default)]
+    pub index: usize,
+    /// The bit fields of the word separated by commas, like `opcode[15:12]=0x3, rd[11:8]=@reg,
+    /// imm[7:0]`. A field with a value always has it, and the others are set by captures using
+    /// the name after `@` or else the field's own name.
+    pub fields: String,
+}
+
+/// A bit field of a word a rule emits.
+#[derive(Debug)]
+pub struct BitField {
+    pub name: String,
+    /// The name captures use for the field in place of its own.
+    pub binding: Option<String>,
+    pub segment: usize,
+    pub index: usize,
+    pub high: u32,
+    pub low: u32,
+}
+
+impl BitField {
+    pub fn bits(&self) -> u32 {
+        self.high - self.low + 1
+    }
+
+    /// The bits of the field in its word.
+    pub fn mask(&self) -> u64 {
+        if self.bits() >= 64 {
+            !0
+        } else {
+            ((1 << self.bits()) - 1) << self.low
+        }
+    }
+
+    /// Whether a value fits in the field as an unsigned or two's complement number.
+    pub fn fits(&self, value: i64) -> bool {
+        let bits = self.bits();
+        // The upper bound is compared unsigned, since it doesn't fit in an i64 for 63 bits.
+        bits >= 64 || value >= -(1 << (bits - 1)) && (value < 0 || (value as u64) < 1 << bits)
+    }
+
+    /// The value cut to the field's width and moved to its bits.
+    pub fn place(&self, value: u64) -> u64 {
+        (value << self.low) & self.mask()
+    }
+}
+
+/// Parse the bit fields of a word layout, giving each with its value if it has one.
+fn parse_layout(layout: &Layout) -> Result<Vec<(BitField, Option<i64>)>, String> {
+    layout.fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (spec, value) = match field.find('=') {
+                Some(i) => (field[..i].trim(), Some(field[i + 1..].trim())),
+                None => (field, None),
+            };
+            let open = try!(spec.find('[')
+                .ok_or_else(|| format!("field \"{}\" has no bits like [7:0]", spec)));
+            let name = spec[..open].trim();
+            if !spec.ends_with(']') {
+                return Err(format!("field \"{}\" has no bits like [7:0]", name));
+            }
+            let range = &spec[open + 1..spec.len() - 1];
+            let bit = |text: &str| {
+                text.trim()
+                    .parse::<u32>()
+                    .map_err(|_| format!("field \"{}\" has invalid bits [{}]", name, range))
+            };
+            let (high, low) = match range.find(':') {
+                Some(i) => (try!(bit(&range[..i])), try!(bit(&range[i + 1..]))),
+                None => (try!(bit(range)), try!(bit(range))),
+            };
+            if high < low {
+                return Err(format!("field \"{}\" has its high bit below its low bit", name));
+            }
+            let (binding, value) = match value {
+                Some(value) if value.starts_with('@') => (Some(value[1..].to_string()), None),
+                Some(value) => (None, Some(try!(evaluate(value, &|_| None)))),
+                None => (None, None),
+            };
+            Ok((BitField {
+                name: name.to_string(),
+                binding: binding,
+                segment: layout.segment,
+                index: layout.index,
+                high: high,
+                low: low,
+            },
+                value))
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ComputedValue {
+    /// An expression whose value is added to a word. Captures are referred to as `$1`, `$2`,
+    /// and so on (evaluated as expressions themselves) and tables are indexed like `name(i)`.
+    pub expression: String,
+    /// The segment of the word to add the value to.
+    pub segment: usize,
+    /// The index of the word to add the value to.
+    pub index: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LengthPrefix {
+    /// The segment the length is emitted to (the string's segment by default).
+    #[serde(default)]
+    pub segment: Option<usize>,
+    /// The amount of words the length takes up, most significant first.
+    #[serde(default="length_prefix_default_words")]
+    pub words: usize,
+}
+
+fn length_prefix_default_words() -> usize {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum PackOrder {
+    /// The first character goes in the most significant bits.
+    HighFirst,
+    /// The first character goes in the least significant bits.
+    LowFirst,
+}
+
+fn packing_default_order() -> PackOrder {
+    PackOrder::HighFirst
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Packing {
+    /// The amount of characters in each word.
+    pub chars_per_word: usize,
+    /// The order of the characters in a word.
+    #[serde(default="packing_default_order")]
+    pub order: PackOrder,
+    /// The code filling the rest of the last word.
+    #[serde(default)]
+    pub pad: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StrCapture {
+    /// The segment the characters are emitted to.
+    pub add_segment: usize,
+    /// The name of an encoding from the config to map characters through instead of using
+    /// their Unicode scalar values.
+    #[serde(default)]
+    pub encoding: Option<String>,
+    /// Emit the amount of characters before them.
+    #[serde(default)]
+    pub length_prefix: Option<LengthPrefix>,
+    /// A word to emit after the characters (e.g. 0 for C strings), which the length prefix
+    /// doesn't count.
+    #[serde(default)]
+    pub terminate: Option<u64>,
+    /// Pack several characters into each word, which splits the word evenly between them.
+    #[serde(default)]
+    pub pack: Option<Packing>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -93,30 +433,270 @@ pub enum Capture {
     Tag {
         feedbacks: Vec<TagFeedback>,
     },
-    Str {
-        add_segment: usize,
-    },
+    Str(StrCapture),
     Num {
         /// The base the number is to be interpreted as.
         base: u32,
         /// All the places the value is inserted in this ruling.
         feedbacks: Vec<NumFeedback>,
+        /// The smallest value allowed.
+        #[serde(default)]
+        min: Option<i64>,
+        /// The largest value allowed.
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    Float {
+        /// The width of the IEEE-754 bit pattern (32 or 64).
+        width: u32,
+        /// All the places the bit pattern is inserted in this ruling.
+        feedbacks: Vec<NumFeedback>,
+    },
+    /// A decimal literal converted to Qm.n fixed point.
+    Fixed {
+        /// The amount of integer bits (m), not including the sign bit.
+        integer_bits: u32,
+        /// The amount of fraction bits (n).
+        fraction_bits: u32,
+        /// If there is a sign bit, in which case negative values are two's complement.
+        #[serde(default="fixed_default_signed")]
+        signed: bool,
+        /// How values between representable ones are rounded.
+        #[serde(default="fixed_default_rounding")]
+        rounding: Rounding,
+        /// All the places the fixed point value is inserted in this ruling.
+        feedbacks: Vec<NumFeedback>,
+    },
+    /// A decimal number encoded as packed BCD, one digit per nibble.
+    Bcd {
+        /// The most digits the field holds (16 by default, which fills 64 bits).
+        #[serde(default)]
+        digits: Option<u32>,
+        /// All the places the BCD value is inserted in this ruling.
+        feedbacks: Vec<NumFeedback>,
+    },
+    /// A list of elements split on a separator (like the registers of `push {r0, r3, r7}`), each
+    /// trimmed, stripped of `prefix` if it starts with it, and handled by the `element` capture.
+    /// Empty elements are skipped.
+    List {
+        separator: String,
+        #[serde(default)]
+        prefix: String,
+        element: Box<Capture>,
+    },
+    /// An operand handled by the first form of the operand of this name in the config which
+    /// matches it, so operands like addressing modes can be shared by rules.
+    Operand {
+        name: String,
     },
+    /// A capture kind registered with the parser under a name.
+    Plugin {
+        name: String,
+        /// Arguments passed to the plugin as they are.
+        #[serde(default)]
+        args: Option<Value>,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OperandForm {
+    /// The regex the whole operand must match, including captures.
+    regex_string: String,
+    #[serde(skip_deserializing)]
+    pub regex: Option<Regex>,
+    /// Values added to the words of the rule using the operand (like the bits of an addressing
+    /// mode), which may leave out words at the end.
+    #[serde(default)]
+    pub segment_values: Vec<Vec<u64>>,
+    /// Capture structs for the capture groups, whose feedbacks refer to the words of the rule.
+    #[serde(default)]
+    pub captures: Vec<Capture>,
+    /// What happens when adding the segment values overflows a word of the rule (saturating and
+    /// erroring at the width of the word's segment).
+    #[serde(default="feedback_default_overflow")]
+    pub overflow: Overflow,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Rule {
+    /// A name for the rule used in diagnostics and debug output.
+    #[serde(default)]
+    name: Option<String>,
+    /// What the rule is for, for references and editors.
+    #[serde(default)]
+    pub doc: Option<String>,
+    /// How the text the rule matches is written (like `addi:VALUE`), which is shown in errors
+    /// about the text.
+    #[serde(default)]
+    pub syntax: Option<String>,
     /// The regex including captures for this rule.
+    #[serde(default)]
     regex_string: String,
     #[serde(skip_deserializing)]
-    pub regex: Option<Regex>,
+    regex: Option<LazyRegex>,
+    /// A grammar expression (see `Config::productions`) matched in place of a regex, whose
+    /// `{...}` captures are handled by the capture structs.
+    #[serde(default)]
+    pub grammar: Option<String>,
+    #[serde(skip_deserializing)]
+    grammar_expr: Option<grammar::Expr>,
+    /// Overrides the config's case sensitivity for this rule.
+    #[serde(default)]
+    pub case_insensitive: Option<bool>,
     /// The unmodified values to be inserted in order into each segment of the output.
     pub segment_values: Vec<Vec<u64>>,
-    /// The additions of the absolute position back into the segment values (relative ignored).
+    /// The additions of the absolute position back into the segment values, which can't be
+    /// relative or have a target alignment.
     #[serde(default)]
     pub self_references: Vec<TagFeedback>,
+    /// The bit fields of the words in `segment_values`, which are added to the words and which
+    /// captures can set by name in place of shifting.
+    #[serde(default)]
+    pub layout: Vec<Layout>,
+    #[serde(skip_deserializing)]
+    pub bit_fields: Vec<BitField>,
     /// Capture structs for handling each capture group.
     pub captures: Vec<Capture>,
+    /// Text used in place of each capture's group when the group doesn't take part in a match,
+    /// in the order of the captures (like "0" for an optional offset). Captures without one are
+    /// skipped when their group is absent, leaving the words as they are.
+    #[serde(default)]
+    pub defaults: Vec<Option<String>>,
+    /// Values computed from the captures after they are handled.
+    #[serde(default)]
+    pub computed: Vec<ComputedValue>,
+    /// Positions the rule's words must start at, which the segments are padded up to.
+    #[serde(default)]
+    pub alignments: Vec<Alignment>,
+    /// A long encoding used in place of this one when linking if the values of this rule's tag
+    /// references (before shifting) don't fit, which moves everything after it. Words after it
+    /// shouldn't depend on their own position except through tag references.
+    #[serde(default)]
+    pub relax: Option<Relaxation>,
+    /// Emit to the segment selected with the Section directive in place of segment 0 (which
+    /// trades places with it), so the same rule can emit code or data to any section.
+    #[serde(default)]
+    pub follows_section: bool,
+    /// Symbols which must be defined (e.g. with `-D`) for the rule to be enabled, so optional
+    /// extensions of the instruction set can be switched on. Matching a disabled rule is an error.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// What the instruction does when simulated, as statements separated by `;`.
+    #[serde(default)]
+    pub semantics: Option<String>,
+}
+
+fn tag_create_default_redefinable() -> bool {
+    false
+}
+
+/// If every match of an expression is anchored at the start of the text (or at the end when not
+/// `start`), looking through groups, flags, and every branch of alternations.
+fn anchored(expr: &Expr, start: bool) -> bool {
+    match *expr {
+        Expr::StartText => start,
+        Expr::EndText => !start,
+        Expr::Group { ref e, .. } => anchored(e, start),
+        Expr::Alternate(ref es) => es.iter().all(|e| anchored(e, start)),
+        Expr::Concat(ref es) => {
+            let mut parts = es.iter().filter(|e| **e != Expr::Empty);
+            let part = if start { parts.next() } else { parts.last() };
+            part.map_or(false, |e| anchored(e, start))
+        }
+        _ => false,
+    }
+}
+
+/// A regex compiled the first time it's used, since compiling every regex of a large config
+/// dominates the startup of small assemblies. Its syntax is checked when it's made.
+#[derive(Debug)]
+pub struct LazyRegex {
+    source: String,
+    regex: Mutex<Option<Regex>>,
+}
+
+impl LazyRegex {
+    /// Check the syntax of a regex, giving it with the amount of capture groups it has.
+    fn new(source: String) -> Result<(LazyRegex, usize), String> {
+        let expr = try!(Expr::parse(&source).map_err(|e| e.to_string()));
+        let regex = LazyRegex {
+            source: source,
+            regex: Mutex::new(None),
+        };
+        Ok((regex, capture_count(&expr)))
+    }
+
+    /// Run a function with the regex, compiling it if it wasn't used yet.
+    pub fn with<T, F: FnOnce(&Regex) -> T>(&self, f: F) -> T {
+        let mut regex = self.regex.lock().unwrap();
+        if regex.is_none() {
+            // The syntax was checked already, so only a regex compiling too big can fail.
+            *regex = Some(Regex::new(&self.source).unwrap_or_else(|e| {
+                panic!("Error: Failed to compile regex \"{}\": {}", self.source, e)
+            }));
+        }
+        f(regex.as_ref().unwrap())
+    }
+}
+
+/// The amount of capture groups in a regex.
+fn capture_count(expr: &Expr) -> usize {
+    match *expr {
+        Expr::Group { ref e, ref i, .. } => i.is_some() as usize + capture_count(e),
+        Expr::Repeat { ref e, .. } => capture_count(e),
+        Expr::Concat(ref es) |
+        Expr::Alternate(ref es) => es.iter().map(capture_count).sum(),
+        _ => 0,
+    }
+}
+
+impl Rule {
+    /// Get the name of the rule, which is its regex or grammar if it wasn't given one.
+    pub fn name(&self) -> &str {
+        self.name.as_ref().map_or_else(|| self.pattern(), |n| &n[..])
+    }
+
+    /// If the rule only matches whole statements, which rules written with a grammar always
+    /// do and regexes do when every way of matching them starts with `^` and ends with `$`.
+    pub fn is_anchored(&self) -> bool {
+        self.grammar.is_some() ||
+        Expr::parse(&self.regex_string)
+            .map(|expr| anchored(&expr, true) && anchored(&expr, false))
+            .unwrap_or(false)
+    }
+
+    /// The regex of the rule compiled, or `None` if it's written with a grammar.
+    pub fn regex(&self) -> Option<&LazyRegex> {
+        self.regex.as_ref()
+    }
+
+    /// The regex or grammar of the rule.
+    fn pattern(&self) -> &str {
+        self.grammar.as_ref().unwrap_or(&self.regex_string)
+    }
+
+    /// The bit field captures set with a name, if the layout has it.
+    pub fn bit_field(&self, name: &str) -> Option<&BitField> {
+        self.bit_fields.iter().find(|f| f.binding.as_ref().unwrap_or(&f.name) == name)
+    }
+
+    /// The text used for a capture (counting from 0) whose group is absent, if it has one.
+    pub fn capture_default(&self, index: usize) -> Option<&str> {
+        self.defaults.get(index).and_then(|d| d.as_ref()).map(|d| &d[..])
+    }
+
+    /// A note on the documented syntax of the rule to add to errors, if it has one.
+    pub fn syntax_note(&self) -> String {
+        self.syntax.as_ref().map_or_else(String::new, |s| format!(" (expected {})", s))
+    }
+
+    /// The letters and digits the text the rule matches always starts with (like `addi` for
+    /// `^addi:(.*)$`), which may be empty.
+    pub fn mnemonic(&self) -> &str {
+        let regex = self.pattern().trim_left_matches(|c| c == '^' || c == '"');
+        let end = regex.find(|c: char| !c.is_alphanumeric()).unwrap_or(regex.len());
+        &regex[..end]
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -125,133 +705,1306 @@ pub struct TagCreateRule {
     regex_string: String,
     #[serde(skip_deserializing)]
     pub regex: Option<Regex>,
+    /// Tags created by this rule may be redefined, and each use binds to the latest definition.
+    #[serde(default="tag_create_default_redefinable")]
+    pub redefinable: bool,
+}
+
+/// The flag to prefix a regex with to match it ignoring case.
+fn case_flag(case_insensitive: bool) -> &'static str {
+    if case_insensitive {
+        "(?i)"
+    } else {
+        ""
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub enum Directive {
+    /// Sets the entry point to the tag in the one capture group.
+    Entry,
+    /// Sets the namespace of subsequent tags to the one capture group (empty for global).
+    Namespace,
+    /// Binds the redefinable symbol in the first capture group to the value of the expression in
+    /// the second capture group.
+    Set,
+    /// Selects the segment given by the expression in the one capture group as the section
+    /// that rules which follow the section emit to.
+    Section,
+    /// Continues emitting words from the end of the region named in the one capture group.
+    Region,
+    /// Continues emitting words from the end of the bank given by the expression in the one
+    /// capture group in the segment of the directive.
+    Bank,
+    /// Emits the value of the expression in the second capture group the amount of times given
+    /// by the expression in the first capture group (like `.fill 16, 0xFF` or `32 dup(0)`).
+    Repeat,
+    /// Reserves the amount of words given by the expression in the first capture group, filled
+    /// with the value of the expression in the second capture group if it matched or with the
+    /// segment's fill words otherwise (like `.space 64` or `.skip 8, 0`).
+    Space,
+    /// Reports the one capture group as a warning of the `directive` category.
+    Warning,
+    /// Checks after linking that the expression in the first capture group isn't 0, failing
+    /// with the message in the second capture group (if it matched) otherwise. Tags in the
+    /// expression are positions in the segment of the directive (like
+    /// `.assert end - start <= 256, "handler too large"`).
+    Assert,
+}
+
+impl Directive {
+    /// The amount of capture groups the directive regex must have.
+    pub fn captures(&self) -> usize {
+        match *self {
+            Directive::Entry | Directive::Namespace | Directive::Section | Directive::Region |
+            Directive::Bank | Directive::Warning => 1,
+            Directive::Set | Directive::Repeat | Directive::Space | Directive::Assert => 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DirectiveRule {
+    /// The regex which captures the arguments of the directive.
+    regex_string: String,
+    #[serde(skip_deserializing)]
+    pub regex: Option<Regex>,
+    /// What the directive does when it is matched.
+    pub directive: Directive,
+    /// The segment words are emitted to by directives which emit words, and which assertions
+    /// take the positions of tags from.
+    #[serde(default)]
+    pub segment: usize,
+}
+
+fn config_default_line_comments() -> Vec<String> {
+    vec![String::from("#")]
+}
+
+fn config_default_anonymous_labels() -> Option<AnonymousLabels> {
+    Some(AnonymousLabels {
+        forward: '+',
+        backward: '-',
+    })
+}
+
+fn config_default_namespace_separator() -> String {
+    String::from("::")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnonymousLabels {
+    /// The character repeated to create or reference a forward anonymous label.
+    pub forward: char,
+    /// The character repeated to create or reference a backward anonymous label.
+    pub backward: char,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Region {
+    /// The name the Region directive selects the region by.
+    pub name: String,
+    /// The position of the first word of the region.
+    pub start: usize,
+    /// The most words the region may hold.
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Banks {
+    /// The amount of words in each bank, which follow each other in the segment.
+    pub size: usize,
+    /// The address the selected bank is seen at.
+    #[serde(default)]
+    pub window: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum ImageField {
+    /// Bytes written as they are (e.g. a magic number).
+    Bytes(Vec<u8>),
+    /// The value of an expression written in `size` bytes, least significant byte first unless
+    /// `big_endian` is set. The expression may use `length` (the bytes of segment data), `words`
+    /// (the words in the segment), `base_address`, `total_length` (the bytes of the whole image
+    /// with its header and trailer), and the checksums `sum` and `xor` (of the data bytes) and
+    /// `crc32` (the CRC-32 of the data).
+    Value {
+        expression: String,
+        size: usize,
+        #[serde(default)]
+        big_endian: bool,
+    },
+    /// Bytes of `fill` up to an offset from the start of the image, so the fields after it are
+    /// at a fixed offset (e.g. 510 for a 0x55 0xAA boot signature ending a 512 byte sector).
+    Pad {
+        offset: usize,
+        #[serde(default)]
+        fill: u8,
+    },
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SegmentConfig {
+    /// A name the segment can be referred to by in place of its index, in the config and on the
+    /// command line.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The maximum amount of words the segment may contain.
+    #[serde(default)]
+    pub max_size: Option<usize>,
+    /// The word used to fill gaps and padding (e.g. 0xFF for flash or a halt opcode).
+    #[serde(default)]
+    pub fill: u64,
+    /// Words repeated to fill gaps and padding instead of the fill word (e.g. a two-word trap
+    /// sequence), starting over at every gap.
+    #[serde(default)]
+    pub fill_pattern: Vec<u64>,
+    /// Another segment this one must have exactly as many words as after linking (e.g. the
+    /// constant store of a control store split horizontally across segments).
+    #[serde(default)]
+    pub same_size_as: Option<usize>,
+    /// If present, the segment is padded with the fill word up to this many words after linking.
+    #[serde(default)]
+    pub image_size: Option<usize>,
+    /// The byte address the segment is loaded at, which it must fit above in the address space.
+    #[serde(default)]
+    pub base_address: u64,
+    /// The width of addresses in bits, so words which would be past the end of the address space
+    /// (e.g. past 64K with 16-bit addresses) are reported instead of wrapping around.
+    #[serde(default)]
+    pub address_bits: Option<u32>,
+    /// Regions placed at fixed positions in the segment, each emitted to from where it was left
+    /// off when it is selected with the Region directive. Words emitted before any region is
+    /// selected start at position 0.
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    /// Splits the segment into banks which are emitted to after being selected with the Bank
+    /// directive.
+    #[serde(default)]
+    pub banks: Option<Banks>,
+    /// The amount of times the segment's data is repeated in outputs (e.g. 4 for a 16K image in
+    /// a 64K ROM whose address lines wrap around), 1 by default.
+    #[serde(default)]
+    pub mirrors: Option<usize>,
+    /// Fields written before the segment's data in little-endian and big-endian outputs (e.g. a
+    /// magic number and the length of the data), which loaders often expect.
+    #[serde(default)]
+    pub header: Vec<ImageField>,
+    /// Fields written after the segment's data in little-endian and big-endian outputs (e.g. a
+    /// checksum or a boot signature).
+    #[serde(default)]
+    pub trailer: Vec<ImageField>,
+}
+
+impl SegmentConfig {
+    /// The words repeated to fill gaps and padding.
+    pub fn fill_words(&self) -> Vec<u64> {
+        if self.fill_pattern.is_empty() {
+            vec![self.fill]
+        } else {
+            self.fill_pattern.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Machine {
+    /// The amount of registers, which start at 0.
+    pub registers: usize,
+    /// The segment holding the instructions, whose positions are the program counter.
+    #[serde(default)]
+    pub program_segment: usize,
+    /// The segment whose words are loaded into memory before running.
+    #[serde(default)]
+    pub memory_segment: Option<usize>,
+    /// The amount of words of memory (the size of the memory segment by default).
+    #[serde(default)]
+    pub memory_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfigTest {
+    /// The name the test is reported under.
+    pub name: String,
+    /// The assembly source to assemble.
+    pub source: String,
+    /// The words every segment is expected to contain after linking.
+    pub segments: Vec<Vec<u64>>,
+}
+
+fn peephole_word_default_mask() -> u64 {
+    !0
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PeepholeWord {
+    /// The value of the bits in the mask.
+    pub value: u64,
+    /// The bits which must match the value.
+    #[serde(default="peephole_word_default_mask")]
+    pub mask: u64,
+    /// The index of an earlier word of the pattern whose bits outside the mask must be the same
+    /// as this word's (e.g. the register of `push x; pop x`).
+    #[serde(default)]
+    pub same_as: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Peephole {
+    /// The segment whose words are rewritten.
+    pub segment: usize,
+    /// The consecutive words to look for. Words with tag references or tags inside them aren't
+    /// rewritten.
+    pub pattern: Vec<PeepholeWord>,
+    /// The words the matched words are replaced with (none to remove them). Everything after
+    /// them moves, so words after them shouldn't depend on their own position except through
+    /// tag references.
+    pub replacement: Vec<u64>,
+}
+
+fn vector_default_words() -> usize {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Vector {
+    /// The segment the slot is in.
+    pub segment: usize,
+    /// The position of the first word of the slot, which the segment is padded up to if it is
+    /// past its end.
+    pub slot: usize,
+    /// The tag whose position is written in the slot, which must be defined.
+    pub tag: String,
+    /// The segment whose position of the tag is written (the segment of the slot by default).
+    #[serde(default)]
+    pub from_segment: Option<usize>,
+    /// The amount of words the position is split over, least significant word first.
+    #[serde(default="vector_default_words")]
+    pub words: usize,
+    /// Put the most significant word of the position first.
+    #[serde(default)]
+    pub big_endian: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Case {
+    Lower,
+    Upper,
+}
+
+fn style_default_indent() -> usize {
+    4
+}
+
+fn style_default_blank_lines() -> usize {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Style {
+    /// The amount of spaces statements are indented by (labels start at the start of the line).
+    #[serde(default="style_default_indent")]
+    pub indent: usize,
+    /// The column (from 0) the words after the first word of a statement are aligned to, or
+    /// none to separate them by one space.
+    #[serde(default)]
+    pub operand_column: Option<usize>,
+    /// The column (from 0) comments after code are aligned to, or none to separate them by one
+    /// space.
+    #[serde(default)]
+    pub comment_column: Option<usize>,
+    /// The case the letters and digits at the start of the first word of a statement are
+    /// converted to, or none to leave them.
+    #[serde(default)]
+    pub case: Option<Case>,
+    /// The most blank lines kept in a row.
+    #[serde(default="style_default_blank_lines")]
+    pub blank_lines: usize,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            indent: style_default_indent(),
+            operand_column: None,
+            comment_column: None,
+            case: None,
+            blank_lines: style_default_blank_lines(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenClass {
+    /// The regex matching a single token of this class.
+    regex_string: String,
+    #[serde(skip_deserializing)]
+    pub regex: Option<Regex>,
+}
+
+/// The version of the config layout this assembler reads, which configs declare in
+/// `config_version`.
+pub const CONFIG_VERSION: u64 = 2;
+
+/// A change to the top-level fields of the config layout.
+pub enum LayoutChange {
+    /// The field with the first name was renamed to the second in the given version. Older
+    /// configs are read with the old name.
+    Renamed(u64, &'static str, &'static str),
+    /// The field's behavior was built into the assembler before the field existed, as the JSON
+    /// value given, which migrating pins so migrated configs keep behaving the same if the
+    /// default changes.
+    BuiltIn(&'static str, &'static str),
+}
+
+/// Every change to the config layout in order, which both reading and migrating configs follow.
+pub const LAYOUT_CHANGES: &'static [LayoutChange] =
+    &[// `#` was always stripped before `line_comments` existed.
+      LayoutChange::BuiltIn("line_comments", r##"["#"]"##),
+      LayoutChange::Renamed(2, "tag_creates", "tag_rules")];
+
+/// Rename the fields a config of the given version uses the old names of, returning a
+/// description of every field renamed. Configs of later versions can't use the old names.
+pub fn rename_fields(json: &mut Value, version: u64) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+    let object = match json.as_object_mut() {
+        Some(object) => object,
+        None => return Ok(changes),
+    };
+    for change in LAYOUT_CHANGES {
+        if let LayoutChange::Renamed(since, old, new) = *change {
+            if !object.contains_key(old) {
+                continue;
+            }
+            if version >= since {
+                return Err(format!("field \"{}\" was replaced by \"{}\" in version {} of the \
+                                   config layout",
+                                   old,
+                                   new,
+                                   since));
+            }
+            if object.contains_key(new) {
+                return Err(format!("the config has both \"{}\" and \"{}\", which replaced it in \
+                                   version {} of the config layout",
+                                   old,
+                                   new,
+                                   since));
+            }
+            let value = object.remove(old).unwrap();
+            object.insert(new.to_string(), value);
+            changes.push(format!("renamed \"{}\" to \"{}\"", old, new));
+        }
+    }
+    Ok(changes)
+}
+
+fn config_default_version() -> u64 {
+    1
+}
+
+/// Check the version a config declares before reading the rest of it, so configs for a newer
+/// layout fail with that rather than with whatever field doesn't match.
+fn check_version(json: &Value) -> Result<u64, String> {
+    let version = match json.pointer("/config_version") {
+        Some(version) => {
+            try!(version.as_u64().ok_or_else(|| {
+                format!("Error: The config version must be a whole number, not {}.", version)
+            }))
+        }
+        None => config_default_version(),
+    };
+    if version == 0 {
+        return Err(String::from("Error: Config version 0 doesn't exist (the first version is 1)."));
+    }
+    if version > CONFIG_VERSION {
+        return Err(format!("Error: The config is for version {} of the config layout, but this \
+                           assembler only reads versions up to {}.",
+                           version,
+                           CONFIG_VERSION));
+    }
+    Ok(version)
+}
+
+/// The keys whose values are segment indices, which may be segment names instead.
+const SEGMENT_KEYS: &'static [&'static str] = &["segment",
+                                                 "add_segment",
+                                                 "from_segment",
+                                                 "program_segment",
+                                                 "memory_segment",
+                                                 "same_size_as"];
+
+fn segment_index(names: &[Option<String>], name: &str) -> Result<usize, String> {
+    names.iter()
+        .position(|n| n.as_ref().map_or(false, |n| n == name))
+        .ok_or_else(|| format!("Error: There is no segment named \"{}\".", name))
+}
+
+fn resolve_in(json: &mut Value, names: &[Option<String>]) -> Result<(), String> {
+    match *json {
+        Value::Object(ref mut map) => {
+            for (key, value) in map.iter_mut() {
+                if SEGMENT_KEYS.contains(&&key[..]) {
+                    let name = value.as_str().map(String::from);
+                    if let Some(name) = name {
+                        *value = from_str(&try!(segment_index(names, &name)).to_string())
+                            .unwrap();
+                        continue;
+                    }
+                }
+                if key == "segment_values" {
+                    // Segment values given by segment name, where the segments left out are empty.
+                    let by_name = match value.as_object() {
+                        Some(words) => {
+                            let mut values = vec![Value::Array(Vec::new()); names.len()];
+                            for (name, words) in words {
+                                values[try!(segment_index(names, name))] = words.clone();
+                            }
+                            Some(values)
+                        }
+                        None => None,
+                    };
+                    if let Some(values) = by_name {
+                        *value = Value::Array(values);
+                        continue;
+                    }
+                }
+                try!(resolve_in(value, names));
+            }
+        }
+        Value::Array(ref mut values) => {
+            for value in values {
+                try!(resolve_in(value, names));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace the segment names used in place of segment indices with the indices.
+fn resolve_segment_names(json: &mut Value) -> Result<(), String> {
+    let names = json.pointer("/segments")
+        .and_then(Value::as_array)
+        .map_or_else(Vec::new, |segments| {
+            segments.iter()
+                .map(|s| s.pointer("/name").and_then(Value::as_str).map(String::from))
+                .collect::<Vec<_>>()
+        });
+    if names.iter().any(Option::is_some) {
+        try!(resolve_in(json, &names));
+    }
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    /// The version of the config layout the config is written for (1 if it isn't given).
+    #[serde(default="config_default_version")]
+    pub config_version: u64,
+    /// The name of the architecture this config describes.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The version of this config, so outputs can be traced back to the config that made them.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// A description of the architecture or config.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The size of a machine word of the architecture in bits.
+    #[serde(default)]
+    pub word_size: Option<usize>,
     /// The widths of each output segment in octets.
     pub segment_widths: Vec<usize>,
+    /// Per-segment options in the same order as the segment widths (may be omitted entirely).
+    #[serde(default)]
+    pub segments: Vec<SegmentConfig>,
     /// If the regexes are on a word basis
     pub split_whitespace: bool,
-    /// The rule for creating tags.
-    pub tag_creates: Vec<TagCreateRule>,
+    /// The strings which comment out the rest of a line (e.g. ";" or "//").
+    #[serde(default="config_default_line_comments")]
+    pub line_comments: Vec<String>,
+    /// The characters which open and close quoted strings, inside which comments aren't stripped.
+    #[serde(default)]
+    pub quotes: Vec<char>,
+    /// The strings that start and end a block comment, which may span several lines.
+    #[serde(default)]
+    pub block_comment: Option<(String, String)>,
+    /// A string which joins a line with the next one when it ends the line (e.g. "\\").
+    #[serde(default)]
+    pub line_continuation: Option<String>,
+    /// A string which separates several statements on one line (outside of quotes).
+    #[serde(default)]
+    pub statement_separator: Option<String>,
+    /// If not empty, each line is split into tokens of these classes (tried in order) and rules
+    /// match the whole statement with the tokens separated by single spaces.
+    #[serde(default)]
+    pub token_classes: Vec<TokenClass>,
+    /// The characters used for anonymous labels (null disables them). A reference made of N
+    /// forward characters resolves to the nearest following label of N forward characters and
+    /// likewise for backward characters.
+    #[serde(default="config_default_anonymous_labels")]
+    pub anonymous_labels: Option<AnonymousLabels>,
+    /// If the regexes ignore case, in which case tag names are also compared ignoring case.
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// The separator between a namespace and a tag name in qualified tags.
+    #[serde(default="config_default_namespace_separator")]
+    pub namespace_separator: String,
+    /// The tag used as the entry point if the source doesn't declare one.
+    #[serde(default)]
+    pub entry: Option<String>,
+    /// The rules for assembler directives, which are checked before everything else.
+    #[serde(default)]
+    pub directives: Vec<DirectiveRule>,
+    /// The rules for creating tags (`tag_creates` before version 2 of the layout).
+    pub tag_rules: Vec<TagCreateRule>,
     /// The rules for everything else.
     pub rules: Vec<Rule>,
+    /// Character encodings for string captures by name. Each is the characters in order of
+    /// their codes, so the first character is encoded as 0.
+    #[serde(default)]
+    pub encodings: HashMap<String, String>,
+    /// Lookup tables which computed values can index by name.
+    #[serde(default)]
+    pub tables: HashMap<String, Vec<i64>>,
+    /// Operands used by Operand captures by name, each a list of alternative forms tried in
+    /// order.
+    #[serde(default)]
+    pub operands: HashMap<String, Vec<OperandForm>>,
+    /// Named grammar expressions which the grammars of rules and other productions can use by
+    /// name (like `register` for `"r" [0-9]+`). Productions can't have captures.
+    #[serde(default)]
+    pub productions: HashMap<String, String>,
+    #[serde(skip_deserializing)]
+    grammar_productions: HashMap<String, grammar::Expr>,
+    /// The execution model used to simulate programs with the `run` subcommand.
+    #[serde(default)]
+    pub machine: Option<Machine>,
+    /// Small programs with their expected output, run by the `test` subcommand.
+    #[serde(default)]
+    pub tests: Vec<ConfigTest>,
+    /// Rewrites of emitted words made in order before linking, each over a whole segment.
+    #[serde(default)]
+    pub peepholes: Vec<Peephole>,
+    /// Slots written with the positions of tags when linking (e.g. reset and interrupt vectors).
+    #[serde(default)]
+    pub vectors: Vec<Vector>,
+    /// How sources are laid out by the formatter.
+    #[serde(default)]
+    pub style: Style,
+}
+
+/// Check that a feedback's field width is between 1 and 64 bits, and that it has one if it
+/// replaces the field or saturates or errors when adding overflows the field.
+fn check_width(rule: &Rule,
+               width: Option<u32>,
+               combine: Combine,
+               overflow: Overflow)
+               -> Result<(), String> {
+    if width.map_or(false, |w| w == 0 || w > 64) {
+        return Err(format!("Error: Rule \"{}\" has a feedback with a width of {} bits, which must \
+                           be between 1 and 64.",
+                           rule.pattern(),
+                           width.unwrap()));
+    }
+    let checked = overflow == Overflow::Saturate || overflow == Overflow::Error;
+    if width.is_none() && (combine == Combine::Replace || combine == Combine::Add && checked) {
+        return Err(format!("Error: Rule \"{}\" has a feedback which replaces, saturates, or errors \
+                           without a width for its field.",
+                           rule.pattern()));
+    }
+    Ok(())
+}
+
+fn check_num_feedbacks(rule: &Rule,
+                       feedbacks: &[NumFeedback],
+                       segment_counts: &[usize])
+                       -> Result<(), String> {
+    for feedback in feedbacks {
+        try!(check_width(rule, feedback.width, feedback.combine, feedback.overflow));
+        if feedback.field.is_some() && feedback.width.is_some() {
+            return Err(format!("Error: Rule \"{}\" gives the width of a feedback to a field, which \
+                               has its own.",
+                               rule.pattern()));
+        }
+        if let Some(ref name) = feedback.field {
+            if rule.bit_field(name).is_none() {
+                return Err(format!("Error: Rule \"{}\" has no field \"{}\" to set a value into.",
+                                   rule.pattern(),
+                                   name));
+            }
+            if feedback.fill {
+                return Err(format!("Error: Rule \"{}\" fills with field \"{}\", which can only be \
+                                   set.",
+                                   rule.pattern(),
+                                   name));
+            }
+            continue;
+        }
+        let count = *try!(segment_counts.get(feedback.segment)
+            .ok_or_else(|| {
+                format!("Error: Rule \"{}\" attempts to access invalid segment {}.",
+                        rule.pattern(),
+                        feedback.segment)
+            }));
+        if feedback.index >= count {
+            return Err(format!("Error: Rule \"{}\" attempts to access invalid segment value {}:{}.",
+                               rule.pattern(),
+                               feedback.segment,
+                               feedback.index));
+        }
+        if feedback.fill && !feedback.segment_fill &&
+           (feedback.pattern_words == 0 || feedback.index + feedback.pattern_words > count) {
+            return Err(format!("Error: Rule \"{}\" fills with an invalid pattern of {} values from \
+                               {}:{}.",
+                               rule.pattern(),
+                               feedback.pattern_words,
+                               feedback.segment,
+                               feedback.index));
+        }
+    }
+    Ok(())
 }
 
 impl Config {
+    /// Get the key a tag name is stored under, which is lowercase when ignoring case.
+    pub fn tag_key(&self, tag: &str) -> String {
+        if self.case_insensitive {
+            tag.to_lowercase()
+        } else {
+            tag.to_string()
+        }
+    }
+
+    /// Describe the config by its name and version for output that should be traceable to it.
+    pub fn identity(&self) -> String {
+        let mut identity = self.name.clone().unwrap_or_else(|| String::from("unnamed config"));
+        if let Some(ref version) = self.version {
+            identity.push_str(" version ");
+            identity.push_str(version);
+        }
+        identity
+    }
+
+    /// Describe a segment by its name in quotes, or by its index if it has no name.
+    pub fn segment_name(&self, index: usize) -> String {
+        match self.segments.get(index).and_then(|s| s.name.as_ref()) {
+            Some(name) => format!("\"{}\"", name),
+            None => index.to_string(),
+        }
+    }
+
+    /// Match a statement with a rule, giving the text of its groups. Grammar rules which don't
+    /// match give where they stopped matching.
+    pub fn rule_groups<'t>(&self,
+                           rule: &Rule,
+                           text: &'t str)
+                           -> Result<Groups<'t>, Option<Failure>> {
+        match rule.grammar_expr {
+            Some(ref expr) => {
+                grammar::match_text(expr,
+                                    &self.grammar_productions,
+                                    rule.captures.len(),
+                                    text,
+                                    rule.case_insensitive.unwrap_or(self.case_insensitive))
+                    .map_err(Some)
+            }
+            None => {
+                rule.regex
+                    .as_ref()
+                    .unwrap()
+                    .with(|regex| regex.captures(text).map(|caps| Groups::from_captures(&caps)))
+                    .ok_or(None)
+            }
+        }
+    }
+
+    /// Find a segment by its name or index, as given on the command line.
+    pub fn find_segment(&self, segment: &str) -> Option<usize> {
+        self.segments
+            .iter()
+            .position(|s| s.name.as_ref().map_or(false, |n| n == segment))
+            .or_else(|| segment.parse::<usize>().ok())
+            .and_then(|index| if index < self.segment_widths.len() { Some(index) } else { None })
+    }
+
+    /// Parse and check a config from a JSON string.
+    pub fn new_from_str(json: &str) -> Config {
+        Config::try_from_str(json).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Parse and check a config from a JSON string, giving the error if it's invalid.
+    pub fn try_from_str(json: &str) -> Result<Config, String> {
+        Config::new_from_json(try!(from_str(json)
+            .map_err(|e| format!("Error: Failed to parse file to JSON: {}", e))))
+    }
+
     pub fn new_from_filename(filename: &str) -> Config {
-        // Open file and parse JSON into a Config
-        let mut config: Self = from_reader(File::open(filename)
-                .unwrap_or_else(|e| panic!("Error: Failed to open config file: {}", e)))
-            .unwrap_or_else(|e| panic!("Error: Failed to parse file to JSON: {}", e));
+        // Open file and parse JSON into a Config, buffered since the JSON is read a byte at a time
+        Config::new_from_json(from_reader(BufReader::new(File::open(filename)
+                .unwrap_or_else(|e| panic!("Error: Failed to open config file: {}", e))))
+            .unwrap_or_else(|e| panic!("Error: Failed to parse file to JSON: {}", e)))
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn new_from_json(mut json: Value) -> Result<Config, String> {
+        let version = try!(check_version(&json));
+        try!(rename_fields(&mut json, version)
+            .map_err(|e| format!("Error: Invalid config: {}.", e)));
+        try!(resolve_segment_names(&mut json));
+        let mut config: Self = try!(from_value(json).map_err(|e| {
+            format!("Error: Failed to read the config as version {} of the config layout: {}",
+                    version,
+                    e)
+        }));
 
         // Check the config to provide error feedback
-        config.consistency_check();
+        try!(config.consistency_check());
 
-        config
+        Ok(config)
     }
 
-    pub fn consistency_check(&mut self) {
+    pub fn consistency_check(&mut self) -> Result<(), String> {
+        if self.word_size == Some(0) {
+            return Err(String::from("Error: A word size of 0 is not allowed."));
+        }
         for width in &self.segment_widths {
             if *width == 0 {
-                panic!("Error: A segment width of 0 is not allowed.");
+                return Err(String::from("Error: A segment width of 0 is not allowed."));
+            }
+        }
+        if let Some(ref al) = self.anonymous_labels {
+            if al.forward == al.backward {
+                return Err(String::from("Error: Forward and backward anonymous labels must use \
+                                         different characters."));
+            }
+        }
+        if self.segments.is_empty() {
+            self.segments = self.segment_widths.iter().map(|_| SegmentConfig::default()).collect();
+        } else if self.segments.len() != self.segment_widths.len() {
+            return Err(format!("Error: There are {} segment configs, but {} segment widths.",
+                               self.segments.len(),
+                               self.segment_widths.len()));
+        }
+        let mut names = self.segments.iter().filter_map(|s| s.name.as_ref()).collect::<Vec<_>>();
+        let named = names.len();
+        names.sort();
+        names.dedup();
+        if names.len() != named {
+            return Err(String::from("Error: Two segments have the same name."));
+        }
+        if self.segments
+            .iter()
+            .any(|sc| sc.address_bits.map_or(false, |bits| bits == 0 || bits > 64)) {
+            return Err(String::from("Error: Address widths must be between 1 and 64 bits."));
+        }
+        for field in self.segments.iter().flat_map(|sc| sc.header.iter().chain(&sc.trailer)) {
+            if let ImageField::Value { ref expression, size, .. } = *field {
+                if size == 0 || size > 8 {
+                    return Err(format!("Error: The field \"{}\" must be between 1 and 8 \
+                                       bytes.", expression));
+                }
+            }
+        }
+        for (index, sc) in self.segments.iter().enumerate() {
+            if let Some(other) = sc.same_size_as {
+                if other >= self.segments.len() || other == index {
+                    return Err(format!("Error: Segment {} must be the same size as segment {}, \
+                                       which isn't another segment.",
+                                       index,
+                                       other));
+                }
+            }
+        }
+        if self.segments.iter().any(|sc| sc.mirrors == Some(0)) {
+            return Err(String::from("Error: A segment must be mirrored at least once."));
+        }
+        if self.segments.iter().any(|sc| sc.banks.as_ref().map_or(false, |b| b.size == 0)) {
+            return Err(String::from("Error: Banks must hold at least one word."));
+        }
+        {
+            let mut names = HashSet::new();
+            for region in self.segments.iter().flat_map(|sc| &sc.regions) {
+                if !names.insert(&region.name) {
+                    return Err(format!("Error: Region \"{}\" is declared more than \
+                                       once.", region.name));
+                }
+            }
+        }
+        if self.split_whitespace && !self.token_classes.is_empty() {
+            return Err(String::from("Error: Token classes can't be used when splitting on \
+                                     whitespace."));
+        }
+        if self.line_comments.iter().any(|delimiter| delimiter.is_empty()) {
+            return Err(String::from("Error: Line comment delimiters can't be empty."));
+        }
+        if self.statement_separator.as_ref().map_or(false, |s| s.is_empty()) {
+            return Err(String::from("Error: The statement separator can't be empty."));
+        }
+        if let Some((ref start, ref end)) = self.block_comment {
+            if start.is_empty() || end.is_empty() {
+                return Err(String::from("Error: Block comment delimiters can't be empty."));
             }
         }
-        for tc in &mut self.tag_creates {
-            tc.regex = Some(Regex::new(&tc.regex_string)
-                .unwrap_or_else(|e| panic!("Error: Failed to parse tag create regex: {}", e)));
+        for tc in &mut self.token_classes {
+            // Anchor the regex so it only matches at the current position.
+            tc.regex = Some(try!(Regex::new(&format!("{}^(?:{})",
+                                                case_flag(self.case_insensitive),
+                                                tc.regex_string))
+                .map_err(|e| format!("Error: Failed to parse token class regex: {}", e))));
+        }
+        for (name, forms) in &mut self.operands {
+            for form in forms {
+                // Anchor the regex so it matches the whole operand.
+                form.regex = Some(try!(Regex::new(&format!("{}^(?:{})$",
+                                                    case_flag(self.case_insensitive),
+                                                    form.regex_string))
+                    .map_err(|e| format!("Error: Failed to parse operand regex: {}", e))));
+                if form.regex.as_ref().unwrap().captures_len() - 1 != form.captures.len() {
+                    return Err(format!("Error: A form of operand \"{}\" has a different amount of \
+                                       capture structs than its regex has captures.",
+                                       name));
+                }
+            }
+        }
+        for dr in &mut self.directives {
+            dr.regex = Some(try!(Regex::new(&(case_flag(self.case_insensitive).to_string() +
+                                         &dr.regex_string))
+                .map_err(|e| format!("Error: Failed to parse directive regex: {}", e))));
+            if dr.regex.as_ref().unwrap().captures_len() - 1 != dr.directive.captures() {
+                return Err(format!("Error: Directive \"{}\" must have exactly {} capture group(s).",
+                                   dr.regex_string,
+                                   dr.directive.captures()));
+            }
+            if dr.segment >= self.segment_widths.len() {
+                return Err(format!("Error: Directive \"{}\" emits to an invalid segment {}.",
+                                   dr.regex_string,
+                                   dr.segment));
+            }
+            if let Directive::Bank = dr.directive {
+                if self.segments[dr.segment].banks.is_none() {
+                    return Err(format!("Error: Directive \"{}\" selects banks of segment {}, which \
+                                       has no banks.",
+                                       dr.regex_string,
+                                       dr.segment));
+                }
+            }
+        }
+        for tc in &mut self.tag_rules {
+            tc.regex = Some(try!(Regex::new(&(case_flag(self.case_insensitive).to_string() +
+                                         &tc.regex_string))
+                .map_err(|e| format!("Error: Failed to parse tag create regex: {}", e))));
             if tc.regex.as_ref().unwrap().captures_len() != 2 {
-                panic!("Error: The tag create regex must always have one capture group for the \
-                        tag.");
+                return Err(String::from("Error: The tag create regex must always have one capture \
+                                         group for the tag."));
             }
         }
 
+        for peephole in &self.peepholes {
+            if peephole.segment >= self.segment_widths.len() {
+                return Err(format!("Error: A peephole rewrites invalid segment \
+                                   {}.", peephole.segment));
+            }
+            if peephole.pattern.is_empty() {
+                return Err(format!("Error: A peephole in segment {} has an empty pattern.",
+                                   self.segment_name(peephole.segment)));
+            }
+            for (index, word) in peephole.pattern.iter().enumerate() {
+                if word.same_as.map_or(false, |other| other >= index) {
+                    return Err(format!("Error: A peephole pattern word in segment {} must be the \
+                                       same as a later word.",
+                                       self.segment_name(peephole.segment)));
+                }
+            }
+        }
+        for vector in &self.vectors {
+            if vector.segment >= self.segment_widths.len() ||
+               vector.from_segment.map_or(false, |s| s >= self.segment_widths.len()) {
+                return Err(format!("Error: The vector for \"{}\" refers to a segment which doesn't \
+                                   exist.",
+                                   vector.tag));
+            }
+            if vector.words == 0 {
+                return Err(format!("Error: The vector for \"{}\" must have at least one \
+                                   word.", vector.tag));
+            }
+        }
+        if let Some(ref machine) = self.machine {
+            if machine.program_segment >= self.segment_widths.len() ||
+               machine.memory_segment.map_or(false, |s| s >= self.segment_widths.len()) {
+                return Err(String::from("Error: The machine refers to a segment which doesn't \
+                                         exist."));
+            }
+        }
+        for test in &self.tests {
+            if test.segments.len() != self.segment_widths.len() {
+                return Err(format!("Error: Test \"{}\" expects {} segments, but there are {}.",
+                                   test.name,
+                                   test.segments.len(),
+                                   self.segment_widths.len()));
+            }
+        }
+
+        for (name, text) in &self.productions {
+            let (expr, captures) = try!(grammar::parse(text).map_err(|e| {
+                format!("Error: Failed to parse production \"{}\": {}.", name, e)
+            }));
+            if captures != 0 {
+                return Err(format!("Error: Production \"{}\" can't have captures, so capture it \
+                                   where it's used instead.",
+                                   name));
+            }
+            self.grammar_productions.insert(name.clone(), expr);
+        }
+        try!(grammar::check_productions(&self.grammar_productions)
+            .map_err(|e| format!("Error: Invalid productions: {}.", e)));
+        let rule_names = self.rules.iter().map(|r| r.name().to_string()).collect::<HashSet<_>>();
+        let aligned_rule_names = self.rules
+            .iter()
+            .filter(|r| !r.alignments.is_empty())
+            .map(|r| r.name().to_string())
+            .collect::<HashSet<_>>();
         for rule in &mut self.rules {
             let segment_counts = rule.segment_values.iter().map(|v| v.len()).collect_vec();
             if segment_counts.len() != self.segment_widths.len() {
-                panic!("Error: Rule \"{}\" contains an invalid amount of segment values.",
-                       rule.regex_string);
+                return Err(format!("Error: Rule \"{}\" contains an invalid amount of segment \
+                                   values.",
+                                   rule.pattern()));
             }
-            rule.regex = Some(Regex::new(&rule.regex_string)
-                .unwrap_or_else(|e| panic!("Error: Failed to parse regex: {}", e)));
-            if rule.regex.as_ref().unwrap().captures_len() - 1 != rule.captures.len() {
-                panic!("Error: Rule \"{}\" has a different amount of capture structs than its \
-                        regex has captures.",
-                       rule.regex_string);
+            if let Some(ref text) = rule.grammar {
+                if !rule.regex_string.is_empty() {
+                    return Err(format!("Error: Rule \"{}\" has both a regex and a grammar.", text));
+                }
+                let productions = &self.grammar_productions;
+                let (expr, captures) = try!(grammar::parse(text)
+                    .and_then(|(expr, captures)| {
+                        try!(grammar::check_references(&expr, productions));
+                        Ok((expr, captures))
+                    })
+                    .map_err(|e| format!("Error: Failed to parse grammar \"{}\": {}.", text, e)));
+                if captures != rule.captures.len() {
+                    return Err(format!("Error: Rule \"{}\" has a different amount of capture \
+                                       structs than its grammar has captures.",
+                                       text));
+                }
+                rule.grammar_expr = Some(expr);
+            } else {
+                let case_insensitive = rule.case_insensitive.unwrap_or(self.case_insensitive);
+                let (regex, captures) = try!(LazyRegex::new(case_flag(case_insensitive)
+                        .to_string() + &rule.regex_string)
+                    .map_err(|e| format!("Error: Failed to parse regex: {}", e)));
+                rule.regex = Some(regex);
+                if captures != rule.captures.len() {
+                    return Err(format!("Error: Rule \"{}\" has a different amount of capture \
+                                       structs than its regex has captures.",
+                                       rule.regex_string));
+                }
+            }
+            if rule.defaults.len() > rule.captures.len() {
+                return Err(format!("Error: Rule \"{}\" has more capture defaults than captures.",
+                                   rule.pattern()));
+            }
+            for computed in &rule.computed {
+                if computed.segment >= segment_counts.len() ||
+                   computed.index >= segment_counts[computed.segment] {
+                    return Err(format!("Error: Rule \"{}\" attempts to add a computed value to an \
+                                       invalid segment value {}:{}.",
+                                       rule.pattern(),
+                                       computed.segment,
+                                       computed.index));
+                }
+            }
+            if let Some(ref relax) = rule.relax {
+                if !rule_names.contains(&relax.rule) {
+                    return Err(format!("Error: Rule \"{}\" relaxes to rule \"{}\", which doesn't \
+                                       exist.",
+                                       rule.pattern(),
+                                       relax.rule));
+                }
+                if !rule.alignments.is_empty() {
+                    return Err(format!("Error: Rule \"{}\" can't be both relaxable and aligned.",
+                                       rule.pattern()));
+                }
+                if relax.min > relax.max {
+                    return Err(format!("Error: Rule \"{}\" relaxes with a minimum {} greater \
+                                       than its maximum {}.",
+                                       rule.pattern(),
+                                       relax.min,
+                                       relax.max));
+                }
+                if aligned_rule_names.contains(&relax.rule) {
+                    return Err(format!("Error: Rule \"{}\" relaxes to rule \"{}\", which is \
+                                       aligned.",
+                                       rule.pattern(),
+                                       relax.rule));
+                }
+            }
+            for alignment in &rule.alignments {
+                if alignment.segment >= self.segment_widths.len() {
+                    return Err(format!("Error: Rule \"{}\" attempts to align in an invalid segment \
+                                       {}.",
+                                       rule.pattern(),
+                                       alignment.segment));
+                }
+                if alignment.words == 0 {
+                    return Err(format!("Error: Rule \"{}\" has an alignment of 0 words.",
+                                       rule.pattern()));
+                }
             }
             for self_reference in &rule.self_references {
+                try!(check_width(rule,
+                                 self_reference.width,
+                                 self_reference.combine,
+                                 self_reference.overflow));
                 if self_reference.from_segment >= self.segment_widths.len() {
-                    panic!("Error: Rule \"{}\" attempts to self-reference an invalid segment {}.",
-                           rule.regex_string,
-                           self_reference.from_segment);
+                    return Err(format!("Error: Rule \"{}\" attempts to self-reference an invalid \
+                                       segment {}.",
+                                       rule.pattern(),
+                                       self_reference.from_segment));
                 }
                 if self_reference.add_segment >= segment_counts.len() {
-                    panic!("Error: Rule \"{}\" attempts to add a self-reference to an invalid \
-                            segment {}.",
-                           rule.regex_string,
-                           self_reference.add_segment);
+                    return Err(format!("Error: Rule \"{}\" attempts to add a self-reference to an \
+                                       invalid segment {}.",
+                                       rule.pattern(),
+                                       self_reference.add_segment));
                 }
                 if self_reference.add_index >= segment_counts[self_reference.add_segment] {
-                    panic!("Error: Rule \"{}\" attempts to add a self-reference to an invalid \
-                            index {} of segment {}.",
-                           rule.regex_string,
-                           self_reference.add_index,
-                           self_reference.add_segment);
+                    return Err(format!("Error: Rule \"{}\" attempts to add a self-reference to an \
+                                       invalid index {} of segment {}.",
+                                       rule.pattern(),
+                                       self_reference.add_index,
+                                       self_reference.add_segment));
+                }
+                if self_reference.relative || self_reference.target_alignment.is_some() {
+                    return Err(format!("Error: Rule \"{}\" makes a self-reference relative or \
+                                       aligned, which only tag feedbacks can be.",
+                                       rule.pattern()));
+                }
+                if self_reference.bank != BankUse::Position &&
+                   self.segments[self_reference.from_segment].banks.is_none() {
+                    return Err(format!("Error: Rule \"{}\" uses the bank of a self-reference in \
+                                       segment {}, which has no banks.",
+                                       rule.pattern(),
+                                       self_reference.from_segment));
+                }
+                if let SelfReferenceBase::Word(index) = self_reference.base {
+                    if index >= segment_counts[self_reference.from_segment] {
+                        return Err(format!("Error: Rule \"{}\" bases a self-reference on word {} \
+                                           of segment {}, which it doesn't emit.",
+                                           rule.pattern(),
+                                           index,
+                                           self_reference.from_segment));
+                    }
                 }
             }
-            for capture in &rule.captures {
+            for layout in &rule.layout {
+                if layout.segment >= segment_counts.len() ||
+                   layout.index >= segment_counts[layout.segment] {
+                    return Err(format!("Error: Rule \"{}\" lays out invalid segment value {}:{}.",
+                                       rule.pattern(),
+                                       layout.segment,
+                                       layout.index));
+                }
+                let fields = try!(parse_layout(layout).map_err(|e| {
+                    format!("Error: Rule \"{}\" has an invalid layout: {}.", rule.pattern(), e)
+                }));
+                let width = self.segment_widths[layout.segment] * 8;
+                for (field, value) in fields {
+                    if field.high as usize >= min(width, 64) {
+                        return Err(format!("Error: Field \"{}\" of rule \"{}\" doesn't fit in the \
+                                           {}-bit words of segment {}.",
+                                           field.name,
+                                           rule.pattern(),
+                                           width,
+                                           layout.segment));
+                    }
+                    let key = field.binding.as_ref().unwrap_or(&field.name);
+                    if let Some(other) = rule.bit_fields.iter().find(|other| {
+                        other.binding.as_ref().unwrap_or(&other.name) == key ||
+                        other.segment == field.segment && other.index == field.index &&
+                        other.mask() & field.mask() != 0
+                    }) {
+                        return Err(format!("Error: Fields \"{}\" and \"{}\" of rule \"{}\" overlap \
+                                           or have the same name.",
+                                           other.name,
+                                           field.name,
+                                           rule.pattern()));
+                    }
+                    if let Some(value) = value {
+                        if !field.fits(value) {
+                            return Err(format!("Error: Value {} doesn't fit in the {} bits of \
+                                               field \"{}\" of rule \"{}\".",
+                                               value,
+                                               field.bits(),
+                                               field.name,
+                                               rule.pattern()));
+                        }
+                        rule.segment_values[field.segment][field.index] |=
+                            field.place(value as u64);
+                    }
+                    rule.bit_fields.push(field);
+                }
+            }
+            // The elements of lists and the captures of operands are checked like the captures.
+            let mut captures = rule.captures.iter().collect::<Vec<_>>();
+            let mut operands = HashSet::new();
+            while let Some(capture) = captures.pop() {
                 match *capture {
                     Capture::Tag { ref feedbacks } => {
                         for feedback in feedbacks {
                             if feedback.from_segment >= segment_counts.len() {
-                                panic!("Error: Rule \"{}\" attempts to access invalid tag \
-                                        segment {}.",
-                                       rule.regex_string,
-                                       feedback.from_segment);
+                                return Err(format!("Error: Rule \"{}\" attempts to access invalid \
+                                                   tag segment {}.",
+                                                   rule.pattern(),
+                                                   feedback.from_segment));
                             }
                             if feedback.add_segment >= segment_counts.len() {
-                                panic!("Error: Rule \"{}\" attempts to access invalid feedback \
-                                        segment {}.",
-                                       rule.regex_string,
-                                       feedback.add_segment);
+                                return Err(format!("Error: Rule \"{}\" attempts to access invalid \
+                                                   feedback segment {}.",
+                                                   rule.pattern(),
+                                                   feedback.add_segment));
+                            }
+                            if feedback.bank != BankUse::Position &&
+                               self.segments[feedback.from_segment].banks.is_none() {
+                                return Err(format!("Error: Rule \"{}\" uses the bank of a tag in \
+                                                   segment {}, which has no banks.",
+                                                   rule.pattern(),
+                                                   feedback.from_segment));
+                            }
+                            if feedback.bank == BankUse::Bank && feedback.relative {
+                                return Err(format!("Error: Rule \"{}\" uses the bank of a tag \
+                                                   relatively.",
+                                                   rule.pattern()));
+                            }
+                            try!(check_width(rule,
+                                             feedback.width,
+                                             feedback.combine,
+                                             feedback.overflow));
+                            if feedback.base != SelfReferenceBase::Start {
+                                return Err(format!("Error: Rule \"{}\" gives a base to a tag \
+                                                   feedback, which only self-references have.",
+                                                   rule.pattern()));
+                            }
+                            if feedback.target_alignment == Some(0) {
+                                return Err(format!("Error: Rule \"{}\" requires tags to be aligned \
+                                                   to 0 words.",
+                                                   rule.pattern()));
                             }
                             if feedback.add_index >= segment_counts[feedback.add_segment] {
-                                panic!("Error: Rule \"{}\" attempts to access invalid index {} \
-                                        in segment {}.",
-                                       rule.regex_string,
-                                       feedback.add_index,
-                                       feedback.add_segment);
+                                return Err(format!("Error: Rule \"{}\" attempts to access invalid \
+                                                   index {} in segment {}.",
+                                                   rule.pattern(),
+                                                   feedback.add_index,
+                                                   feedback.add_segment));
+                            }
+                        }
+                    }
+                    Capture::Str(ref options) => {
+                        let prefix_segment = options.length_prefix
+                            .as_ref()
+                            .and_then(|p| p.segment)
+                            .unwrap_or(options.add_segment);
+                        for &segment in &[options.add_segment, prefix_segment] {
+                            if segment >= segment_counts.len() {
+                                return Err(format!("Error: Rule \"{}\" attempts to access invalid \
+                                                   segment {}.",
+                                                   rule.pattern(),
+                                                   segment));
+                            }
+                        }
+                        if let Some(ref pack) = options.pack {
+                            if pack.chars_per_word == 0 ||
+                               pack.chars_per_word > self.segment_widths[options.add_segment] * 8 {
+                                return Err(format!("Error: Rule \"{}\" packs an invalid amount of \
+                                                   characters into each word.",
+                                                   rule.pattern()));
+                            }
+                        }
+                        if options.length_prefix.as_ref().map_or(false, |p| p.words == 0) {
+                            return Err(format!("Error: Rule \"{}\" has a length prefix of 0 words.",
+                                               rule.pattern()));
+                        }
+                        if let Some(ref encoding) = options.encoding {
+                            if !self.encodings.contains_key(encoding) {
+                                return Err(format!("Error: Rule \"{}\" uses encoding \"{}\", which \
+                                                   isn't in the config.",
+                                                   rule.pattern(),
+                                                   encoding));
                             }
                         }
                     }
-                    Capture::Str { add_segment } => {
-                        if add_segment >= segment_counts.len() {
-                            panic!("Error: Rule \"{}\" attempts to access invalid segment {}.",
-                                   rule.regex_string,
-                                   add_segment);
+                    Capture::Num { ref feedbacks, min, max, .. } => {
+                        if min.map_or(false, |min| max.map_or(false, |max| min > max)) {
+                            return Err(format!("Error: Rule \"{}\" has a number capture whose \
+                                               minimum is above its maximum.",
+                                               rule.pattern()));
                         }
+                        try!(check_num_feedbacks(rule, feedbacks, &segment_counts));
                     }
-                    Capture::Num { ref feedbacks, .. } => {
-                        for feedback in feedbacks {
-                            let count = *segment_counts.get(feedback.segment)
-                                .unwrap_or_else(|| {
-                                    panic!("Error: Rule \"{}\" attempts to access invalid \
-                                            segment {}.",
-                                           rule.regex_string,
-                                           feedback.segment);
-                                });
-                            if feedback.index >= count {
-                                panic!("Error: Rule \"{}\" attempts to access invalid segment \
-                                        value {}:{}.",
-                                       rule.regex_string,
-                                       feedback.segment,
-                                       feedback.index);
+                    Capture::Fixed { integer_bits, fraction_bits, signed, ref feedbacks, .. } => {
+                        if integer_bits + fraction_bits + if signed { 1 } else { 0 } > 64 {
+                            return Err(format!("Error: Rule \"{}\" has a fixed point capture wider \
+                                               than 64 bits.",
+                                               rule.pattern()));
+                        }
+                        try!(check_num_feedbacks(rule, feedbacks, &segment_counts));
+                    }
+                    Capture::Bcd { digits, ref feedbacks } => {
+                        if digits.map_or(false, |d| d == 0 || d > 16) {
+                            return Err(format!("Error: Rule \"{}\" has a BCD capture which must \
+                                               have between 1 and 16 digits.",
+                                               rule.pattern()));
+                        }
+                        try!(check_num_feedbacks(rule, feedbacks, &segment_counts));
+                    }
+                    Capture::Float { width, ref feedbacks } => {
+                        if width != 32 && width != 64 {
+                            return Err(format!("Error: Rule \"{}\" has a float capture of width \
+                                               {}, which must be 32 or 64.",
+                                               rule.pattern(),
+                                               width));
+                        }
+                        try!(check_num_feedbacks(rule, feedbacks, &segment_counts));
+                    }
+                    Capture::List { ref separator, ref element, .. } => {
+                        if separator.is_empty() {
+                            return Err(format!("Error: Rule \"{}\" has a list capture with an \
+                                               empty separator.",
+                                               rule.pattern()));
+                        }
+                        captures.push(element);
+                    }
+                    Capture::Operand { ref name } => {
+                        let forms = try!(self.operands.get(name).ok_or_else(|| {
+                            format!("Error: Rule \"{}\" uses operand \"{}\", which isn't in the \
+                                     config.",
+                                    rule.pattern(),
+                                    name)
+                        }));
+                        if !operands.insert(name) {
+                            continue;
+                        }
+                        for form in forms {
+                            if form.segment_values.len() > segment_counts.len() ||
+                               form.segment_values
+                                .iter()
+                                .zip(&segment_counts)
+                                .any(|(values, &count)| values.len() > count) {
+                                return Err(format!("Error: Operand \"{}\" has more segment values \
+                                                   than rule \"{}\" which uses it.",
+                                                   name,
+                                                   rule.pattern()));
                             }
+                            captures.extend(&form.captures);
                         }
                     }
+                    // Plugins are registered with the parser, so they can only be checked when
+                    // they are used.
+                    Capture::Plugin { .. } => {}
                 }
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,46 @@
+use super::config::Config;
+use super::hooks::{ParseHook, RuleEvent};
+use super::parse::Parser;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A hook which counts the statements each rule matched.
+pub struct Coverage {
+    matches: Rc<RefCell<Vec<usize>>>,
+}
+
+impl Coverage {
+    /// Count into a list of matches with one entry for each rule of the config.
+    pub fn new(matches: Rc<RefCell<Vec<usize>>>) -> Coverage {
+        Coverage { matches: matches }
+    }
+}
+
+impl ParseHook for Coverage {
+    fn rule(&mut self, event: &RuleEvent) {
+        self.matches.borrow_mut()[event.index] += 1;
+    }
+}
+
+/// Parse the source of every test embedded in the config, counting the statements each rule
+/// matched. Tests which fail still count the statements matched before they failed.
+pub fn cover_tests(config: &Config, matches: &Rc<RefCell<Vec<usize>>>) {
+    for test in &config.tests {
+        let mut parser = Parser::new(config);
+        parser.add_hook(Box::new(Coverage::new(matches.clone())));
+        let _ = parser.try_parse_str(&test.source);
+    }
+}
+
+/// Print every rule which never matched a statement and return the amount of them.
+pub fn report_unmatched(config: &Config, matches: &[usize]) -> usize {
+    let mut unmatched = 0;
+    for (rule, &count) in config.rules.iter().zip(matches) {
+        if count == 0 {
+            unmatched += 1;
+            println!("rule \"{}\" never matched", rule.name());
+        }
+    }
+    println!("{} of {} rules matched", config.rules.len() - unmatched, config.rules.len());
+    unmatched
+}
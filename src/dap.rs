@@ -0,0 +1,563 @@
+use super::config::Config;
+use super::parse::{Parser, Diagnostic};
+use super::sim::{Simulator, Recorder};
+use serde::Serialize;
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+/// How many instructions run for a continue request before pausing, so a program that never
+/// halts doesn't hang the debugger.
+const CONTINUE_LIMIT: usize = 1000000;
+
+#[derive(Deserialize)]
+struct Request {
+    seq: u64,
+    command: String,
+    #[serde(default)]
+    arguments: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct Response<T> {
+    seq: u64,
+    #[serde(rename="type")]
+    kind: &'static str,
+    request_seq: u64,
+    success: bool,
+    command: String,
+    message: Option<String>,
+    body: T,
+}
+
+#[derive(Serialize)]
+struct Event<T> {
+    seq: u64,
+    #[serde(rename="type")]
+    kind: &'static str,
+    event: &'static str,
+    body: T,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+#[derive(Serialize)]
+struct Capabilities {
+    #[serde(rename="supportsConfigurationDoneRequest")]
+    configuration_done: bool,
+    #[serde(rename="supportsFunctionBreakpoints")]
+    function_breakpoints: bool,
+}
+
+#[derive(Serialize)]
+struct Breakpoint {
+    verified: bool,
+    line: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+}
+
+#[derive(Serialize)]
+struct Thread {
+    id: u64,
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+struct Threads {
+    threads: Vec<Thread>,
+}
+
+#[derive(Serialize)]
+struct Source {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct StackFrame {
+    id: u64,
+    name: String,
+    source: Source,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Serialize)]
+struct StackTrace {
+    #[serde(rename="stackFrames")]
+    stack_frames: Vec<StackFrame>,
+    #[serde(rename="totalFrames")]
+    total_frames: usize,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    #[serde(rename="variablesReference")]
+    variables_reference: u64,
+    expensive: bool,
+}
+
+#[derive(Serialize)]
+struct Scopes {
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct Variable {
+    name: String,
+    value: String,
+    #[serde(rename="variablesReference")]
+    variables_reference: u64,
+}
+
+#[derive(Serialize)]
+struct Variables {
+    variables: Vec<Variable>,
+}
+
+#[derive(Serialize)]
+struct Stopped {
+    reason: &'static str,
+    #[serde(rename="threadId")]
+    thread_id: u64,
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Output {
+    category: &'static str,
+    output: String,
+}
+
+#[derive(Serialize)]
+struct Exited {
+    #[serde(rename="exitCode")]
+    exit_code: i64,
+}
+
+const REGISTERS: u64 = 1;
+const MEMORY: u64 = 2;
+
+/// Compare paths the way the editor sees them.
+fn canonical(path: &str) -> String {
+    fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or(path.to_string())
+}
+
+/// A debug adapter which runs the simulator for an editor over the Debug Adapter Protocol.
+struct Adapter<'a, W> {
+    config: &'a Config,
+    output: W,
+    seq: u64,
+    simulator: Option<Simulator>,
+    tags: HashMap<String, usize>,
+    stop_on_entry: bool,
+    // Breakpoint positions by source file and from function (tag) breakpoints.
+    line_breakpoints: HashMap<String, Vec<usize>>,
+    tag_breakpoints: Vec<usize>,
+    breakpoints: HashSet<usize>,
+}
+
+impl<'a, W> Adapter<'a, W>
+    where W: Write
+{
+    fn send<T>(&mut self, message: &T)
+        where T: Serialize
+    {
+        let json = serde_json::to_string(message).unwrap();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", json.len(), json)
+            .and_then(|_| self.output.flush())
+            .unwrap_or_else(|e| panic!("Error: Failed to write to the debugger: {}", e));
+    }
+
+    fn respond<T>(&mut self, request: &Request, result: Result<T, String>)
+        where T: Serialize
+    {
+        self.seq += 1;
+        let (success, message, body) = match result {
+            Ok(body) => (true, None, Some(body)),
+            Err(message) => (false, Some(message), None),
+        };
+        let response = Response {
+            seq: self.seq,
+            kind: "response",
+            request_seq: request.seq,
+            success: success,
+            command: request.command.clone(),
+            message: message,
+            body: body,
+        };
+        self.send(&response);
+    }
+
+    fn event<T>(&mut self, event: &'static str, body: T)
+        where T: Serialize
+    {
+        self.seq += 1;
+        let event = Event {
+            seq: self.seq,
+            kind: "event",
+            event: event,
+            body: body,
+        };
+        self.send(&event);
+    }
+
+    fn stopped(&mut self, reason: &'static str, text: Option<String>) {
+        self.event("stopped",
+                   Stopped {
+                       reason: reason,
+                       thread_id: 1,
+                       text: text,
+                   });
+    }
+
+    /// Assemble the inputs and start a simulator for them.
+    fn launch(&mut self, arguments: &Value) -> Result<Empty, String> {
+        let inputs = try!(arguments.pointer("/inputs")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| String::from("the launch arguments need a list of inputs")));
+        self.stop_on_entry = arguments.pointer("/stopOnEntry")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let mut parser = Parser::new(self.config);
+        parser.add_hook(Box::new(Recorder::new(self.config, records.clone())));
+        let to_string = |diagnostics: Vec<Diagnostic>| {
+            diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n")
+        };
+        for input in inputs {
+            let name = try!(input.as_str().ok_or_else(|| String::from("inputs must be paths")));
+            let mut source = String::new();
+            try!(File::open(name)
+                .and_then(|mut f| f.read_to_string(&mut source))
+                .map_err(|e| format!("failed to read input file \"{}\": {}", name, e)));
+            parser.set_file(&canonical(name));
+            try!(parser.try_parse_str(&source).map_err(|e| to_string(e)));
+        }
+        try!(parser.try_link().map_err(|e| to_string(e)));
+        let program_segment = self.config.machine.as_ref().map_or(0, |m| m.program_segment);
+        self.tags = parser.tags()
+            .iter()
+            .map(|(name, positions)| (name.clone(), positions[program_segment]))
+            .collect();
+        let records = records.borrow().clone();
+        self.simulator = Some(try!(Simulator::new(self.config, &parser, records)));
+        Ok(Empty {})
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.breakpoints = self.line_breakpoints
+            .values()
+            .flat_map(|positions| positions.iter().cloned())
+            .chain(self.tag_breakpoints.iter().cloned())
+            .collect();
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Breakpoints, String> {
+        let path = canonical(try!(arguments.pointer("/source/path")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| String::from("the source has no path"))));
+        let lines = arguments.pointer("/breakpoints")
+            .and_then(|v| v.as_array())
+            .map_or_else(Vec::new, |breakpoints| {
+                breakpoints.iter()
+                    .filter_map(|b| b.pointer("/line").and_then(|v| v.as_u64()))
+                    .collect()
+            });
+        let mut positions = Vec::new();
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            let found = self.simulator
+                .as_ref()
+                .map_or_else(Vec::new, |s| s.positions_of_line(&path, line as usize));
+            breakpoints.push(Breakpoint {
+                verified: !found.is_empty(),
+                line: Some(line as usize),
+            });
+            positions.extend(found);
+        }
+        self.line_breakpoints.insert(path, positions);
+        self.update_breakpoints();
+        Ok(Breakpoints { breakpoints: breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Result<Breakpoints, String> {
+        let names = arguments.pointer("/breakpoints")
+            .and_then(|v| v.as_array())
+            .map_or_else(Vec::new, |breakpoints| {
+                breakpoints.iter()
+                    .filter_map(|b| b.pointer("/name").and_then(|v| v.as_str()))
+                    .map(|name| self.config.tag_key(name))
+                    .collect()
+            });
+        let mut breakpoints = Vec::new();
+        self.tag_breakpoints.clear();
+        for name in names {
+            let position = self.tags.get(&name).cloned();
+            breakpoints.push(Breakpoint {
+                verified: position.is_some(),
+                line: position.and_then(|p| self.simulator.as_ref().and_then(|s| s.line(p))),
+            });
+            self.tag_breakpoints.extend(position);
+        }
+        self.update_breakpoints();
+        Ok(Breakpoints { breakpoints: breakpoints })
+    }
+
+    /// Check that a program was launched before running it.
+    fn launched(&self) -> Result<Empty, String> {
+        if self.simulator.is_some() {
+            Ok(Empty {})
+        } else {
+            Err(String::from("not launched"))
+        }
+    }
+
+    /// Run at most `limit` instructions, stopping early at breakpoints, and tell the editor why
+    /// the simulator stopped.
+    fn run(&mut self, limit: usize) {
+        let mut result = Ok(());
+        let mut steps = 0;
+        let mut printed = Vec::new();
+        {
+            let simulator = self.simulator.as_mut().unwrap();
+            while !simulator.halted && steps < limit {
+                result = simulator.step();
+                printed.extend(simulator.output.drain(..));
+                steps += 1;
+                if result.is_err() || self.breakpoints.contains(&simulator.pc) {
+                    break;
+                }
+            }
+        }
+        for value in printed {
+            self.event("output",
+                       Output {
+                           category: "stdout",
+                           output: format!("{}\n", value),
+                       });
+        }
+        let (halted, pc) = {
+            let simulator = self.simulator.as_ref().unwrap();
+            (simulator.halted, simulator.pc)
+        };
+        if let Err(e) = result {
+            self.stopped("exception", Some(e));
+        } else if halted {
+            self.event("terminated", Empty {});
+            self.event("exited", Exited { exit_code: 0 });
+        } else if self.breakpoints.contains(&pc) && steps != 0 {
+            self.stopped("breakpoint", None);
+        } else if limit == 1 {
+            self.stopped("step", None);
+        } else {
+            self.stopped("pause", None);
+        }
+    }
+
+    fn stack_trace(&self) -> Result<StackTrace, String> {
+        let simulator = try!(self.simulator.as_ref().ok_or_else(|| String::from("not launched")));
+        let pc = simulator.pc;
+        let name = simulator.rule(pc)
+            .map_or_else(|| format!("{}", pc),
+                         |rule| format!("{} ({})", self.config.rules[rule].name(), pc));
+        Ok(StackTrace {
+            stack_frames: vec![StackFrame {
+                                   id: 1,
+                                   name: name,
+                                   source: Source {
+                                       path: simulator.file(pc).unwrap_or("").to_string(),
+                                   },
+                                   line: simulator.line(pc).unwrap_or(0),
+                                   column: 1,
+                               }],
+            total_frames: 1,
+        })
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Variables, String> {
+        let simulator = try!(self.simulator.as_ref().ok_or_else(|| String::from("not launched")));
+        let (prefix, values) = match arguments.pointer("/variablesReference")
+            .and_then(|v| v.as_u64()) {
+            Some(REGISTERS) => ("r", &simulator.registers),
+            Some(MEMORY) => ("m", &simulator.memory),
+            _ => return Err(String::from("unknown variables reference")),
+        };
+        Ok(Variables {
+            variables: values.iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    Variable {
+                        name: format!("{}{}", prefix, i),
+                        value: format!("{} (0x{:x})", value, value),
+                        variables_reference: 0,
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    /// Handle a request, returning false when the session is over.
+    fn handle(&mut self, request: Request) -> bool {
+        let null = Value::Null;
+        let arguments = request.arguments.clone();
+        let arguments = arguments.as_ref().unwrap_or(&null);
+        match &request.command[..] {
+            "initialize" => {
+                self.respond(&request,
+                             Ok(Capabilities {
+                                 configuration_done: true,
+                                 function_breakpoints: true,
+                             }));
+            }
+            "launch" => {
+                let result = self.launch(arguments);
+                let launched = result.is_ok();
+                self.respond(&request, result);
+                if launched {
+                    self.event("initialized", Empty {});
+                }
+            }
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(arguments);
+                self.respond(&request, result);
+            }
+            "setFunctionBreakpoints" => {
+                let result = self.set_function_breakpoints(arguments);
+                self.respond(&request, result);
+            }
+            "configurationDone" => {
+                let result = self.launched();
+                let launched = result.is_ok();
+                self.respond(&request, result);
+                if launched && self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else if launched {
+                    self.run(CONTINUE_LIMIT);
+                }
+            }
+            "threads" => {
+                self.respond(&request,
+                             Ok(Threads {
+                                 threads: vec![Thread {
+                                                   id: 1,
+                                                   name: "main",
+                                               }],
+                             }));
+            }
+            "stackTrace" => {
+                let result = self.stack_trace();
+                self.respond(&request, result);
+            }
+            "scopes" => {
+                self.respond(&request,
+                             Ok(Scopes {
+                                 scopes: vec![Scope {
+                                                  name: "Registers",
+                                                  variables_reference: REGISTERS,
+                                                  expensive: false,
+                                              },
+                                              Scope {
+                                                  name: "Memory",
+                                                  variables_reference: MEMORY,
+                                                  expensive: true,
+                                              }],
+                             }));
+            }
+            "variables" => {
+                let result = self.variables(arguments);
+                self.respond(&request, result);
+            }
+            "continue" => {
+                let result = self.launched();
+                let launched = result.is_ok();
+                self.respond(&request, result);
+                if launched {
+                    self.run(CONTINUE_LIMIT);
+                }
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let result = self.launched();
+                let launched = result.is_ok();
+                self.respond(&request, result);
+                if launched {
+                    self.run(1);
+                }
+            }
+            "disconnect" => {
+                self.respond(&request, Ok(Empty {}));
+                return false;
+            }
+            command => {
+                self.respond::<Empty>(&request,
+                                      Err(format!("\"{}\" isn't supported", command)));
+            }
+        }
+        true
+    }
+}
+
+/// Read one message framed by a Content-Length header.
+fn read_message<R>(input: &mut R) -> Option<String>
+    where R: BufRead
+{
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).unwrap_or(0) == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if header.starts_with("Content-Length:") {
+            length = header["Content-Length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = match length {
+        Some(length) => vec![0; length],
+        None => return None,
+    };
+    if input.read_exact(&mut body).is_err() {
+        return None;
+    }
+    String::from_utf8(body).ok()
+}
+
+/// Serve the Debug Adapter Protocol on `input` and `output` until the editor disconnects.
+/// Instruction steps, line breakpoints, and tag breakpoints (as function breakpoints) are
+/// supported, with the registers and memory of the machine as variables.
+pub fn serve<R, W>(config: &Config, mut input: R, output: W)
+    where R: BufRead,
+          W: Write
+{
+    let mut adapter = Adapter {
+        config: config,
+        output: output,
+        seq: 0,
+        simulator: None,
+        tags: HashMap::new(),
+        stop_on_entry: false,
+        line_breakpoints: HashMap::new(),
+        tag_breakpoints: Vec::new(),
+        breakpoints: HashSet::new(),
+    };
+    while let Some(message) = read_message(&mut input) {
+        let request = match serde_json::from_str::<Request>(&message) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        if !adapter.handle(request) {
+            break;
+        }
+    }
+}
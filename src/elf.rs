@@ -0,0 +1,174 @@
+use super::parse::Source;
+use byteorder::{ByteOrder, BigEndian};
+use std::io::Write;
+
+const EHSIZE: usize = 52;
+const PHENTSIZE: usize = 32;
+const SHENTSIZE: usize = 40;
+
+fn push_u16(v: &mut Vec<u8>, n: u16) {
+    let mut bytes = [0u8; 2];
+    BigEndian::write_u16(&mut bytes, n);
+    v.extend_from_slice(&bytes);
+}
+
+fn push_u32(v: &mut Vec<u8>, n: u32) {
+    let mut bytes = [0u8; 4];
+    BigEndian::write_u32(&mut bytes, n);
+    v.extend_from_slice(&bytes);
+}
+
+fn push_uleb(v: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            v.push(byte);
+            return;
+        }
+        v.push(byte | 0x80);
+    }
+}
+
+fn push_sleb(v: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            v.push(byte);
+            return;
+        }
+        v.push(byte | 0x80);
+    }
+}
+
+/// Build a DWARF 2 `.debug_line` section with a row wherever the source of a word changes.
+fn debug_line(base: u64, width: usize, files: &[String], sources: &[Source]) -> Vec<u8> {
+    // Everything in the header after the header_length field.
+    let mut header = vec![1, 1, (-5i8) as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+    // No include directories.
+    header.push(0);
+    for file in files {
+        header.extend_from_slice(file.as_bytes());
+        header.push(0);
+        // Directory, modification time, and length.
+        header.extend_from_slice(&[0, 0, 0]);
+    }
+    header.push(0);
+
+    let mut program = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    let mut line = 1i64;
+    for (index, source) in sources.iter().enumerate() {
+        if source.line == 0 || current == Some((source.file, source.line)) {
+            continue;
+        }
+        if current.map_or(true, |c| c.0 != source.file) {
+            // DW_LNS_set_file
+            program.push(4);
+            push_uleb(&mut program, source.file as u64 + 1);
+        }
+        // DW_LNE_set_address
+        program.extend_from_slice(&[0, 5, 2]);
+        push_u32(&mut program, (base + (index * width) as u64) as u32);
+        // DW_LNS_advance_line
+        program.push(3);
+        push_sleb(&mut program, source.line as i64 - line);
+        line = source.line as i64;
+        // DW_LNS_copy
+        program.push(1);
+        current = Some((source.file, source.line));
+    }
+    // End the sequence after the last word.
+    program.extend_from_slice(&[0, 5, 2]);
+    push_u32(&mut program, (base + (sources.len() * width) as u64) as u32);
+    program.extend_from_slice(&[0, 1, 1]);
+
+    let mut section = Vec::new();
+    push_u32(&mut section,
+             (2 + 4 + header.len() + program.len()) as u32);
+    // DWARF version 2
+    push_u16(&mut section, 2);
+    push_u32(&mut section, header.len() as u32);
+    section.extend_from_slice(&header);
+    section.extend_from_slice(&program);
+    section
+}
+
+/// Write a big-endian ELF32 executable with the segment's bytes loaded at its base address, DWARF
+/// line information mapping each address to its source line, and a `.comment` naming the config.
+/// The entry point is relative to the base address.
+pub fn write_elf<W>(w: &mut W,
+                    segment: usize,
+                    data: &[u8],
+                    base: u64,
+                    width: usize,
+                    entry: Option<u64>,
+                    comment: &str,
+                    files: &[String],
+                    sources: &[Source])
+    where W: Write
+{
+    let debug_line = debug_line(base, width, files, sources);
+    let shstrtab = b"\0.text\0.debug_line\0.shstrtab\0.comment\0";
+
+    let text_offset = EHSIZE + PHENTSIZE;
+    let debug_line_offset = text_offset + data.len();
+    let shstrtab_offset = debug_line_offset + debug_line.len();
+    let comment_offset = shstrtab_offset + shstrtab.len();
+    let shoff = comment_offset + comment.len() + 1;
+
+    let mut elf = Vec::new();
+    // ELF identification: 32-bit, big-endian, version 1.
+    elf.extend_from_slice(&[0x7F, b'E', b'L', b'F', 1, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // ET_EXEC with no particular machine.
+    push_u16(&mut elf, 2);
+    push_u16(&mut elf, 0);
+    push_u32(&mut elf, 1);
+    push_u32(&mut elf, (base + entry.unwrap_or(0)) as u32);
+    push_u32(&mut elf, EHSIZE as u32);
+    push_u32(&mut elf, shoff as u32);
+    push_u32(&mut elf, 0);
+    push_u16(&mut elf, EHSIZE as u16);
+    push_u16(&mut elf, PHENTSIZE as u16);
+    push_u16(&mut elf, 1);
+    push_u16(&mut elf, SHENTSIZE as u16);
+    push_u16(&mut elf, 5);
+    push_u16(&mut elf, 3);
+
+    // A single readable and executable PT_LOAD segment.
+    for &field in &[1,
+                    text_offset,
+                    base as usize,
+                    base as usize,
+                    data.len(),
+                    data.len(),
+                    5,
+                    1] {
+        push_u32(&mut elf, field as u32);
+    }
+
+    elf.extend_from_slice(data);
+    elf.extend_from_slice(&debug_line);
+    elf.extend_from_slice(shstrtab);
+    elf.extend_from_slice(comment.as_bytes());
+    elf.push(0);
+
+    // Section headers: name, type, flags, addr, offset, size, link, info, addralign, entsize.
+    let sections = [[0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    [1, 1, 6, base as usize, text_offset, data.len(), 0, 0, 1, 0],
+                    [7, 1, 0, 0, debug_line_offset, debug_line.len(), 0, 0, 1, 0],
+                    [19, 3, 0, 0, shstrtab_offset, shstrtab.len(), 0, 0, 1, 0],
+                    [29, 1, 0x30, 0, comment_offset, comment.len() + 1, 0, 0, 1, 1]];
+    for section in &sections {
+        for &field in section {
+            push_u32(&mut elf, field as u32);
+        }
+    }
+
+    w.write_all(&elf).unwrap_or_else(|e| {
+        panic!("Error: Writing to output file for segment {} failed: {}",
+               segment,
+               e);
+    });
+}
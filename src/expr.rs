@@ -0,0 +1,390 @@
+/// Evaluates a constant expression, using `lookup` to get the values of symbols.
+///
+/// Numbers may be decimal or prefixed with `0x`/`0h` for hex and `0b` for binary. The operators
+/// are those of C with its precedence, from lowest to highest: `c ? a : b`, `||`, `&&`, `|`, `^`,
+/// `&`, `==` and `!=`, `<`, `<=`, `>`, and `>=`, `<<` and `>>`, `+` and `-`, `*`, `/`, and `%`,
+/// and the unary `-`, `+`, `~`, and `!`. Comparisons and logical operators give 1 or 0, `>>`
+/// keeps the sign, and all arithmetic wraps. Parentheses group as usual. A `:` right after a
+/// symbol is part of the symbol, so it needs a space before it in conditionals.
+///
+/// The built-in functions are `lo(x)` and `hi(x)` for the low and high bytes of 16 bits,
+/// `defined(SYMBOL)` for 1 if a symbol is defined and 0 otherwise, `strlen("text")` for the
+/// amount of characters in a string, `align(x, n)` for `x` rounded up to a multiple of `n`, and
+/// `min` and `max` of one or more values.
+pub fn evaluate<F>(expression: &str, lookup: &F) -> Result<i64, String>
+    where F: Fn(&str) -> Option<i64>
+{
+    evaluate_with_functions(expression, lookup, &|_: &str, _: &[i64]| None)
+}
+
+/// Evaluates a constant expression like `evaluate`, also allowing calls like `name(a, b)` which
+/// are given to `call` with the values of their arguments. `call` returns `None` if there is no
+/// such function.
+pub fn evaluate_with_functions<F, G>(expression: &str, lookup: &F, call: &G) -> Result<i64, String>
+    where F: Fn(&str) -> Option<i64>,
+          G: Fn(&str, &[i64]) -> Option<Result<i64, String>>
+{
+    let mut evaluator = Evaluator {
+        chars: expression.chars().collect(),
+        pos: 0,
+        lookup: lookup,
+        call: call,
+    };
+    let value = try!(evaluator.conditional());
+    match evaluator.peek() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected character '{}' in expression \"{}\"", c, expression)),
+    }
+}
+
+/// Characters that may appear in a symbol name after the first character.
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == ':' || c == '$'
+}
+
+struct Evaluator<'a, F: 'a, G: 'a> {
+    chars: Vec<char>,
+    pos: usize,
+    lookup: &'a F,
+    call: &'a G,
+}
+
+impl<'a, F, G> Evaluator<'a, F, G>
+    where F: Fn(&str) -> Option<i64>,
+          G: Fn(&str, &[i64]) -> Option<Result<i64, String>>
+{
+    /// Skip whitespace and return the next character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' in expression", c))
+        }
+    }
+
+    /// Parse the rest of `defined(SYMBOL)` after the opening parenthesis.
+    fn defined(&mut self) -> Result<i64, String> {
+        self.peek();
+        let start = self.pos;
+        while self.pos < self.chars.len() && is_symbol_char(self.chars[self.pos]) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(String::from("expected a symbol in defined()"));
+        }
+        let symbol: String = self.chars[start..self.pos].iter().cloned().collect();
+        try!(self.expect(')'));
+        Ok((self.lookup)(&symbol).is_some() as i64)
+    }
+
+    /// Parse the rest of `strlen("text")` after the opening parenthesis, where `\` escapes the
+    /// next character.
+    fn strlen(&mut self) -> Result<i64, String> {
+        try!(self.expect('"'));
+        let mut length = 0;
+        loop {
+            match self.chars.get(self.pos).cloned() {
+                Some('"') => break,
+                Some('\\') => self.pos += 2,
+                Some(_) => self.pos += 1,
+                None => return Err(String::from("unterminated string in strlen()")),
+            }
+            length += 1;
+        }
+        self.pos += 1;
+        try!(self.expect(')'));
+        Ok(length)
+    }
+
+    /// Consume an operator if it is next and isn't the start of a longer operator.
+    fn operator(&mut self, op: &str, not_followed_by: &[char]) -> bool {
+        self.peek();
+        let len = op.chars().count();
+        let matches = self.chars[self.pos..].iter().take(len).cloned().eq(op.chars()) &&
+                      self.chars.get(self.pos + len).map_or(true, |c| !not_followed_by.contains(c));
+        if matches {
+            self.pos += len;
+        }
+        matches
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = try!(self.logical_or());
+        if !self.operator("?", &[]) {
+            return Ok(condition);
+        }
+        let then = try!(self.conditional());
+        try!(self.expect(':'));
+        let otherwise = try!(self.conditional());
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    fn logical_or(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.logical_and());
+        while self.operator("||", &[]) {
+            let rhs = try!(self.logical_and());
+            value = (value != 0 || rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.bitwise_or());
+        while self.operator("&&", &[]) {
+            let rhs = try!(self.bitwise_or());
+            value = (value != 0 && rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn bitwise_or(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.bitwise_xor());
+        while self.operator("|", &['|']) {
+            value |= try!(self.bitwise_xor());
+        }
+        Ok(value)
+    }
+
+    fn bitwise_xor(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.bitwise_and());
+        while self.operator("^", &[]) {
+            value ^= try!(self.bitwise_and());
+        }
+        Ok(value)
+    }
+
+    fn bitwise_and(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.equality());
+        while self.operator("&", &['&']) {
+            value &= try!(self.equality());
+        }
+        Ok(value)
+    }
+
+    fn equality(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.relational());
+        loop {
+            if self.operator("==", &[]) {
+                value = (value == try!(self.relational())) as i64;
+            } else if self.operator("!=", &[]) {
+                value = (value != try!(self.relational())) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn relational(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.shift());
+        loop {
+            if self.operator("<=", &[]) {
+                value = (value <= try!(self.shift())) as i64;
+            } else if self.operator(">=", &[]) {
+                value = (value >= try!(self.shift())) as i64;
+            } else if self.operator("<", &['<']) {
+                value = (value < try!(self.shift())) as i64;
+            } else if self.operator(">", &['>']) {
+                value = (value > try!(self.shift())) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn shift(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.additive());
+        loop {
+            let left = if self.operator("<<", &[]) {
+                true
+            } else if self.operator(">>", &[]) {
+                false
+            } else {
+                return Ok(value);
+            };
+            let amount = try!(self.additive());
+            if amount < 0 {
+                return Err(format!("negative shift amount {} in expression", amount));
+            }
+            // Amounts of 64 or more shift every bit out, which casting them could hide.
+            let amount = if amount >= 64 { 64 } else { amount as u32 };
+            value = if left {
+                value.checked_shl(amount).unwrap_or(0)
+            } else {
+                value.checked_shr(amount).unwrap_or(if value < 0 { -1 } else { 0 })
+            };
+        }
+    }
+
+    fn additive(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.multiplicative());
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.pos += 1;
+                    value = value.wrapping_add(try!(self.multiplicative()));
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    value = value.wrapping_sub(try!(self.multiplicative()));
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<i64, String> {
+        let mut value = try!(self.unary());
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    value = value.wrapping_mul(try!(self.unary()));
+                }
+                Some(c) if c == '/' || c == '%' => {
+                    self.pos += 1;
+                    let rhs = try!(self.unary());
+                    if rhs == 0 {
+                        return Err(String::from("division by zero in expression"));
+                    }
+                    value = if c == '/' {
+                        value.wrapping_div(rhs)
+                    } else {
+                        value.wrapping_rem(rhs)
+                    };
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(try!(self.unary()).wrapping_neg())
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            Some('~') => {
+                self.pos += 1;
+                Ok(!try!(self.unary()))
+            }
+            Some('!') => {
+                self.pos += 1;
+                Ok((try!(self.unary()) == 0) as i64)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = try!(self.conditional());
+                try!(self.expect(')'));
+                Ok(value)
+            }
+            Some(c) if c.is_digit(10) => {
+                let start = self.pos;
+                while self.pos < self.chars.len() && self.chars[self.pos].is_alphanumeric() {
+                    self.pos += 1;
+                }
+                let literal: String = self.chars[start..self.pos].iter().cloned().collect();
+                parse_number(&literal)
+            }
+            Some(c) if is_symbol_char(c) => {
+                let start = self.pos;
+                while self.pos < self.chars.len() && is_symbol_char(self.chars[self.pos]) {
+                    self.pos += 1;
+                }
+                let symbol: String = self.chars[start..self.pos].iter().cloned().collect();
+                if self.peek() == Some('(') {
+                    self.pos += 1;
+                    match &symbol[..] {
+                        "defined" => return self.defined(),
+                        "strlen" => return self.strlen(),
+                        _ => {}
+                    }
+                    let mut args = Vec::new();
+                    if self.peek() == Some(')') {
+                        self.pos += 1;
+                    } else {
+                        loop {
+                            args.push(try!(self.conditional()));
+                            if self.peek() == Some(',') {
+                                self.pos += 1;
+                            } else {
+                                try!(self.expect(')'));
+                                break;
+                            }
+                        }
+                    }
+                    return (self.call)(&symbol, &args)
+                        .or_else(|| builtin(&symbol, &args))
+                        .unwrap_or_else(|| Err(format!("function \"{}\" is not defined", symbol)));
+                }
+                (self.lookup)(&symbol).ok_or_else(|| format!("symbol \"{}\" is not defined", symbol))
+            }
+            Some(c) => Err(format!("unexpected character '{}' in expression", c)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+/// Calls a built-in function, returning `None` if there is no such function.
+fn builtin(name: &str, args: &[i64]) -> Option<Result<i64, String>> {
+    let arity = |count: usize| if args.len() == count {
+        Ok(())
+    } else {
+        Err(format!("function \"{}\" takes {} argument(s), not {}", name, count, args.len()))
+    };
+    Some(match name {
+        "lo" => arity(1).map(|_| args[0] & 0xFF),
+        "hi" => arity(1).map(|_| (args[0] >> 8) & 0xFF),
+        "align" => {
+            arity(2).and_then(|_| if args[1] <= 0 {
+                Err(format!("can't align to {}", args[1]))
+            } else {
+                // Round towards positive infinity for negative values too.
+                let misalignment = args[0].wrapping_rem(args[1]);
+                Ok(if misalignment > 0 {
+                    args[0].wrapping_add(args[1] - misalignment)
+                } else {
+                    args[0].wrapping_sub(misalignment)
+                })
+            })
+        }
+        "min" => {
+            args.iter().cloned().min().ok_or_else(|| String::from("min() takes at least one value"))
+        }
+        "max" => {
+            args.iter().cloned().max().ok_or_else(|| String::from("max() takes at least one value"))
+        }
+        _ => return None,
+    })
+}
+
+/// Parses a number literal with an optional base prefix.
+pub fn parse_number(literal: &str) -> Result<i64, String> {
+    let (digits, base) = if literal.starts_with("0x") || literal.starts_with("0h") {
+        (&literal[2..], 16)
+    } else if literal.starts_with("0b") {
+        (&literal[2..], 2)
+    } else {
+        (literal, 10)
+    };
+    u64::from_str_radix(digits, base)
+        .map(|v| v as i64)
+        .map_err(|e| format!("invalid number \"{}\": {}", literal, e))
+}
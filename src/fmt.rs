@@ -0,0 +1,123 @@
+use super::config::{Case, Config};
+use super::parse::strip_comments;
+
+/// Split code on whitespace outside of quotes.
+fn fields<'b>(config: &Config, code: &'b str) -> Vec<&'b str> {
+    let mut fields = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start = None;
+    for (i, c) in code.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+        } else if c.is_whitespace() {
+            if let Some(s) = start.take() {
+                fields.push(&code[s..i]);
+            }
+            continue;
+        } else if config.quotes.contains(&c) {
+            quote = Some(c);
+        }
+        if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        fields.push(&code[s..]);
+    }
+    fields
+}
+
+/// Pad a line with spaces up to a column, or by one space if it is already past it.
+fn pad_to(line: &mut String, column: Option<usize>) {
+    let len = line.chars().count();
+    let spaces = column.map_or(1, |c| if c > len { c - len } else { 1 });
+    line.extend((0..spaces).map(|_| ' '));
+}
+
+/// Convert the case of the letters and digits at the start of a word, leaving the rest (like
+/// tag names after them) alone.
+fn convert_case(word: &str, case: Option<Case>) -> String {
+    let end = word.find(|c: char| !c.is_alphanumeric()).unwrap_or(word.len());
+    let head = match case {
+        Some(Case::Lower) => word[..end].to_lowercase(),
+        Some(Case::Upper) => word[..end].to_uppercase(),
+        None => word[..end].to_string(),
+    };
+    head + &word[end..]
+}
+
+/// Lay out a source with the style of the config. Labels start at the start of the line,
+/// statements are indented, and operands and comments are aligned to their columns. Lines in
+/// or around block comments are only stripped of trailing whitespace.
+pub fn format_source(config: &Config, source: &str) -> String {
+    let style = &config.style;
+    let mut out = String::new();
+    let mut in_block = false;
+    let mut blank_lines = 0;
+    for line in source.lines() {
+        let line = line.trim_right();
+        if line.is_empty() {
+            blank_lines += 1;
+            if blank_lines <= style.blank_lines {
+                out.push('\n');
+            }
+            continue;
+        }
+        blank_lines = 0;
+
+        let was_in_block = in_block;
+        let code = strip_comments(config, line, &mut in_block);
+        // Without block comments the code is the line up to its line comment.
+        if was_in_block || in_block || !line.starts_with(code.as_str()) {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let comment = line[code.len()..].trim();
+        let words = fields(config, &code);
+
+        let labels = words.iter()
+            .take_while(|word| {
+                config.tag_rules.iter().any(|tc| tc.regex.as_ref().unwrap().is_match(word))
+            })
+            .count();
+        let mut formatted = words[..labels].join(" ");
+        if let Some((first, operands)) = words[labels..].split_first() {
+            let len = formatted.chars().count();
+            let spaces = if len < style.indent {
+                style.indent - len
+            } else if len == 0 {
+                0
+            } else {
+                1
+            };
+            formatted.extend((0..spaces).map(|_| ' '));
+            formatted.push_str(&convert_case(first, style.case));
+            if !operands.is_empty() {
+                pad_to(&mut formatted, style.operand_column);
+                formatted.push_str(&operands.join(" "));
+            }
+        }
+        if !comment.is_empty() {
+            if words.is_empty() {
+                // Comments on their own line stay at the start or are indented like statements.
+                if line.starts_with(char::is_whitespace) {
+                    formatted.extend((0..style.indent).map(|_| ' '));
+                }
+            } else {
+                pad_to(&mut formatted, style.comment_column);
+            }
+            formatted.push_str(comment);
+        }
+        out.push_str(&formatted);
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::Path;
+
+/// If a name contains any of the special characters of a glob pattern.
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(|c: char| c == '*' || c == '?' || c == '[')
+}
+
+/// Match the characters of a name against a pattern for one path component, where `*` matches
+/// any characters, `?` matches one character, and `[...]` matches one character in a set of
+/// characters and ranges like `a-z` (or not in it if the set starts with `!`).
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') => (0..name.len() + 1).any(|skip| matches(&pattern[1..], &name[skip..])),
+        Some(&'?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some(&'[') => {
+            let end = match pattern.iter().skip(2).position(|&c| c == ']') {
+                Some(end) => end + 2,
+                // A bracket which isn't closed is matched literally.
+                None => return name.first() == Some(&'[') && matches(&pattern[1..], &name[1..]),
+            };
+            let c = match name.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let negated = pattern[1] == '!';
+            let set = &pattern[if negated { 2 } else { 1 }..end];
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && matches(&pattern[end + 1..], &name[1..])
+        }
+        Some(&p) => name.first() == Some(&p) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+/// The entries of a directory as names and if they are directories, skipping hidden entries
+/// unless the pattern for them starts with a dot.
+fn entries(dir: &str, pattern: &str) -> Vec<(String, bool)> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                    entry.file_name().into_string().ok().map(|name| (name, is_dir))
+                })
+                .filter(|&(ref name, _)| !name.starts_with('.') || pattern.starts_with('.'))
+                .collect()
+        })
+        .unwrap_or_else(|_| Vec::new())
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Collect the paths under a directory matching the remaining components of a pattern.
+fn walk(dir: &str, components: &[&str], found: &mut Vec<String>) {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            if Path::new(dir).is_file() {
+                found.push(dir.to_string());
+            }
+            return;
+        }
+    };
+    if *component == "**" {
+        // Any amount of directories, including none.
+        walk(dir, rest, found);
+        for (name, is_dir) in entries(dir, "") {
+            if is_dir {
+                walk(&join(dir, &name), components, found);
+            }
+        }
+    } else if is_pattern(component) {
+        let pattern = component.chars().collect::<Vec<_>>();
+        for (name, _) in entries(dir, component) {
+            if matches(&pattern, &name.chars().collect::<Vec<_>>()) {
+                walk(&join(dir, &name), rest, found);
+            }
+        }
+    } else {
+        walk(&join(dir, component), rest, found);
+    }
+}
+
+/// Expand a glob pattern (like `src/**/*.s`, where `**` matches any amount of directories) to
+/// the files it matches in sorted order, so the order doesn't depend on the shell or the file
+/// system. Names which aren't patterns are kept as they are.
+pub fn expand(pattern: &str) -> Vec<String> {
+    if !is_pattern(pattern) {
+        return vec![pattern.to_string()];
+    }
+    let root = if pattern.starts_with('/') { "/" } else { "" };
+    let components = pattern.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    let mut found = Vec::new();
+    walk(root, &components, &mut found);
+    if found.is_empty() {
+        panic!("Error: Input pattern \"{}\" doesn't match any files.", pattern);
+    }
+    found.sort();
+    found.dedup();
+    found
+}
@@ -0,0 +1,538 @@
+use regex::Captures;
+use std::collections::HashMap;
+
+/// A parsed grammar expression, which rules can use in place of a regex.
+///
+/// Grammars are parsing expressions: `"text"` matches text, `[a-z0-9]` (or `[^...]`) one
+/// character of a class, `.` any character, `_` any amount of whitespace, and a name the
+/// production of that name. Items in a row match in sequence, `a / b` tries `a` and then `b`,
+/// `?`, `*`, and `+` repeat the item before them as often as they can, and parentheses group.
+/// `{...}` captures the text its expression matches, numbered in the order of the braces like
+/// regex groups. Choices and repetitions never backtrack once they matched, and productions
+/// can't be left recursive.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(String),
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Any,
+    Space,
+    Production(String),
+    Sequence(Vec<Expr>),
+    Choice(Vec<Expr>),
+    Repeat {
+        expr: Box<Expr>,
+        min: usize,
+        max: Option<usize>,
+    },
+    Capture {
+        index: usize,
+        expr: Box<Expr>,
+    },
+}
+
+/// The text of each group of a match, starting with the whole match, like regex captures.
+pub struct Groups<'t>(Vec<Option<&'t str>>);
+
+impl<'t> Groups<'t> {
+    pub fn from_captures(caps: &Captures<'t>) -> Groups<'t> {
+        Groups((0..caps.len()).map(|i| caps.at(i)).collect())
+    }
+
+    /// The text of a group, or `None` if it didn't take part in the match.
+    pub fn at(&self, index: usize) -> Option<&'t str> {
+        self.0.get(index).and_then(|g| *g)
+    }
+
+    /// The amount of groups including the whole match.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Where a grammar stopped matching: the farthest byte position any part of it got to and what
+/// it expected there.
+#[derive(Debug, Default)]
+pub struct Failure {
+    pub position: usize,
+    pub expected: Vec<String>,
+}
+
+impl Failure {
+    /// The things expected where matching failed, like `"," or register`.
+    pub fn expected(&self) -> String {
+        self.expected.join(" or ")
+    }
+}
+
+/// Parse a grammar expression, giving it and how many captures it has.
+pub fn parse(text: &str) -> Result<(Expr, usize), String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        captures: 0,
+    };
+    let expr = try!(parser.choice());
+    match parser.peek() {
+        None => Ok((expr, parser.captures)),
+        Some(c) => Err(format!("unexpected '{}' at column {}", c, parser.pos + 1)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    captures: usize,
+}
+
+impl Parser {
+    /// Skip whitespace and return the next character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take the next character as it is, or the character after a backslash.
+    fn literal_char(&mut self) -> Result<char, String> {
+        if self.chars.get(self.pos) == Some(&'\\') {
+            self.pos += 1;
+        }
+        let c = try!(self.chars.get(self.pos).cloned().ok_or("unexpected end of grammar"));
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn choice(&mut self) -> Result<Expr, String> {
+        let mut alternatives = vec![try!(self.sequence())];
+        while self.eat('/') {
+            alternatives.push(try!(self.sequence()));
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Choice(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr, String> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some('/') | Some(')') | Some('}') => break,
+                _ => items.push(try!(self.postfix())),
+            }
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Sequence(items)
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = try!(self.primary());
+        loop {
+            let (min, max) = match self.chars.get(self.pos) {
+                Some(&'?') => (0, Some(1)),
+                Some(&'*') => (0, None),
+                Some(&'+') => (1, None),
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Repeat {
+                expr: Box::new(expr),
+                min: min,
+                max: max,
+            };
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        let c = try!(self.peek().ok_or("unexpected end of grammar"));
+        self.pos += 1;
+        match c {
+            '"' => {
+                let mut literal = String::new();
+                while self.chars.get(self.pos) != Some(&'"') {
+                    literal.push(try!(self.literal_char()));
+                }
+                self.pos += 1;
+                Ok(Expr::Literal(literal))
+            }
+            '[' => {
+                let negated = self.chars.get(self.pos) == Some(&'^');
+                if negated {
+                    self.pos += 1;
+                }
+                let mut ranges = Vec::new();
+                while self.chars.get(self.pos) != Some(&']') {
+                    let first = try!(self.literal_char());
+                    let last = if self.chars.get(self.pos) == Some(&'-') &&
+                                  self.chars.get(self.pos + 1).map_or(false, |&c| c != ']') {
+                        self.pos += 1;
+                        try!(self.literal_char())
+                    } else {
+                        first
+                    };
+                    if last < first {
+                        return Err(format!("invalid range {}-{} at column {}",
+                                           first,
+                                           last,
+                                           start + 1));
+                    }
+                    ranges.push((first, last));
+                }
+                self.pos += 1;
+                Ok(Expr::Class {
+                    ranges: ranges,
+                    negated: negated,
+                })
+            }
+            '.' => Ok(Expr::Any),
+            '_' => Ok(Expr::Space),
+            '(' => {
+                let expr = try!(self.choice());
+                if !self.eat(')') {
+                    return Err(format!("unclosed '(' at column {}", start + 1));
+                }
+                Ok(expr)
+            }
+            '{' => {
+                let index = self.captures + 1;
+                self.captures += 1;
+                let expr = try!(self.choice());
+                if !self.eat('}') {
+                    return Err(format!("unclosed '{{' at column {}", start + 1));
+                }
+                Ok(Expr::Capture {
+                    index: index,
+                    expr: Box::new(expr),
+                })
+            }
+            c if c.is_alphabetic() => {
+                while self.chars
+                    .get(self.pos)
+                    .map_or(false, |&c| c.is_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                Ok(Expr::Production(self.chars[start..self.pos].iter().cloned().collect()))
+            }
+            c => Err(format!("unexpected '{}' at column {}", c, start + 1)),
+        }
+    }
+}
+
+impl Expr {
+    /// Call a function on this expression and every expression in it.
+    fn walk<'e, F>(&'e self, f: &mut F)
+        where F: FnMut(&'e Expr)
+    {
+        f(self);
+        match *self {
+            Expr::Sequence(ref exprs) |
+            Expr::Choice(ref exprs) => {
+                for expr in exprs {
+                    expr.walk(f);
+                }
+            }
+            Expr::Repeat { ref expr, .. } |
+            Expr::Capture { ref expr, .. } => expr.walk(f),
+            _ => {}
+        }
+    }
+
+    /// Whether the expression can match without taking any text. Productions already being
+    /// checked are taken not to, so cycles end.
+    fn nullable<'e>(&'e self,
+                    productions: &'e HashMap<String, Expr>,
+                    visiting: &mut Vec<&'e str>)
+                    -> bool {
+        match *self {
+            Expr::Literal(ref literal) => literal.is_empty(),
+            Expr::Class { .. } | Expr::Any => false,
+            Expr::Space => true,
+            Expr::Production(ref name) => {
+                if visiting.contains(&&name[..]) {
+                    return false;
+                }
+                visiting.push(name);
+                let nullable = productions[name].nullable(productions, visiting);
+                visiting.pop();
+                nullable
+            }
+            Expr::Sequence(ref exprs) => exprs.iter().all(|e| e.nullable(productions, visiting)),
+            Expr::Choice(ref exprs) => exprs.iter().any(|e| e.nullable(productions, visiting)),
+            Expr::Repeat { ref expr, min, .. } => min == 0 || expr.nullable(productions, visiting),
+            Expr::Capture { ref expr, .. } => expr.nullable(productions, visiting),
+        }
+    }
+
+    /// Add the productions the expression may use before taking any text.
+    fn leftmost<'e>(&'e self, productions: &'e HashMap<String, Expr>, out: &mut Vec<&'e str>) {
+        match *self {
+            Expr::Production(ref name) => out.push(name),
+            Expr::Sequence(ref exprs) => {
+                for expr in exprs {
+                    expr.leftmost(productions, out);
+                    if !expr.nullable(productions, &mut Vec::new()) {
+                        break;
+                    }
+                }
+            }
+            Expr::Choice(ref exprs) => {
+                for expr in exprs {
+                    expr.leftmost(productions, out);
+                }
+            }
+            Expr::Repeat { ref expr, .. } |
+            Expr::Capture { ref expr, .. } => expr.leftmost(productions, out),
+            _ => {}
+        }
+    }
+
+    /// Describe what the expression matches for errors.
+    fn describe(&self) -> String {
+        match *self {
+            Expr::Literal(ref literal) => format!("\"{}\"", literal),
+            Expr::Class { ref ranges, negated } => {
+                let ranges = ranges.iter()
+                    .map(|&(first, last)| if first == last {
+                        first.to_string()
+                    } else {
+                        format!("{}-{}", first, last)
+                    })
+                    .collect::<String>();
+                format!("[{}{}]", if negated { "^" } else { "" }, ranges)
+            }
+            Expr::Production(ref name) => name.clone(),
+            _ => String::from("any character"),
+        }
+    }
+}
+
+/// Check that the productions an expression uses exist.
+pub fn check_references(expr: &Expr, productions: &HashMap<String, Expr>) -> Result<(), String> {
+    let mut missing = None;
+    expr.walk(&mut |e| if let Expr::Production(ref name) = *e {
+        if !productions.contains_key(name) {
+            missing = Some(name.clone());
+        }
+    });
+    match missing {
+        Some(name) => Err(format!("production \"{}\" doesn't exist", name)),
+        None => Ok(()),
+    }
+}
+
+/// Check that the productions only use productions which exist and none of them is left
+/// recursive, which would never finish matching.
+pub fn check_productions(productions: &HashMap<String, Expr>) -> Result<(), String> {
+    for expr in productions.values() {
+        try!(check_references(expr, productions));
+    }
+    for (name, expr) in productions {
+        let mut seen = Vec::new();
+        let mut pending = Vec::new();
+        expr.leftmost(productions, &mut pending);
+        while let Some(next) = pending.pop() {
+            if next == name {
+                return Err(format!("production \"{}\" is left recursive", name));
+            }
+            if !seen.contains(&next) {
+                seen.push(next);
+                productions[next].leftmost(productions, &mut pending);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Match the whole of a text with a grammar expression which has `captures` captures, giving
+/// the text of its groups or where it failed.
+pub fn match_text<'t>(expr: &Expr,
+                      productions: &HashMap<String, Expr>,
+                      captures: usize,
+                      text: &'t str,
+                      case_insensitive: bool)
+                      -> Result<Groups<'t>, Failure> {
+    let mut matcher = Matcher {
+        productions: productions,
+        text: text,
+        case_insensitive: case_insensitive,
+        groups: vec![None; captures + 1],
+        failure: Failure::default(),
+        quiet: 0,
+    };
+    match matcher.at(expr, 0) {
+        Some(end) if end == text.len() => {
+            matcher.groups[0] = Some((0, end));
+            Ok(Groups(matcher.groups
+                .iter()
+                .map(|g| g.map(|(start, end)| &text[start..end]))
+                .collect()))
+        }
+        Some(end) => {
+            matcher.fail(end, String::from("the end of the statement"));
+            Err(matcher.failure)
+        }
+        None => Err(matcher.failure),
+    }
+}
+
+struct Matcher<'g, 't> {
+    productions: &'g HashMap<String, Expr>,
+    text: &'t str,
+    case_insensitive: bool,
+    // The byte range of each group.
+    groups: Vec<Option<(usize, usize)>>,
+    failure: Failure,
+    // How many productions are being matched, which report failing as a whole.
+    quiet: usize,
+}
+
+impl<'g, 't> Matcher<'g, 't> {
+    fn fail(&mut self, position: usize, expected: String) {
+        if self.quiet > 0 || position < self.failure.position {
+            return;
+        }
+        if position > self.failure.position {
+            self.failure.position = position;
+            self.failure.expected.clear();
+        }
+        if !self.failure.expected.contains(&expected) {
+            self.failure.expected.push(expected);
+        }
+    }
+
+    fn same(&self, a: char, b: char) -> bool {
+        a == b || self.case_insensitive && a.to_lowercase().eq(b.to_lowercase())
+    }
+
+    fn in_class(&self, c: char, ranges: &[(char, char)]) -> bool {
+        let in_ranges = |c: char| ranges.iter().any(|&(first, last)| first <= c && c <= last);
+        in_ranges(c) ||
+        self.case_insensitive && c.to_lowercase().chain(c.to_uppercase()).any(in_ranges)
+    }
+
+    /// Match an expression at a position, giving the position after it.
+    fn at(&mut self, expr: &'g Expr, position: usize) -> Option<usize> {
+        let text = self.text;
+        let next = text[position..].chars().next();
+        match *expr {
+            Expr::Literal(ref literal) => {
+                let mut end = position;
+                let mut chars = text[position..].chars();
+                for l in literal.chars() {
+                    match chars.next() {
+                        Some(c) if self.same(c, l) => end += c.len_utf8(),
+                        _ => {
+                            self.fail(position, expr.describe());
+                            return None;
+                        }
+                    }
+                }
+                Some(end)
+            }
+            Expr::Class { ref ranges, negated } => {
+                match next {
+                    Some(c) if self.in_class(c, ranges) != negated => Some(position + c.len_utf8()),
+                    _ => {
+                        self.fail(position, expr.describe());
+                        None
+                    }
+                }
+            }
+            Expr::Any => {
+                match next {
+                    Some(c) => Some(position + c.len_utf8()),
+                    None => {
+                        self.fail(position, expr.describe());
+                        None
+                    }
+                }
+            }
+            Expr::Space => {
+                Some(text[position..]
+                    .find(|c: char| !c.is_whitespace())
+                    .map_or(text.len(), |offset| position + offset))
+            }
+            Expr::Production(ref name) => {
+                let productions = self.productions;
+                self.quiet += 1;
+                let end = self.at(&productions[name], position);
+                self.quiet -= 1;
+                if end.is_none() {
+                    self.fail(position, expr.describe());
+                }
+                end
+            }
+            Expr::Sequence(ref exprs) => {
+                let mut end = position;
+                for expr in exprs {
+                    end = match self.at(expr, end) {
+                        Some(end) => end,
+                        None => return None,
+                    };
+                }
+                Some(end)
+            }
+            Expr::Choice(ref exprs) => {
+                for expr in exprs {
+                    let groups = self.groups.clone();
+                    if let Some(end) = self.at(expr, position) {
+                        return Some(end);
+                    }
+                    self.groups = groups;
+                }
+                None
+            }
+            Expr::Repeat { ref expr, min, max } => {
+                let mut end = position;
+                let mut count = 0;
+                while max.map_or(true, |max| count < max) {
+                    let groups = self.groups.clone();
+                    match self.at(expr, end) {
+                        Some(next) => {
+                            count += 1;
+                            // An expression matching nothing would match forever.
+                            if next == end {
+                                break;
+                            }
+                            end = next;
+                        }
+                        None => {
+                            self.groups = groups;
+                            break;
+                        }
+                    }
+                }
+                if count < min {
+                    None
+                } else {
+                    Some(end)
+                }
+            }
+            Expr::Capture { index, ref expr } => {
+                let end = self.at(expr, position);
+                if let Some(end) = end {
+                    self.groups[index] = Some((position, end));
+                }
+                end
+            }
+        }
+    }
+}
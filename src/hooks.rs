@@ -0,0 +1,40 @@
+/// A rule which matched a statement.
+pub struct RuleEvent<'e> {
+    /// The name of the rule (or its regex or grammar if it has none).
+    pub rule: &'e str,
+    /// The index of the rule in the config.
+    pub index: usize,
+    /// The statement the rule matched.
+    pub text: &'e str,
+    /// The text of each capture group, or its default if it didn't participate (`None` if the
+    /// rule has no default for it either).
+    pub captures: &'e [Option<&'e str>],
+    /// The words emitted to each segment, before tag references are filled in when linking.
+    pub values: &'e [&'e [u64]],
+    /// The position in each segment where the words were emitted.
+    pub positions: &'e [usize],
+    /// The name of the input file being parsed.
+    pub file: &'e str,
+    pub line: usize,
+}
+
+/// A named tag which was defined.
+pub struct TagEvent<'e> {
+    /// The tag qualified with the namespace it was defined in.
+    pub name: &'e str,
+    /// The position of the tag in each segment.
+    pub positions: &'e [usize],
+    /// If the tag may be defined again.
+    pub redefinable: bool,
+    pub line: usize,
+}
+
+/// Callbacks made by a parser as it parses, so custom analyses can be done on the source
+/// without modifying the parser. Every method does nothing by default.
+pub trait ParseHook {
+    /// Called after a rule emits its words.
+    fn rule(&mut self, _event: &RuleEvent) {}
+
+    /// Called when a named tag is defined.
+    fn tag(&mut self, _event: &TagEvent) {}
+}
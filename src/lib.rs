@@ -0,0 +1,30 @@
+#![feature(custom_derive, plugin)]
+#![plugin(serde_macros)]
+extern crate serde;
+extern crate serde_json;
+extern crate itertools;
+extern crate regex;
+extern crate regex_syntax;
+extern crate byteorder;
+extern crate rustc_serialize;
+
+mod expr;
+mod elf;
+pub mod config;
+pub mod grammar;
+pub mod parse;
+pub mod cache;
+pub mod stream;
+pub mod selftest;
+pub mod lint;
+pub mod coverage;
+pub mod fmt;
+pub mod glob;
+pub mod migrate;
+pub mod reference;
+pub mod testing;
+pub mod assembler;
+pub mod hooks;
+pub mod plugin;
+pub mod sim;
+pub mod dap;
@@ -0,0 +1,132 @@
+use super::config::Config;
+use regex_syntax::{Expr, Repeater};
+use std::iter;
+
+/// The most sample strings generated for any part of a regex.
+const MAX_SAMPLES: usize = 32;
+
+/// Characters preferred when a sample has to pick one out of a class.
+const PREFERRED: &'static str = "a0Z9_x1$.:-+ ";
+
+/// Two rules which both match a sample string, where the first one is the one used.
+pub struct Ambiguity {
+    pub first: usize,
+    pub second: usize,
+    pub sample: String,
+}
+
+/// Generate some strings an expression matches by taking a few of the choices at each
+/// alternation, class, and repetition.
+fn samples(expr: &Expr) -> Vec<String> {
+    let mut samples = match *expr {
+        Expr::Literal { ref chars, .. } => vec![chars.iter().cloned().collect()],
+        Expr::AnyChar | Expr::AnyCharNoNL => vec![String::from("a"), String::from("0")],
+        Expr::Class(ref class) => {
+            let mut chars = Vec::new();
+            for range in class.iter() {
+                let preferred = PREFERRED.chars()
+                    .filter(|&c| range.start <= c && c <= range.end)
+                    .take(2)
+                    .collect::<Vec<_>>();
+                if preferred.is_empty() {
+                    chars.push(range.start);
+                } else {
+                    chars.extend(preferred);
+                }
+            }
+            chars.into_iter().take(3).map(|c| c.to_string()).collect()
+        }
+        Expr::Group { ref e, .. } => samples(e),
+        Expr::Repeat { ref e, r, .. } => {
+            let counts = match r {
+                Repeater::ZeroOrOne => vec![0, 1],
+                Repeater::ZeroOrMore => vec![0, 1, 2],
+                Repeater::OneOrMore => vec![1, 2],
+                Repeater::Range { min, max } => {
+                    if max.map_or(true, |max| max > min) {
+                        vec![min, min + 1]
+                    } else {
+                        vec![min]
+                    }
+                }
+            };
+            let inner = samples(e);
+            counts.iter()
+                .flat_map(|&count| {
+                    inner.iter()
+                        .map(move |s| iter::repeat(&s[..]).take(count as usize).collect::<String>())
+                })
+                .collect()
+        }
+        Expr::Concat(ref exprs) => {
+            exprs.iter().fold(vec![String::new()], |prefixes, e| {
+                let parts = samples(e);
+                prefixes.iter()
+                    .flat_map(|prefix| parts.iter().map(move |part| format!("{}{}", prefix, part)))
+                    .take(MAX_SAMPLES)
+                    .collect()
+            })
+        }
+        Expr::Alternate(ref exprs) => exprs.iter().flat_map(|e| samples(e)).collect(),
+        // Anchors, word boundaries, and byte expressions contribute no text.
+        _ => vec![String::new()],
+    };
+    samples.sort();
+    samples.dedup();
+    samples.truncate(MAX_SAMPLES);
+    samples
+}
+
+/// Find pairs of rules where an earlier rule matches a sample string generated from a later
+/// one, so the later rule is never used for that text. Pairs where the earlier rule relaxes to
+/// the later one overlap on purpose and are left out. Only samples are checked, so rules may
+/// overlap without being found, and rules written with a grammar aren't checked.
+pub fn ambiguous_rules(config: &Config) -> Vec<Ambiguity> {
+    let regexes = config.rules
+        .iter()
+        .map(|r| r.regex().map(|regex| regex.with(|regex| regex.clone())))
+        .collect::<Vec<_>>();
+    let mut ambiguities = Vec::new();
+    for (second, regex) in regexes.iter().enumerate() {
+        let regex = match *regex {
+            Some(ref regex) => regex,
+            None => continue,
+        };
+        let expr = match Expr::parse(regex.as_str()) {
+            Ok(expr) => expr,
+            Err(_) => continue,
+        };
+        let samples = samples(&expr).into_iter().filter(|s| regex.is_match(s)).collect::<Vec<_>>();
+        for (first, rule) in config.rules[..second].iter().enumerate() {
+            if rule.relax.as_ref().map_or(false, |r| r.rule == config.rules[second].name()) {
+                continue;
+            }
+            let first_regex = match regexes[first] {
+                Some(ref regex) => regex,
+                None => continue,
+            };
+            if let Some(sample) = samples.iter().find(|s| first_regex.is_match(s)) {
+                ambiguities.push(Ambiguity {
+                    first: first,
+                    second: second,
+                    sample: sample.clone(),
+                });
+            }
+        }
+    }
+    ambiguities.sort_by_key(|a| (a.first, a.second));
+    ambiguities
+}
+
+/// Print every pair of rules found to match the same text and return the amount of pairs.
+pub fn run_lint(config: &Config) -> usize {
+    let ambiguities = ambiguous_rules(config);
+    for a in &ambiguities {
+        println!("rule \"{}\" matches \"{}\" before rule \"{}\" can",
+                 config.rules[a.first].name(),
+                 a.sample,
+                 config.rules[a.second].name());
+    }
+    println!("{} ambiguous rule pairs", ambiguities.len());
+    ambiguities.len()
+}
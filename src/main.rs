@@ -1,35 +1,306 @@
-#![feature(custom_derive, plugin)]
-#![plugin(serde_macros)]
+extern crate uasm;
 extern crate serde_json;
 extern crate itertools;
 extern crate clap;
-extern crate regex;
-extern crate byteorder;
-extern crate rustc_serialize;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand, Values};
 use itertools::{Itertools, EitherOrBoth};
 
-mod config;
-use config::Config;
-mod parse;
-use parse::{Parser, OutputFormat};
+use uasm::config::Config;
+use uasm::parse::{Parser, Parsed, OutputFormat, RuleProfile, SymbolFormat, XrefOrder};
+use uasm::cache::Cache;
+use uasm::stream::Stream;
+use uasm::selftest;
+use uasm::lint;
+use uasm::coverage::{self, Coverage};
+use uasm::fmt::format_source;
+use uasm::glob;
+use uasm::migrate::migrate_config;
+use uasm::reference::{write_reference, ReferenceFormat};
+use uasm::sim::{Simulator, Recorder};
 
-use std::io::BufReader;
-use std::fs::File;
+use std::cmp::{max, min};
+use std::ffi::OsString;
+use std::io::{self, BufReader, Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+fn open_input(name: &str) -> BufReader<File> {
+    BufReader::new(File::open(name)
+        .unwrap_or_else(|e| panic!("Error: Failed to open input file \"{}\": {}", name, e)))
+}
+
+fn read_input(name: &str) -> Vec<u8> {
+    let mut source = Vec::new();
+    open_input(name)
+        .read_to_end(&mut source)
+        .unwrap_or_else(|e| panic!("Error: Failed to read input file \"{}\": {}", name, e));
+    source
+}
+
+/// The names of the input files given as arguments, with glob patterns expanded.
+fn input_names(values: Option<Values>) -> Vec<String> {
+    values.map_or_else(Vec::new, |iter| iter.flat_map(glob::expand).collect())
+}
+
+/// Parse libraries of symbol definitions and return the defines with the symbols they set, so
+/// they are defined before every input is parsed.
+fn load_libraries(config: &Config,
+                  defines: Vec<(String, i64)>,
+                  libraries: &[&str])
+                  -> Vec<(String, i64)> {
+    if libraries.is_empty() {
+        return defines;
+    }
+    let mut parser = Parser::new(config);
+    for &(ref name, value) in &defines {
+        parser.define(name, value);
+    }
+    for &name in libraries {
+        parser.set_file(name);
+        parser.parse(open_input(name));
+        let stats = parser.stats();
+        if stats.tags != 0 || stats.segments.iter().any(|s| s.words != 0) {
+            panic!("Error: Symbol library \"{}\" emits words or creates tags, but it may only \
+                    set symbols.",
+                   name);
+        }
+    }
+    parser.constants()
+}
+
+/// Print every error found while parsing and fail if there were any.
+fn check_errors(parser: &Parser) {
+    let errors = parser.errors();
+    if !errors.is_empty() {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        for &(file, diagnostic) in &errors {
+            writeln!(stderr, "{}:{}: {}", file, diagnostic.line, diagnostic.message)
+                .unwrap_or_else(|e| panic!("Error: Failed to write an error: {}", e));
+        }
+        panic!("Error: Parsing failed with {} errors.", errors.len());
+    }
+}
+
+/// The extension appended to the outputs of a format when several formats are written.
+fn format_extension(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::LittleEndian => "bin",
+        OutputFormat::BigEndian => "be.bin",
+        OutputFormat::HexList => "txt",
+        OutputFormat::IntelHex => "hex",
+        OutputFormat::SRecord => "srec",
+        OutputFormat::Elf => "elf",
+    }
+}
+
+fn parse_format(name: &str) -> OutputFormat {
+    match name {
+        "little-endian" => OutputFormat::LittleEndian,
+        "big-endian" => OutputFormat::BigEndian,
+        "hex-list" => OutputFormat::HexList,
+        "intel-hex" => OutputFormat::IntelHex,
+        "srecord" => OutputFormat::SRecord,
+        "elf" => OutputFormat::Elf,
+        v => panic!("Error: \"{}\" is not a valid format.", v),
+    }
+}
+
+/// Compare an assembled output against the file already on disk, printing a summary of how they
+/// differ, and return if they are the same.
+fn verify_output(name: &str, assembled: &[u8]) -> bool {
+    let mut existing = Vec::new();
+    if let Err(e) = File::open(name).and_then(|mut f| f.read_to_end(&mut existing)) {
+        println!("{}: can't be read ({})", name, e);
+        return false;
+    }
+    let differing = existing.iter().zip(assembled).filter(|&(a, b)| a != b).count() +
+                    max(existing.len(), assembled.len()) - min(existing.len(), assembled.len());
+    if differing == 0 {
+        println!("{}: up to date", name);
+        return true;
+    }
+    let first = existing.iter()
+        .zip(assembled)
+        .position(|(a, b)| a != b)
+        .unwrap_or(min(existing.len(), assembled.len()));
+    println!("{}: {} bytes differ starting at offset {:#x} ({} bytes on disk, {} assembled)",
+             name,
+             differing,
+             first,
+             existing.len(),
+             assembled.len());
+    false
+}
+
+/// Split the contents of a response file into arguments on whitespace, where quotes keep
+/// whitespace in an argument.
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut argument = String::new();
+    // If an argument was started, which may be empty if it was an empty pair of quotes.
+    let mut started = false;
+    let mut quote = None;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => argument.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                started = true;
+            }
+            None if c.is_whitespace() => {
+                if started {
+                    arguments.push(argument.clone());
+                    argument.clear();
+                    started = false;
+                }
+            }
+            None => {
+                argument.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        arguments.push(argument);
+    }
+    arguments
+}
+
+/// Replace every argument of the form `@FILE` with the arguments in the file, which may name
+/// more response files. Arguments which aren't UTF-8 are passed on as they are.
+fn expand_response_files<I>(args: I, depth: usize) -> Vec<OsString>
+    where I: IntoIterator<Item = OsString>
+{
+    let mut expanded = Vec::new();
+    for arg in args {
+        let file = match arg.to_str() {
+            Some(arg) if arg.len() > 1 && arg.starts_with('@') => Some(arg[1..].to_string()),
+            _ => None,
+        };
+        if let Some(file) = file {
+            if depth == 16 {
+                panic!("Error: Response files are nested too deeply at \"@{}\".", file);
+            }
+            let text = String::from_utf8(read_input(&file)).unwrap_or_else(|e| {
+                panic!("Error: Response file \"{}\" isn't UTF-8: {}", file, e)
+            });
+            expanded.extend(expand_response_files(split_arguments(&text)
+                                                      .into_iter()
+                                                      .map(OsString::from),
+                                                  depth + 1));
+        } else {
+            expanded.push(arg);
+        }
+    }
+    expanded
+}
+
+/// An input which is either already parsed from the cache or being parsed on a thread (which
+/// also returns how long parsing took).
+enum Pending {
+    Cached(Parsed),
+    Parsing(Option<u64>, JoinHandle<(Parsed, Duration)>),
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1e6
+}
+
+/// Print how often each rule was tried and matched and how long trying it took, slowest first.
+fn print_rule_profile(config: &Config, profile: &[RuleProfile]) {
+    let mut rules = config.rules.iter().zip(profile).collect::<Vec<_>>();
+    rules.sort_by(|a, b| b.1.nanoseconds.cmp(&a.1.nanoseconds));
+    println!("{:>12} {:>10} {:>10}  rule", "time (ms)", "attempts", "matches");
+    for (rule, p) in rules {
+        println!("{:>12.3} {:>10} {:>10}  {}",
+                 p.nanoseconds as f64 / 1e6,
+                 p.attempts,
+                 p.matches,
+                 rule.name());
+    }
+}
+
+/// How long each phase of an assembly took and how much was done, printed with --stats.
+struct BuildStats {
+    // When the build and the current phase started.
+    start: Instant,
+    phase_start: Instant,
+    phases: Vec<(String, Duration)>,
+    files: Vec<(String, Duration)>,
+    rules: usize,
+    statements: usize,
+    replacements: usize,
+}
+
+impl BuildStats {
+    fn new() -> BuildStats {
+        let now = Instant::now();
+        BuildStats {
+            start: now,
+            phase_start: now,
+            phases: Vec::new(),
+            files: Vec::new(),
+            rules: 0,
+            statements: 0,
+            replacements: 0,
+        }
+    }
+
+    /// End the current phase under a name and start the next one.
+    fn phase(&mut self, name: &str) {
+        let now = Instant::now();
+        self.phases.push((name.to_string(), now.duration_since(self.phase_start)));
+        self.phase_start = now;
+    }
+
+    /// Take the counts from a linked parser.
+    fn count(&mut self, parser: &Parser) {
+        let stats = parser.stats();
+        self.statements = stats.statements;
+        self.replacements = stats.segments.iter().map(|s| s.replacements).sum();
+    }
+
+    fn print(&self) {
+        for &(ref name, duration) in &self.phases {
+            println!("{}: {:.3} ms", name, milliseconds(duration));
+            if name == "parsing" {
+                for &(ref file, duration) in &self.files {
+                    println!("  {}: {:.3} ms", file, milliseconds(duration));
+                }
+            }
+        }
+        println!("total: {:.3} ms", milliseconds(self.start.elapsed()));
+        println!("rules compiled: {}", self.rules);
+        println!("statements matched: {}", self.statements);
+        println!("replacements resolved: {}", self.replacements);
+    }
+}
 
 fn main() {
     let matches = App::new("uarc-asm")
         .version("0.1.0")
         .author("Geordon Worley <vadixidav@gmail.com>")
         .about("A configurable, generic assembler")
+        .after_help("An argument of the form @FILE is replaced with the arguments in FILE, \
+                     separated by whitespace.")
         .arg(Arg::with_name("format")
             .long("format")
             .short("f")
             .takes_value(true)
-            .possible_values(&["little-endian", "big-endian", "hex-list"])
+            .possible_values(&["little-endian", "big-endian", "hex-list", "intel-hex", "srecord",
+                              "elf"])
+            .multiple(true)
+            .number_of_values(1)
             .default_value("little-endian")
-            .help("The format of the output files"))
+            .help("The format of the output files (given more than once, every segment is \
+                   written in each format with the extension of the format appended)"))
         .arg(Arg::with_name("config")
             .long("config")
             .short("c")
@@ -43,35 +314,532 @@ fn main() {
             .number_of_values(1)
             .takes_value(true)
             .help("Output file names in order (one at a time)"))
+        .arg(Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .takes_value(true)
+            .default_value("1")
+            .help("Amount of input files to parse in parallel (namespaces and redefinable \
+                   symbols don't carry between files, and values computed from positions \
+                   can't be used, when above 1)"))
+        .arg(Arg::with_name("cache")
+            .long("cache")
+            .takes_value(true)
+            .help("Directory in which parsed inputs are cached to skip parsing unchanged inputs \
+                   (inputs are then parsed independently, as with --jobs)"))
+        .arg(Arg::with_name("stream")
+            .long("stream")
+            .takes_value(true)
+            .help("Write segments to the outputs while parsing whenever this many words are \
+                   buffered and patch them in place when linking"))
+        .arg(Arg::with_name("source-map")
+            .long("source-map")
+            .takes_value(true)
+            .help("Write a JSON file mapping every output word to its file, line, and rule"))
+        .arg(Arg::with_name("symbols")
+            .long("symbols")
+            .takes_value(true)
+            .help("Write the addresses of all tags to a label file for an emulator debugger"))
+        .arg(Arg::with_name("symbol-format")
+            .long("symbol-format")
+            .takes_value(true)
+            .possible_values(&["vice", "nocash", "mame"])
+            .default_value("vice")
+            .help("The format of the label file"))
+        .arg(Arg::with_name("symbol-segment")
+            .long("symbol-segment")
+            .takes_value(true)
+            .default_value("0")
+            .help("The segment (by name or index) whose addresses are written to the label file"))
+        .arg(Arg::with_name("tags")
+            .long("tags")
+            .takes_value(true)
+            .help("Write a ctags file of where every tag is defined"))
+        .arg(Arg::with_name("xref")
+            .long("xref")
+            .takes_value(true)
+            .help("Write a cross-reference listing of every tag with where it is defined and \
+                   every line that refers to it"))
+        .arg(Arg::with_name("xref-order")
+            .long("xref-order")
+            .takes_value(true)
+            .possible_values(&["name", "address"])
+            .default_value("name")
+            .help("The order tags are listed in by the cross-reference listing"))
+        .arg(Arg::with_name("xref-segment")
+            .long("xref-segment")
+            .takes_value(true)
+            .default_value("0")
+            .help("The segment (by name or index) whose addresses are listed in the \
+                   cross-reference listing"))
+        .arg(Arg::with_name("segment-stats")
+            .long("segment-stats")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .help("Print statistics about every segment after linking"))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .help("Print how long compiling the config, parsing each input, linking, and writing \
+                   the outputs took, and how many rules, statements, and replacements there were"))
+        .arg(Arg::with_name("profile-rules")
+            .long("profile-rules")
+            .help("Print how often each rule was tried and matched and how long trying it took \
+                   after parsing, slowest first"))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .help("Compare the assembled outputs against the output files already on disk \
+                   without writing them, failing if any of them differ"))
+        .arg(Arg::with_name("diff")
+            .long("diff")
+            .help("Print the words of the assembled outputs which differ from the output files \
+                   already on disk with the lines that emitted them, without writing them"))
+        .arg(Arg::with_name("dump")
+            .long("dump")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .value_name("SEGMENT")
+            .help("Print a hex and ASCII dump of a segment (by name or index, 0 by default) \
+                   after linking"))
+        .arg(Arg::with_name("byte-swap")
+            .long("byte-swap")
+            .takes_value(true)
+            .value_name("BYTES")
+            .help("Reverse every group of this many bytes in the outputs (2 swaps byte pairs)"))
+        .arg(Arg::with_name("output-spec")
+            .long("output-spec")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Write a segment in a format to a file given as SEGMENT:FORMAT:FILE (the \
+                   segments without a file from --outputs aren't written when this is given)"))
+        .arg(Arg::with_name("combined")
+            .long("combined")
+            .takes_value(true)
+            .help("Write every segment at its base address to this one Intel HEX or S-record \
+                   file (by --format) rather than to the outputs"))
+        .arg(Arg::with_name("remove-unreferenced")
+            .long("remove-unreferenced")
+            .help("Remove the words of tags which nothing reachable from the start of the \
+                   segments, the entry point, or the kept tags refers to (tags are ordered by \
+                   their position in segment 0)"))
+        .arg(Arg::with_name("keep")
+            .long("keep")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A tag which is never removed by --remove-unreferenced"))
+        .arg(Arg::with_name("define")
+            .long("define")
+            .short("D")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Define a symbol as NAME or NAME=VALUE (1 by default) before parsing, which \
+                   also enables rules that require it"))
+        .arg(Arg::with_name("symbol-lib")
+            .long("symbol-lib")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Parse a library of symbol definitions before the inputs and define the \
+                   symbols it sets for every input (it may not emit words or create tags)"))
+        .arg(Arg::with_name("defsym")
+            .long("defsym")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Define a tag at a position in every segment with NAME=POSITION (decimal or \
+                   hex with 0x) before linking, like the address of a routine or register \
+                   outside the program"))
+        .arg(Arg::with_name("deny-warnings")
+            .long("deny-warnings")
+            .help("Report every warning as an error"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Reject rules which aren't anchored with ^ and $ or which another rule matches \
+                   before, and report words too wide for their segment and tags nothing refers \
+                   to as errors"))
+        .arg(Arg::with_name("warning")
+            .short("W")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Report warnings of a category as errors with error=NAME (entry, directive, or \
+                   width)"))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .short("v")
+            .help("Print the config being used and each input as it is parsed"))
         .arg(Arg::with_name("inputs")
             .index(1)
             .multiple(true)
-            .help("List of the input assembly files in the order they are parsed"))
-        .get_matches();
+            .help("List of the input assembly files in the order they are parsed, where glob \
+                   patterns like src/**/*.s are expanded to the files they match in sorted \
+                   order"))
+        .subcommand(SubCommand::with_name("test")
+            .about("Run the tests embedded in the config and report failures"))
+        .subcommand(SubCommand::with_name("lint")
+            .about("Report pairs of rules which can match the same text, where only the first \
+                    one is ever used"))
+        .subcommand(SubCommand::with_name("coverage")
+            .about("Parse the inputs and the tests embedded in the config and report the rules \
+                    which never matched a statement")
+            .arg(Arg::with_name("inputs")
+                .index(1)
+                .multiple(true)
+                .help("List of the input assembly files in the order they are parsed")))
+        .subcommand(SubCommand::with_name("fmt")
+            .about("Lay out the inputs in place with the style in the config")
+            .arg(Arg::with_name("check")
+                .long("check")
+                .help("List the inputs which aren't laid out with the style and fail if there \
+                       are any rather than changing them"))
+            .arg(Arg::with_name("inputs")
+                .index(1)
+                .multiple(true)
+                .help("List of the input assembly files to lay out")))
+        .subcommand(SubCommand::with_name("migrate-config")
+            .about("Rewrite the config from an older layout to the current one in place, keeping \
+                    the original as <config>.bak")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("Write the migrated config here rather than over the config"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Only print the changes migrating would make")))
+        .subcommand(SubCommand::with_name("reference")
+            .about("Write an instruction set reference generated from the rules in the config")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .takes_value(true)
+                .possible_values(&["markdown", "html"])
+                .default_value("markdown")
+                .help("The format of the reference"))
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("Write the reference here rather than to stdout")))
+        .subcommand(SubCommand::with_name("dap")
+            .about("Serve the Debug Adapter Protocol on stdin and stdout to debug programs in the \
+                    simulator"))
+        .subcommand(SubCommand::with_name("run")
+            .about("Assemble the inputs and simulate them with the machine in the config")
+            .arg(Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true)
+                .default_value("1000000")
+                .help("The amount of instructions to run before giving up"))
+            .arg(Arg::with_name("inputs")
+                .index(1)
+                .multiple(true)
+                .help("List of the input assembly files in the order they are parsed")))
+        .get_matches_from({
+            let mut args = std::env::args_os();
+            args.next().into_iter().chain(expand_response_files(args, 0))
+        });
 
-    let format = match matches.value_of("format").unwrap() {
-        "little-endian" => OutputFormat::LittleEndian,
-        "big-endian" => OutputFormat::BigEndian,
-        "hex-list" => OutputFormat::HexList,
-        v => panic!("Error: \"{}\" is not a valid format.", v),
-    };
+    let formats = matches.values_of("format")
+        .unwrap()
+        .map(parse_format)
+        .collect::<Vec<_>>();
+    let mut extensions = formats.iter().map(|&f| format_extension(f)).collect::<Vec<_>>();
+    extensions.sort();
+    extensions.dedup();
+    if extensions.len() != formats.len() {
+        panic!("Error: A format is given more than once.");
+    }
+    let format = formats[0];
+
+    let jobs = matches.value_of("jobs")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| panic!("Error: Invalid amount of jobs: {}", e));
+    if jobs == 0 {
+        panic!("Error: The amount of jobs must be at least 1.");
+    }
+
+    let defines = matches.values_of("define")
+        .map_or_else(Vec::new, |iter| iter.collect())
+        .into_iter()
+        .map(|define| {
+            let mut parts = define.splitn(2, '=');
+            let name = parts.next().unwrap().to_string();
+            let value = parts.next().map_or(1, |value| {
+                value.parse::<i64>().unwrap_or_else(|e| {
+                    panic!("Error: Invalid value for defined symbol \"{}\": {}", name, e)
+                })
+            });
+            (name, value)
+        })
+        .collect::<Vec<_>>();
+
+    let defsyms = matches.values_of("defsym")
+        .map_or_else(Vec::new, |iter| iter.collect())
+        .into_iter()
+        .map(|defsym| {
+            let mut parts = defsym.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let position = parts.next()
+                .unwrap_or_else(|| {
+                    panic!("Error: Defined tag \"{}\" has no position (expected NAME=POSITION).",
+                           defsym)
+                });
+            let parsed = if position.starts_with("0x") || position.starts_with("0X") {
+                usize::from_str_radix(&position[2..], 16)
+            } else {
+                position.parse::<usize>()
+            };
+            (name,
+             parsed.unwrap_or_else(|e| {
+                panic!("Error: Invalid position for defined tag \"{}\": {}", name, e)
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    let mut denied_warnings = matches.values_of("warning")
+        .map_or_else(Vec::new, |iter| iter.collect())
+        .into_iter()
+        .map(|option| {
+            if !option.starts_with("error=") {
+                panic!("Error: Invalid warning option \"{}\" (expected error=NAME).", option);
+            }
+            option["error=".len()..].to_string()
+        })
+        .collect::<Vec<_>>();
+    if matches.is_present("deny-warnings") {
+        denied_warnings.push(String::from("all"));
+    }
+    let strict = matches.is_present("strict");
+    if strict {
+        denied_warnings.push(String::from("width"));
+    }
 
     let config_filename = matches.value_of("config").unwrap();
-    let config = Config::new_from_filename(config_filename);
+    if let Some(migrate) = matches.subcommand_matches("migrate-config") {
+        let mut json = serde_json::from_slice(&read_input(config_filename))
+            .unwrap_or_else(|e| panic!("Error: Failed to parse file to JSON: {}", e));
+        let changes = migrate_config(&mut json)
+            .unwrap_or_else(|e| panic!("Error: Failed to migrate the config: {}", e));
+        let migrated = serde_json::to_string_pretty(&json).unwrap();
+        // Make sure the result loads before writing it.
+        Config::new_from_str(&migrated);
+        for change in &changes {
+            println!("{}", change);
+        }
+        if migrate.is_present("dry-run") {
+            return;
+        }
+        let output = match migrate.value_of("output") {
+            Some(output) => output,
+            // There is nothing to rewrite in place.
+            None if changes.is_empty() => return,
+            None => {
+                let backup = format!("{}.bak", config_filename);
+                fs::copy(config_filename, &backup).unwrap_or_else(|e| {
+                    panic!("Error: Failed to back up the config to \"{}\": {}", backup, e)
+                });
+                config_filename
+            }
+        };
+        File::create(output)
+            .and_then(|mut f| f.write_all(migrated.as_bytes()))
+            .unwrap_or_else(|e| panic!("Error: Failed to write \"{}\": {}", output, e));
+        return;
+    }
 
-    let mut parser = Parser::new(&config);
+    let mut build_stats = BuildStats::new();
+    let config = Arc::new(Config::new_from_filename(config_filename));
+    build_stats.phase("config");
+    build_stats.rules = config.rules.len();
+    if strict {
+        if let Some(rule) = config.rules.iter().find(|r| !r.is_anchored()) {
+            panic!("Error: Rule \"{}\" isn't anchored with ^ and $, which --strict requires.",
+                   rule.name());
+        }
+        if let Some(a) = lint::ambiguous_rules(&config).first() {
+            panic!("Error: Rule \"{}\" matches \"{}\" before rule \"{}\" can, which --strict \
+                    doesn't allow.",
+                   config.rules[a.first].name(),
+                   a.sample,
+                   config.rules[a.second].name());
+        }
+    }
+    if matches.subcommand_matches("test").is_some() {
+        if selftest::run_tests(&config) != 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    for name in matches.values_of("inputs")
-        .map_or_else(Vec::new, |iter| iter.collect()) {
-        parser.parse(BufReader::new(File::open(&name)
-            .unwrap_or_else(|e| panic!("Error: Failed to open input file \"{}\": {}", name, e))));
+    if matches.subcommand_matches("lint").is_some() {
+        if lint::run_lint(&config) != 0 {
+            std::process::exit(1);
+        }
+        return;
     }
 
-    // Link the program.
-    parser.link();
+    if let Some(fmt) = matches.subcommand_matches("fmt") {
+        let mut unformatted = 0;
+        for name in &input_names(fmt.values_of("inputs")) {
+            let source = String::from_utf8(read_input(name))
+                .unwrap_or_else(|e| panic!("Error: Input file \"{}\" isn't UTF-8: {}", name, e));
+            let formatted = format_source(&config, &source);
+            if formatted == source {
+                continue;
+            }
+            if fmt.is_present("check") {
+                println!("{}", name);
+                unformatted += 1;
+            } else {
+                File::create(name)
+                    .and_then(|mut f| f.write_all(formatted.as_bytes()))
+                    .unwrap_or_else(|e| panic!("Error: Failed to write \"{}\": {}", name, e));
+            }
+        }
+        if unformatted != 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(reference) = matches.subcommand_matches("reference") {
+        let format = match reference.value_of("format").unwrap() {
+            "markdown" => ReferenceFormat::Markdown,
+            "html" => ReferenceFormat::Html,
+            v => panic!("Error: \"{}\" is not a valid reference format.", v),
+        };
+        match reference.value_of("output") {
+            Some(name) => {
+                write_reference(&config,
+                                format,
+                                &mut File::create(name).unwrap_or_else(|e| {
+                                    panic!("Error: Failed to open reference file \"{}\": {}",
+                                           name,
+                                           e)
+                                }))
+            }
+            None => write_reference(&config, format, &mut io::stdout()),
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("dap").is_some() {
+        let stdin = std::io::stdin();
+        uasm::dap::serve(&config, stdin.lock(), std::io::stdout());
+        return;
+    }
+
+    let libraries = matches.values_of("symbol-lib").map_or_else(Vec::new, |iter| iter.collect());
+    let defines = load_libraries(&config, defines, &libraries);
+
+    if let Some(cover) = matches.subcommand_matches("coverage") {
+        let counts = Rc::new(RefCell::new(vec![0; config.rules.len()]));
+        {
+            let mut parser = Parser::new(&config);
+            for &(ref name, value) in &defines {
+                parser.define(name, value);
+            }
+            parser.recover_errors();
+            parser.add_hook(Box::new(Coverage::new(counts.clone())));
+            for name in &input_names(cover.values_of("inputs")) {
+                parser.set_file(name);
+                parser.parse(open_input(name));
+            }
+            check_errors(&parser);
+        }
+        coverage::cover_tests(&config, &counts);
+        if coverage::report_unmatched(&config, &counts.borrow()) != 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(run) = matches.subcommand_matches("run") {
+        let max_steps = run.value_of("max-steps")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| panic!("Error: Invalid amount of steps: {}", e));
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let mut parser = Parser::new(&config);
+        for &(ref name, value) in &defines {
+            parser.define(name, value);
+        }
+        for category in &denied_warnings {
+            parser.deny_warning(category);
+        }
+        parser.recover_errors();
+        parser.add_hook(Box::new(Recorder::new(&config, records.clone())));
+        for name in &input_names(run.values_of("inputs")) {
+            parser.set_file(name);
+            parser.parse(open_input(name));
+        }
+        check_errors(&parser);
+        parser.link();
+        let records = records.borrow().clone();
+        let mut simulator = Simulator::new(&config, &parser, records)
+            .unwrap_or_else(|e| panic!("Error: Failed to start the simulator: {}", e));
+        while !simulator.halted {
+            if simulator.steps == max_steps {
+                panic!("Error: The program didn't halt within {} steps.", max_steps);
+            }
+            simulator.step().unwrap_or_else(|e| {
+                panic!("Error: Simulation failed at position {} (line {}): {}",
+                       simulator.pc,
+                       simulator.line(simulator.pc).unwrap_or(0),
+                       e)
+            });
+            for value in simulator.output.drain(..) {
+                println!("{}", value);
+            }
+        }
+        println!("Halted after {} steps at position {}.", simulator.steps, simulator.pc);
+        for (i, value) in simulator.registers.iter().enumerate() {
+            println!("r{} = {}", i, value);
+        }
+        return;
+    }
+
+    let verbose = matches.is_present("verbose");
+    if verbose {
+        println!("Config: {}", config.identity());
+        if let Some(ref description) = config.description {
+            println!("  {}", description);
+        }
+        if let Some(word_size) = config.word_size {
+            println!("  {}-bit words", word_size);
+        }
+    }
+
+    let specs = matches.values_of("output-spec")
+        .map_or_else(Vec::new, |iter| iter.collect())
+        .into_iter()
+        .map(|spec| {
+            let parts = spec.splitn(3, ':').collect::<Vec<_>>();
+            if parts.len() != 3 {
+                panic!("Error: Output spec \"{}\" isn't SEGMENT:FORMAT:FILE.", spec);
+            }
+            let segment = config.find_segment(parts[0]).unwrap_or_else(|| {
+                panic!("Error: Output spec \"{}\" is for segment {}, which doesn't exist.",
+                       spec,
+                       parts[0])
+            });
+            (segment, parse_format(parts[1]), parts[2].to_string())
+        })
+        .collect::<Vec<_>>();
 
     let outputs = matches.values_of("outputs").map_or_else(Vec::new, |iter| iter.collect());
-    for (i, name) in (0..config.segment_widths.len())
+    let segments = if specs.is_empty() {
+        config.segment_widths.len()
+    } else {
+        outputs.len()
+    };
+    let outputs = (0..segments)
         .zip_longest(outputs)
         .map(|v| {
             match v {
@@ -84,11 +852,356 @@ fn main() {
                 }
             }
         })
-        .enumerate() {
+        .collect::<Vec<String>>();
+
+    let mut parser = Parser::new(&config);
+    for &(ref name, value) in &defines {
+        parser.define(name, value);
+    }
+    for category in &denied_warnings {
+        parser.deny_warning(category);
+    }
+    parser.recover_errors();
+    let profile_rules = matches.is_present("profile-rules");
+    if profile_rules {
+        parser.profile_rules();
+    }
+    if let Some(group) = matches.value_of("byte-swap") {
+        parser.swap_bytes(group.parse::<usize>()
+            .unwrap_or_else(|e| panic!("Error: Invalid byte swap group: {}", e)));
+    }
+
+    let stream = matches.value_of("stream").map(|threshold| {
+        threshold.parse::<usize>()
+            .unwrap_or_else(|e| panic!("Error: Invalid stream threshold: {}", e))
+    });
+    if let Some(threshold) = stream {
+        if jobs != 1 || matches.is_present("cache") {
+            panic!("Error: Streaming can't be combined with --jobs or --cache.");
+        }
+        if !specs.is_empty() || formats.len() > 1 {
+            panic!("Error: Streaming can't be combined with --output-spec or several formats.");
+        }
+        if matches.is_present("byte-swap") {
+            panic!("Error: Bytes can't be swapped in streaming mode.");
+        }
+        let files = outputs.iter()
+            .map(|name| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(name)
+                    .unwrap_or_else(|e| {
+                        panic!("Error: Failed to open output file \"{}\": {}", name, e)
+                    })
+            })
+            .collect();
+        parser.stream_to(Stream::new(format, files, config.segment_widths.clone(), threshold));
+    }
+
+    let inputs = input_names(matches.values_of("inputs"));
+    let cache = matches.value_of("cache").map(|dir| Cache::new(dir, config_filename, &defines));
+    if jobs == 1 && cache.is_none() {
+        for name in inputs {
+            if verbose {
+                println!("Parsing {}", name);
+            }
+            let started = Instant::now();
+            parser.set_file(&name);
+            parser.parse(open_input(&name));
+            build_stats.files.push((name, started.elapsed()));
+        }
+    } else {
+        // Parse each input separately (on its own thread unless it is cached) and merge them in
+        // order so the result is the same as if they were assembled one after the other.
+        for chunk in inputs.chunks(jobs) {
+            let pending = chunk.iter()
+                .map(|name| {
+                    if verbose {
+                        println!("Parsing {}", name);
+                    }
+                    let source = read_input(name);
+                    let key = cache.as_ref().map(|c| c.key(name, &source));
+                    let started = Instant::now();
+                    if let Some(parsed) = key.and_then(|k| cache.as_ref().unwrap().load(k)) {
+                        build_stats.files
+                            .push((format!("{} (cached)", name), started.elapsed()));
+                        return Pending::Cached(parsed);
+                    }
+                    let config = config.clone();
+                    let defines = defines.clone();
+                    let denied_warnings = denied_warnings.clone();
+                    let name = name.to_string();
+                    Pending::Parsing(key,
+                                     thread::spawn(move || {
+                                         let mut parser = Parser::new(&config);
+                                         for (name, value) in defines {
+                                             parser.define(&name, value);
+                                         }
+                                         for category in denied_warnings {
+                                             parser.deny_warning(&category);
+                                         }
+                                         parser.recover_errors();
+                                         if profile_rules {
+                                             parser.profile_rules();
+                                         }
+                                         parser.set_file(&name);
+                                         let started = Instant::now();
+                                         parser.parse(&source[..]);
+                                         (parser.into_parsed(), started.elapsed())
+                                     }))
+                })
+                .collect::<Vec<_>>();
+            for (name, p) in chunk.iter().zip(pending) {
+                let parsed = match p {
+                    Pending::Cached(parsed) => parsed,
+                    Pending::Parsing(key, handle) => {
+                        let (parsed, duration) = handle.join().unwrap_or_else(|_| {
+                            panic!("Error: Failed to parse an input in parallel.")
+                        });
+                        build_stats.files.push((name.clone(), duration));
+                        if let (Some(cache), Some(key)) = (cache.as_ref(), key) {
+                            cache.store(key, &parsed);
+                        }
+                        parsed
+                    }
+                };
+                parser.merge(parsed);
+            }
+        }
+    }
+
+    build_stats.phase("parsing");
+    if profile_rules {
+        print_rule_profile(&config, parser.rule_profile());
+    }
+    check_errors(&parser);
+
+    for &(name, position) in &defsyms {
+        parser.define_tag(name, position);
+    }
+
+    if matches.is_present("remove-unreferenced") {
+        let keep = matches.values_of("keep").map_or_else(Vec::new, |iter| iter.collect());
+        let removed = parser.remove_unreferenced(0, &keep).unwrap_or_else(|e| panic!("{}", e));
+        for (tag, words) in removed {
+            println!("Removed unreferenced tag \"{}\" ({} words)", tag, words);
+        }
+    }
+
+    // Link the program.
+    parser.link();
+    if strict {
+        let unused = parser.unused_tags();
+        if !unused.is_empty() {
+            panic!("Error: Nothing refers to the tags {}, which --strict doesn't allow.",
+                   unused.iter().map(|t| format!("\"{}\"", t)).collect::<Vec<_>>().join(", "));
+        }
+    }
+    build_stats.phase("link");
+    build_stats.count(&parser);
+    let print_stats = matches.is_present("stats");
+
+    if let Some(report) = matches.value_of("segment-stats") {
+        let stats = parser.stats();
+        if report == "json" {
+            println!("{}", serde_json::to_string(&stats).unwrap());
+        } else {
+            println!("tags: {}", stats.tags);
+            for (i, s) in stats.segments.iter().enumerate() {
+                println!("segment {}: {} words, {} bytes, {}, {} replacements",
+                         config.segment_name(i),
+                         s.words,
+                         s.bytes,
+                         match (s.capacity, s.used) {
+                             (Some(capacity), Some(used)) => {
+                                 format!("{:.1}% of {} words used", used, capacity)
+                             }
+                             _ => String::from("no maximum size"),
+                         },
+                         s.replacements);
+            }
+        }
+    }
+
+    if let Some(name) = matches.value_of("tags") {
+        parser.write_tags(&mut File::create(name).unwrap_or_else(|e| {
+            panic!("Error: Failed to open tags file \"{}\": {}", name, e)
+        }));
+    }
+
+    if let Some(name) = matches.value_of("symbols") {
+        let symbol_format = match matches.value_of("symbol-format").unwrap() {
+            "vice" => SymbolFormat::Vice,
+            "nocash" => SymbolFormat::NoCash,
+            "mame" => SymbolFormat::Mame,
+            v => panic!("Error: \"{}\" is not a valid symbol format.", v),
+        };
+        let segment = matches.value_of("symbol-segment").unwrap();
+        let segment = config.find_segment(segment)
+            .unwrap_or_else(|| panic!("Error: Symbol segment {} doesn't exist.", segment));
+        parser.write_symbols(symbol_format,
+                             segment,
+                             &mut File::create(name).unwrap_or_else(|e| {
+                                 panic!("Error: Failed to open symbol file \"{}\": {}", name, e)
+                             }));
+    }
+
+    if let Some(name) = matches.value_of("xref") {
+        if stream.is_some() {
+            panic!("Error: A cross-reference listing can't be written in streaming mode.");
+        }
+        let order = match matches.value_of("xref-order").unwrap() {
+            "name" => XrefOrder::Name,
+            "address" => XrefOrder::Address,
+            v => panic!("Error: \"{}\" is not a valid xref order.", v),
+        };
+        let segment = matches.value_of("xref-segment").unwrap();
+        let segment = config.find_segment(segment)
+            .unwrap_or_else(|| panic!("Error: Xref segment {} doesn't exist.", segment));
+        parser.write_xref(order,
+                          segment,
+                          &mut File::create(name).unwrap_or_else(|e| {
+                              panic!("Error: Failed to open xref file \"{}\": {}", name, e)
+                          }));
+    }
+
+    if stream.is_some() {
+        if matches.is_present("combined") {
+            panic!("Error: A combined image can't be written in streaming mode.");
+        }
+        if matches.is_present("verify") {
+            panic!("Error: Outputs can't be verified in streaming mode.");
+        }
+        if matches.is_present("diff") {
+            panic!("Error: Outputs can't be compared in streaming mode.");
+        }
+        if matches.is_present("source-map") {
+            panic!("Error: A source map can't be written in streaming mode.");
+        }
+        if matches.is_present("dump") {
+            panic!("Error: A segment can't be dumped in streaming mode.");
+        }
+        parser.finish_stream();
+        build_stats.phase("output");
+        if print_stats {
+            build_stats.print();
+        }
+        return;
+    }
+
+    if let Some(name) = matches.value_of("source-map") {
+        parser.write_source_map(&mut File::create(name).unwrap_or_else(|e| {
+            panic!("Error: Failed to open source map file \"{}\": {}", name, e)
+        }));
+    }
+
+    if matches.is_present("dump") {
+        let segment = matches.value_of("dump").unwrap_or("0");
+        let segment = config.find_segment(segment)
+            .unwrap_or_else(|| panic!("Error: Dump segment {} doesn't exist.", segment));
+        parser.write_dump(segment, &mut io::stdout());
+    }
+
+    if let Some(name) = matches.value_of("combined") {
+        if formats.len() > 1 {
+            panic!("Error: A combined image can only be written in one format.");
+        }
+        if matches.is_present("diff") {
+            panic!("Error: A combined image can't be compared word by word, but it can be \
+                    verified with --verify.");
+        }
+        let mut up_to_date = true;
+        if matches.is_present("verify") {
+            let mut assembled = Vec::new();
+            parser.output_combined(format, &mut assembled);
+            up_to_date = verify_output(name, &assembled);
+        } else {
+            parser.output_combined(format,
+                                   &mut File::create(name).unwrap_or_else(|e| {
+                                       panic!("Error: Failed to open output file \"{}\": {}",
+                                              name,
+                                              e)
+                                   }));
+        }
+        build_stats.phase("output");
+        if print_stats {
+            build_stats.print();
+        }
+        if !up_to_date {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let targets = outputs.into_iter()
+        .enumerate()
+        .flat_map(|(segment, name)| {
+            formats.iter()
+                .map(|&format| {
+                    if formats.len() > 1 {
+                        (segment, format, format!("{}.{}", name, format_extension(format)))
+                    } else {
+                        (segment, format, name.clone())
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .chain(specs)
+        .collect::<Vec<_>>();
+
+    if matches.is_present("verify") {
+        let mut up_to_date = true;
+        for &(segment, format, ref name) in &targets {
+            let mut assembled = Vec::new();
+            parser.output(format, segment, &mut assembled);
+            up_to_date &= verify_output(name, &assembled);
+        }
+        build_stats.phase("output");
+        if print_stats {
+            build_stats.print();
+        }
+        if !up_to_date {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.is_present("diff") {
+        let mut differing = 0;
+        for &(segment, format, ref name) in &targets {
+            let mut existing = Vec::new();
+            if let Err(e) = File::open(name).and_then(|mut f| f.read_to_end(&mut existing)) {
+                println!("{}: can't be read ({})", name, e);
+                differing += 1;
+                continue;
+            }
+            println!("--- {}", name);
+            let words = parser.write_diff(format, segment, &existing, &mut io::stdout());
+            println!("{} words differ", words);
+            differing += words;
+        }
+        build_stats.phase("output");
+        if print_stats {
+            build_stats.print();
+        }
+        if differing != 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    for &(segment, format, ref name) in &targets {
         parser.output(format,
-                      i,
-                      &mut File::create(&name).unwrap_or_else(|e| {
+                      segment,
+                      &mut File::create(name).unwrap_or_else(|e| {
                           panic!("Error: Failed to open output file \"{}\": {}", name, e)
                       }));
     }
+    build_stats.phase("output");
+    if print_stats {
+        build_stats.print();
+    }
 }
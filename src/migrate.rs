@@ -0,0 +1,33 @@
+use super::config::{rename_fields, LayoutChange, CONFIG_VERSION, LAYOUT_CHANGES};
+use serde_json::{self, Value};
+
+/// Rewrite a config to the current layout, returning a description of every change made. Fields
+/// are renamed and built-in behavior pinned as the layout changes say, so migrating can't drift
+/// from how configs are read.
+pub fn migrate_config(config: &mut Value) -> Result<Vec<String>, String> {
+    let version = {
+        let object = try!(config.as_object()
+            .ok_or_else(|| String::from("the config isn't a JSON object")));
+        object.get("config_version").and_then(|v| v.as_u64()).unwrap_or(1)
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!("version {} of the config layout is newer than this assembler",
+                           version));
+    }
+    let mut changes = try!(rename_fields(config, version));
+    let object = config.as_object_mut().unwrap();
+    for change in LAYOUT_CHANGES {
+        if let LayoutChange::BuiltIn(field, value) = *change {
+            if !object.contains_key(field) {
+                object.insert(field.to_string(), serde_json::from_str(value).unwrap());
+                changes.push(format!("set \"{}\" to the built-in {}", field, value));
+            }
+        }
+    }
+    if object.get("config_version").and_then(|v| v.as_u64()) != Some(CONFIG_VERSION) {
+        object.insert(String::from("config_version"),
+                      serde_json::from_str(&CONFIG_VERSION.to_string()).unwrap());
+        changes.push(format!("set \"config_version\" to {}", CONFIG_VERSION));
+    }
+    Ok(changes)
+}
@@ -1,14 +1,67 @@
-use super::config::{Config, Capture};
-use std::collections::HashMap;
-use std::io::{BufRead, Write};
+use super::config::{Banks, BankUse, Combine, Config, Capture, Directive, ImageField,
+                    NumFeedback, Overflow, Peephole, Relaxation, Rounding, Rule,
+                    SelfReferenceBase, StrCapture, PackOrder};
+use super::expr::{evaluate, evaluate_with_functions};
+use super::stream::Stream;
+use super::elf::write_elf;
+use super::hooks::{ParseHook, RuleEvent, TagEvent};
+use super::plugin::CapturePlugin;
+use regex::Captures;
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::io::{self, BufRead, Write};
+use std::iter::{Cycle, Take};
+use std::slice;
+use std::time::Instant;
+use super::grammar::{Failure, Groups};
 
 #[derive(Debug, Clone, Copy)]
 pub enum OutputFormat {
     LittleEndian,
     BigEndian,
     HexList,
+    /// Intel HEX records of the big-endian byte stream with a type 05 entry record.
+    IntelHex,
+    /// Motorola S-records of the big-endian byte stream with the entry in the termination record.
+    SRecord,
+    /// A big-endian ELF32 executable of the byte stream with DWARF line information.
+    Elf,
 }
 
+/// The order tags are listed in by a cross-reference listing.
+#[derive(Debug, Clone, Copy)]
+pub enum XrefOrder {
+    /// Alphabetically by name.
+    Name,
+    /// By address in the listed segment, then by name.
+    Address,
+}
+
+/// Label file formats understood by emulator debuggers.
+#[derive(Debug, Clone, Copy)]
+pub enum SymbolFormat {
+    /// VICE monitor labels (`al C:addr .name`).
+    Vice,
+    /// no$ debugger `.sym` files (`addr name`).
+    NoCash,
+    /// A MAME debugger script adding a comment with the name at every address.
+    Mame,
+}
+
+impl SymbolFormat {
+    /// What starts a line the debugger ignores.
+    fn comment(&self) -> &'static str {
+        match *self {
+            SymbolFormat::Vice | SymbolFormat::NoCash => ";",
+            SymbolFormat::Mame => "//",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 struct Replacement {
     // Line for purposes of printing errors.
     line: usize,
@@ -20,104 +73,2542 @@ struct Replacement {
     index: usize,
     // The tag to retrieve the position to add.
     tag: String,
+    // The namespace that was active where the tag was used.
+    namespace: String,
+    // For anonymous tags, the amount of anonymous labels in the relevant direction that were
+    // defined before the tag was used.
+    anonymous_index: usize,
     // The segment for which we are retrieving the position from the tag.
     pos_segment: usize,
     // The offset we add after retrieving the value from the tag.
     pos_offset: isize,
+    // If the offset includes the negated position of the reference.
+    relative: bool,
+    // The positions of a redefinable tag that the reference was bound to when it was used.
+    bound: Option<Vec<usize>>,
+    // What is taken from the position of the tag in a segment split into banks, and the bank
+    // the reference is in.
+    bank: BankUse,
+    reference_bank: usize,
+    // The position of the reference in the pos_segment.
+    reference: usize,
+    // The multiple of words the position of the tag must be at.
+    #[serde(default)]
+    target_alignment: Option<usize>,
+    // The width of the field the position goes into.
+    width: Option<u32>,
+    // How the position is combined with the word, and what happens when adding it overflows.
+    combine: Combine,
+    overflow: Overflow,
+}
+
+/// An instruction with a short encoding which is replaced by its long encoding when linking if
+/// its tag references don't fit.
+#[derive(Serialize, Deserialize)]
+struct Relaxable {
+    // The index of the rule of the short encoding and of the long encoding.
+    rule: usize,
+    long_rule: usize,
+    // The position and amount of words of the encoding in each segment.
+    positions: Vec<usize>,
+    lengths: Vec<usize>,
+    // The words of the long encoding, which are taken when it is used.
+    long: Vec<Vec<u64>>,
+    // The tag references of the encoding in use and of the long encoding.
+    replacements: Vec<Replacement>,
+    long_replacements: Vec<Replacement>,
+    relaxed: bool,
+    file: usize,
+    line: usize,
+}
+
+/// An expression which must be true after linking.
+#[derive(Serialize, Deserialize)]
+struct Assertion {
+    expression: String,
+    message: String,
+    // The values of the redefinable and predefined symbols the expression used where it was
+    // asserted, since tags are only looked up when linking.
+    bindings: HashMap<String, i64>,
+    // The namespace tags are looked up from and the segment their positions are taken from.
+    namespace: String,
+    segment: usize,
+    file: usize,
+    line: usize,
+}
+
+/// Everything a parser collected from its inputs, which can be sent between threads and merged
+/// into another parser.
+#[derive(Serialize, Deserialize)]
+pub struct Parsed {
+    segments: Vec<Vec<u64>>,
+    tags: HashMap<String, Vec<usize>>,
+    forward_tags: Vec<(usize, Vec<usize>)>,
+    backward_tags: Vec<(usize, Vec<usize>)>,
+    replacements: Vec<Replacement>,
+    entry: Option<(String, String, usize)>,
+    files: Vec<String>,
+    sources: Vec<Vec<Source>>,
+    definitions: HashMap<String, (usize, usize)>,
+    alignments: Vec<usize>,
+    strict_alignments: Vec<usize>,
+    relaxables: Vec<Relaxable>,
+    errors: Vec<(usize, Diagnostic)>,
+    statements: usize,
+    profile: Vec<RuleProfile>,
+    assertions: Vec<Assertion>,
+}
+
+/// Where in a segment words are being emitted to.
+#[derive(Clone, Copy, PartialEq)]
+enum Placement {
+    // From position 0, before a region or bank is selected.
+    Default,
+    Region(usize),
+    Bank(usize),
+}
+
+/// How much of everything a statement adds to there was before it, so a statement which fails
+/// partway can be undone.
+struct Checkpoint {
+    words: Vec<usize>,
+    sources: Vec<usize>,
+    replacements: usize,
+    relaxables: usize,
+    assertions: usize,
+    forward_tags: usize,
+    backward_tags: usize,
+    alignments: Vec<usize>,
+    strict_alignments: Vec<usize>,
+    positioned: Option<(usize, usize, &'static str)>,
+}
+
+/// The words emitted to a region of a segment.
+struct Placed {
+    region: Placement,
+    start: usize,
+    words: Vec<u64>,
+    sources: Vec<Source>,
 }
 
 pub struct Parser<'a> {
     config: &'a Config,
     segments: Vec<Vec<u64>>,
     tags: HashMap<String, Vec<usize>>,
-    plus_tags: Vec<(usize, Vec<usize>)>,
-    minus_tags: Vec<(usize, Vec<usize>)>,
+    // Tags given a position from outside the source, which is the same in every segment and
+    // isn't moved when linking.
+    fixed_tags: HashSet<String>,
+    // The first value computed from the position it is emitted at (by `__POS__`, an align fill,
+    // or a self-reference) as its file, line, and what it is, since moving words afterwards
+    // would leave it wrong.
+    positioned: Cell<Option<(usize, usize, &'static str)>>,
+    // Redefinable symbols, which are bound at the point of use rather than at link time, and if
+    // they are positions (as opposed to constant values).
+    sets: HashMap<String, (Vec<usize>, bool)>,
+    // Anonymous labels in order of definition with their depth and positions.
+    forward_tags: Vec<(usize, Vec<usize>)>,
+    backward_tags: Vec<(usize, Vec<usize>)>,
     replacements: Vec<Replacement>,
+    // The line and span of columns of the statement being parsed for diagnostics.
+    line: usize,
+    columns: (usize, usize),
+    // The namespace that named tags are currently created in (empty for global).
+    namespace: String,
+    // The segment that rules following the section emit to in place of segment 0.
+    section: usize,
+    // Instructions which may be replaced by their long encoding when linking.
+    relaxables: Vec<Relaxable>,
+    // The region or bank being emitted to in each segment, the position it starts at, and the
+    // words of the other regions and banks that were emitted to.
+    regions: Vec<Placement>,
+    bases: Vec<usize>,
+    placed: Vec<Vec<Placed>>,
+    // The entry point tag, the namespace it was declared in, and the line it was declared on
+    // (0 if it came from the config).
+    entry: Option<(String, String, usize)>,
+    // Where words are written while parsing in streaming mode.
+    stream: Option<Stream>,
+    // The names of the input files and the index of the one being parsed.
+    files: Vec<String>,
+    file: usize,
+    // Where each word of every segment came from.
+    sources: Vec<Vec<Source>>,
+    // The file index and line where each named tag was defined.
+    definitions: HashMap<String, (usize, usize)>,
+    // The largest alignment any rule required in each segment, which inputs merged after these
+    // are padded to, and the largest one that had to be met without padding.
+    alignments: Vec<usize>,
+    strict_alignments: Vec<usize>,
+    // If statements with errors are skipped to keep parsing, and the file index of the errors
+    // found that way.
+    recover: bool,
+    errors: Vec<(usize, Diagnostic)>,
+    // The amount of statements matched by a directive, tag create, or rule.
+    statements: usize,
+    // How often each rule was tried and matched and how long trying it took, if rules are
+    // profiled.
+    profile: Vec<RuleProfile>,
+    // The assertions checked after linking.
+    assertions: Vec<Assertion>,
+    // The warning categories reported as errors instead.
+    denied_warnings: HashSet<String>,
+    // The size of the groups of bytes reversed in outputs (1 leaves them as they are).
+    byte_swap: usize,
+    // Callbacks for every rule match and tag definition.
+    hooks: Vec<Box<ParseHook + 'a>>,
+    // Capture kinds by the name they are used under in the config.
+    plugins: HashMap<String, Box<CapturePlugin + 'a>>,
+}
+
+/// How often a rule was tried against a statement and matched, and how long trying it took in
+/// total.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RuleProfile {
+    pub attempts: usize,
+    pub matches: usize,
+    pub nanoseconds: u64,
+}
+
+/// Statistics about every segment after linking.
+#[derive(Serialize)]
+pub struct Stats {
+    /// The amount of named tags.
+    pub tags: usize,
+    /// The amount of statements matched.
+    pub statements: usize,
+    pub segments: Vec<SegmentStats>,
+}
+
+#[derive(Serialize)]
+pub struct SegmentStats {
+    /// The amount of words emitted.
+    pub words: usize,
+    /// The amount of bytes the words take up in the output.
+    pub bytes: usize,
+    /// The maximum size of the segment in words if it has one.
+    pub capacity: Option<usize>,
+    /// The percentage of the maximum size used.
+    pub used: Option<f64>,
+    /// The amount of tag references filled in when linking.
+    pub replacements: usize,
+}
+
+/// An error found while assembling.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The line the error was found on (0 if it wasn't found on a particular line, like when
+    /// linking).
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Where a word in a segment came from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Source {
+    /// The index of the input file in the parser's files.
+    pub file: usize,
+    /// The line in the input file (0 if the word wasn't emitted from a line, like padding).
+    pub line: usize,
+    /// The index of the rule that emitted the word if it was emitted by a rule.
+    pub rule: Option<usize>,
+    /// If the word is the first one its line (or statement) emitted to the segment, like the
+    /// start of an instruction.
+    #[serde(default)]
+    pub start: bool,
+}
+
+/// The sources of every word in every segment with the names of the files and rules they index
+/// and the config they were assembled with.
+#[derive(Serialize)]
+struct SourceMap {
+    config: String,
+    files: Vec<String>,
+    rules: Vec<String>,
+    segments: Vec<Vec<Source>>,
+}
+
+/// Convert a decimal literal to the bits of a Qm.n fixed point value.
+fn to_fixed(literal: &str,
+            integer_bits: u32,
+            fraction_bits: u32,
+            signed: bool,
+            rounding: Rounding)
+            -> Result<u64, String> {
+    let value = try!(literal.parse::<f64>().map_err(|e| e.to_string()));
+    let scaled = value * (fraction_bits as f64).exp2();
+    let rounded = match rounding {
+        Rounding::Nearest => scaled.round(),
+        Rounding::Down => scaled.floor(),
+        Rounding::Up => scaled.ceil(),
+        Rounding::Zero => scaled.trunc(),
+    };
+    let magnitude_bits = integer_bits + fraction_bits;
+    let (min, max) = if signed {
+        (-(magnitude_bits as f64).exp2(), (magnitude_bits as f64).exp2() - 1.0)
+    } else {
+        (0.0, (magnitude_bits as f64).exp2() - 1.0)
+    };
+    if !(rounded >= min && rounded <= max) {
+        return Err(format!("{} doesn't fit in Q{}.{}", value, integer_bits, fraction_bits));
+    }
+    let width = magnitude_bits + if signed { 1 } else { 0 };
+    let mask = if width >= 64 {
+        !0
+    } else {
+        (1u64 << width) - 1
+    };
+    Ok(rounded as i64 as u64 & mask)
+}
+
+/// Encode a decimal number as packed BCD with at most the given amount of digits.
+fn to_bcd(literal: &str, digits: u32) -> Result<u64, String> {
+    let literal = literal.trim_left_matches('0');
+    if literal.len() > digits as usize {
+        return Err(format!("it has more than {} digits", digits));
+    }
+    let mut val = 0;
+    for c in literal.chars() {
+        let digit = try!(c.to_digit(10).ok_or_else(|| format!("'{}' isn't a decimal digit", c)));
+        val = val << 4 | digit as u64;
+    }
+    Ok(val)
 }
 
 fn shift_left_or_right(a: u64, shift: i32) -> u64 {
+    // Amounts of 64 or more shift every bit out, as shifts in expressions do.
+    let amount = (shift as i64).abs();
+    let amount = if amount >= 64 { 64 } else { amount as u32 };
     if shift < 0 {
-        a >> (-shift)
+        a.checked_shr(amount).unwrap_or(0)
+    } else {
+        a.checked_shl(amount).unwrap_or(0)
+    }
+}
+
+/// The segment a rule following the given section actually uses for one of its segments.
+fn swapped(segment: usize, section: usize) -> usize {
+    if segment == 0 {
+        section
+    } else if segment == section {
+        0
+    } else {
+        segment
+    }
+}
+
+/// Get what is used from a position in a segment split into banks.
+fn bank_address(banks: &Banks, bank: BankUse, position: usize) -> usize {
+    match bank {
+        BankUse::Position => position,
+        BankUse::Bank => position / banks.size,
+        BankUse::Near | BankUse::Far => banks.window + position % banks.size,
+    }
+}
+
+/// Combine a (shifted) value with the field of a word `width` bits wide at a shift like a
+/// feedback says, giving `None` if a sum doesn't fit in the field and that is an error. Replacing,
+/// saturating, and erroring need a width, which the config checks.
+fn combine_value(word: u64,
+                 value: u64,
+                 combine: Combine,
+                 overflow: Overflow,
+                 width: Option<u32>,
+                 shift: i32)
+                 -> Option<u64> {
+    let mask = shift_left_or_right(width.and_then(|w| 1u64.checked_shl(w)).map_or(!0, |m| m - 1),
+                                   shift);
+    match combine {
+        Combine::Add => {}
+        Combine::Or => return Some(word | value),
+        Combine::Xor => return Some(word ^ value),
+        Combine::AndNot => return Some(word & !value),
+        Combine::Replace => return Some((word & !mask) | (value & mask)),
+    }
+    let field = word & mask;
+    match overflow {
+        Overflow::Wrap => Some(word.wrapping_add(value)),
+        Overflow::Saturate => {
+            Some((word & !mask) | field.checked_add(value).map_or(mask, |sum| min(sum, mask)))
+        }
+        Overflow::Error => {
+            field.checked_add(value)
+                .and_then(|sum| if sum & !mask != 0 { None } else { Some((word & !mask) | sum) })
+        }
+        Overflow::Or => Some(word | value),
+    }
+}
+
+/// Cut a value to the width of the field a feedback inserts it into, giving `None` if it doesn't
+/// fit as an unsigned or two's complement number and that is an error.
+fn fit_width(value: u64, width: Option<u32>, overflow: Overflow) -> Option<u64> {
+    let width = match width {
+        Some(width) if width < 64 => width,
+        _ => return Some(value),
+    };
+    let mask = (1 << width) - 1;
+    if value >> width == 0 || (value as i64) >> (width - 1) == -1 {
+        return Some(value & mask);
+    }
+    match overflow {
+        Overflow::Error => None,
+        Overflow::Saturate if (value as i64) < 0 => Some(1 << (width - 1)),
+        Overflow::Saturate => Some(mask),
+        Overflow::Wrap | Overflow::Or => Some(value & mask),
+    }
+}
+
+/// The error for a value which doesn't fit in the field it is inserted into.
+fn too_wide(value: u64, line: usize, width: Option<u32>) -> String {
+    format!("Error: Value {} on line {} doesn't fit in the {}-bit field it is inserted into.",
+            value as i64,
+            line,
+            width.unwrap_or(64))
+}
+
+/// The error for a value which overflowed the field it was added to.
+fn overflowed(value: u64,
+              line: usize,
+              width: Option<u32>,
+              config: &Config,
+              segment: usize)
+              -> String {
+    format!("Error: Adding 0x{:X} on line {} overflows the {}-bit field it is added to in segment \
+             {}.",
+            value,
+            line,
+            width.unwrap_or(64),
+            config.segment_name(segment))
+}
+
+/// Check that the position of a tag is at the multiple of words a reference to it requires.
+fn check_target_alignment(tag: &str,
+                          line: usize,
+                          position: usize,
+                          alignment: Option<usize>)
+                          -> Result<(), String> {
+    if let Some(alignment) = alignment {
+        if position % alignment != 0 {
+            return Err(format!("Error: Tag \"{}\" used on line {} is at {}, but the reference \
+                                requires a multiple of {}.",
+                               tag,
+                               line,
+                               position,
+                               alignment));
+        }
+    }
+    Ok(())
+}
+
+/// The most words one fill or reservation can add, so a huge count is an error rather than
+/// running out of memory.
+const MAX_FILL: usize = 1 << 24;
+
+/// Check that a count of words to fill with is at most `MAX_FILL`.
+fn check_fill(count: usize, line: usize) -> Result<(), String> {
+    if count > MAX_FILL {
+        return Err(format!("Error: Filling {} words on line {} is more than the {} words one fill \
+                            can add.",
+                           count,
+                           line,
+                           MAX_FILL));
+    }
+    Ok(())
+}
+
+/// Push words repeating a pattern from its start.
+fn pad(words: &mut Vec<u64>, count: usize, pattern: &[u64]) {
+    words.extend(pattern.iter().cycle().take(count));
+}
+
+/// The amount of bytes the fields of a header or trailer starting at an offset take.
+fn fields_len(fields: &[ImageField], offset: usize) -> usize {
+    fields.iter().fold(offset, |offset, field| match *field {
+        ImageField::Bytes(ref b) => offset + b.len(),
+        ImageField::Value { size, .. } => offset + size,
+        ImageField::Pad { offset: to, .. } => max(offset, to),
+    }) - offset
+}
+
+/// The CRC-32 (as used by zlib and PNG) of some bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ b as u32,
+                    |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 })
+    })
+}
+
+fn write_ihex_record<W>(w: &mut W, segment: usize, address: u16, kind: u8, data: &[u8])
+    where W: Write
+{
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend_from_slice(data);
+    let checksum = record.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    record.push(0u8.wrapping_sub(checksum));
+    let line = record.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    writeln!(w, ":{}", line).unwrap_or_else(|e| {
+        panic!("Error: Writing to output file for segment {} failed: {}",
+               segment,
+               e);
+    });
+}
+
+fn write_srecord<W>(w: &mut W,
+                    segment: usize,
+                    kind: usize,
+                    address_len: usize,
+                    address: u64,
+                    data: &[u8])
+    where W: Write
+{
+    let mut record = vec![(address_len + data.len() + 1) as u8];
+    for i in (0..address_len).rev() {
+        record.push((address >> (i * 8)) as u8);
+    }
+    record.extend_from_slice(data);
+    let checksum = record.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    record.push(!checksum);
+    let line = record.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    writeln!(w, "S{}{}", kind, line).unwrap_or_else(|e| {
+        panic!("Error: Writing to output file for segment {} failed: {}",
+               segment,
+               e);
+    });
+}
+
+/// The bytes of a segment placed at a byte address of an image.
+struct Block {
+    segment: usize,
+    address: u64,
+    bytes: Vec<u8>,
+}
+
+/// Write Intel HEX records of blocks with an extended linear address record whenever they cross
+/// into another 64K, followed by the entry point in the segment of the last block if there is
+/// one.
+fn write_intel_hex<W>(w: &mut W, blocks: &[Block], entry: Option<u64>)
+    where W: Write
+{
+    use byteorder::{ByteOrder, BigEndian};
+    let mut upper = 0;
+    let mut segment = 0;
+    for block in blocks {
+        segment = block.segment;
+        if block.address + block.bytes.len() as u64 > 0x100000000 {
+            panic!("Error: Segment {} goes past the 4G bytes Intel HEX can address.", segment);
+        }
+        let mut offset = 0;
+        while offset < block.bytes.len() {
+            let address = block.address + offset as u64;
+            // Records can't cross into another 64K.
+            let len = min(min(16, block.bytes.len() - offset),
+                          0x10000 - (address & 0xFFFF) as usize);
+            if address >> 16 != upper {
+                upper = address >> 16;
+                write_ihex_record(w, segment, 0, 4, &[(upper >> 8) as u8, upper as u8]);
+            }
+            write_ihex_record(w, segment, address as u16, 0, &block.bytes[offset..offset + len]);
+            offset += len;
+        }
+    }
+    if let Some(entry) = entry {
+        let mut data = [0u8; 4];
+        BigEndian::write_u32(&mut data, entry as u32);
+        write_ihex_record(w, segment, 0, 5, &data);
+    }
+    write_ihex_record(w, segment, 0, 1, &[]);
+}
+
+/// Write S-records of blocks with the smallest address size that fits both the data and the
+/// entry point, which is given in the termination record.
+fn write_srecords<W>(w: &mut W, blocks: &[Block], entry: Option<u64>)
+    where W: Write
+{
+    let highest = blocks.iter()
+        .map(|b| b.address + b.bytes.len() as u64)
+        .chain(entry)
+        .max()
+        .unwrap_or(0);
+    let address_len = if highest <= 0x10000 {
+        2
+    } else if highest <= 0x1000000 {
+        3
+    } else if highest <= 0x100000000 {
+        4
+    } else {
+        panic!("Error: The image goes past the 4G bytes S-records can address.");
+    };
+    let segment = blocks.first().map_or(0, |b| b.segment);
+    write_srecord(w, segment, 0, 2, 0, &[]);
+    for block in blocks {
+        for (i, chunk) in block.bytes.chunks(16).enumerate() {
+            write_srecord(w,
+                          block.segment,
+                          address_len - 1,
+                          address_len,
+                          block.address + (i * 16) as u64,
+                          chunk);
+        }
+    }
+    write_srecord(w, segment, 11 - address_len, address_len, entry.unwrap_or(0), &[]);
+}
+
+/// The direction and depth of an anonymous label.
+enum Anonymous {
+    Forward(usize),
+    Backward(usize),
+}
+
+/// Determine if a tag is an anonymous label (a repetition of the configured characters).
+fn anonymous_label(config: &Config, tag: &str) -> Option<Anonymous> {
+    config.anonymous_labels.as_ref().and_then(|al| {
+        if tag.is_empty() {
+            None
+        } else if tag.chars().all(|c| c == al.forward) {
+            Some(Anonymous::Forward(tag.chars().count()))
+        } else if tag.chars().all(|c| c == al.backward) {
+            Some(Anonymous::Backward(tag.chars().count()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Remove comments from a line, tracking if a block comment is still open at the end of it.
+/// Block comments are replaced by spaces so that columns are preserved. Comment delimiters
+/// inside quotes are ignored, and a backslash escapes the next character inside quotes.
+pub fn strip_comments(config: &Config, line: &str, in_block: &mut bool) -> String {
+    let mut out = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut pos = 0;
+    while pos < line.len() {
+        let rest = &line[pos..];
+        let c = rest.chars().next().unwrap();
+        if *in_block {
+            let end = &config.block_comment.as_ref().unwrap().1;
+            if rest.starts_with(end.as_str()) {
+                out.extend(end.chars().map(|_| ' '));
+                pos += end.len();
+                *in_block = false;
+            } else {
+                out.push(' ');
+                pos += c.len_utf8();
+            }
+            continue;
+        }
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+        } else if config.quotes.contains(&c) {
+            quote = Some(c);
+        } else if config.line_comments.iter().any(|delimiter| rest.starts_with(delimiter.as_str())) {
+            return out;
+        } else if let Some((ref start, _)) = config.block_comment {
+            if rest.starts_with(start.as_str()) {
+                out.extend(start.chars().map(|_| ' '));
+                pos += start.len();
+                *in_block = true;
+                continue;
+            }
+        }
+        out.push(c);
+        pos += c.len_utf8();
+    }
+    out
+}
+
+/// Split a line into statements on the configured separator (outside of quotes), keeping the
+/// byte offset of each statement.
+fn split_statements<'b>(config: &Config, line: &'b str) -> Vec<(&'b str, usize)> {
+    let separator = match config.statement_separator {
+        Some(ref separator) => separator,
+        None => return vec![(line, 0)],
+    };
+    let mut statements = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < line.len() {
+        let c = line[pos..].chars().next().unwrap();
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+        } else if config.quotes.contains(&c) {
+            quote = Some(c);
+        } else if line[pos..].starts_with(separator.as_str()) {
+            statements.push((&line[start..pos], start));
+            pos += separator.len();
+            start = pos;
+            continue;
+        }
+        pos += c.len_utf8();
+    }
+    statements.push((&line[start..], start));
+    statements
+}
+
+/// Get the 1-based column of a byte offset in a line.
+fn column(line: &str, offset: usize) -> usize {
+    line[..offset].chars().count() + 1
+}
+
+/// Split a line on whitespace, keeping the byte offset of each word.
+fn words(line: &str) -> Vec<(&str, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push((&line[s..i], s));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((&line[s..], s));
+    }
+    words
+}
+
+/// Split a line into tokens using the config's token classes, returning each token with its
+/// first and last column, or the column where no token class matched.
+fn tokenize<'b>(config: &Config, line: &'b str) -> Result<Vec<(&'b str, usize, usize)>, usize> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    loop {
+        pos += line[pos..].len() - line[pos..].trim_left().len();
+        if pos == line.len() {
+            return Ok(tokens);
+        }
+        let len = config.token_classes
+            .iter()
+            .filter_map(|tc| tc.regex.as_ref().unwrap().find(&line[pos..]))
+            .map(|(_, end)| end)
+            .find(|&end| end != 0);
+        match len {
+            Some(len) => {
+                tokens.push((&line[pos..pos + len], column(line, pos), column(line, pos + len) - 1));
+                pos += len;
+            }
+            None => return Err(column(line, pos)),
+        }
+    }
+}
+
+/// Qualify a tag name with a namespace.
+fn qualify(separator: &str, namespace: &str, tag: &str) -> String {
+    if namespace.is_empty() {
+        tag.to_string()
     } else {
-        a << shift
+        format!("{}{}{}", namespace, separator, tag)
+    }
+}
+
+/// Get the text of a capture group which a directive or tag create needs, since the group may
+/// be optional in its regex.
+fn required_group<'t>(caps: &Captures<'t>,
+                      index: usize,
+                      segment: &str,
+                      line: usize)
+                      -> Result<&'t str, String> {
+    caps.at(index).ok_or_else(|| {
+        format!("Error: Capture group {} isn't given in \"{}\" on line {}.",
+                index,
+                segment,
+                line)
+    })
+}
+
+/// Look a tag up from a namespace, preferring a tag in that namespace over a global one.
+/// Tags which already contain the separator are treated as fully qualified.
+fn lookup_tag<'b, T>(tags: &'b HashMap<String, T>,
+                     separator: &str,
+                     namespace: &str,
+                     tag: &str)
+                     -> Option<&'b T> {
+    if namespace.is_empty() || tag.contains(separator) {
+        tags.get(tag)
+    } else {
+        tags.get(&format!("{}{}{}", namespace, separator, tag)).or_else(|| tags.get(tag))
+    }
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Parser {
+            config: config,
+            segments: {
+                let mut v = Vec::new();
+                for _ in 0..config.segment_widths.len() {
+                    v.push(Vec::new());
+                }
+                v
+            },
+            tags: HashMap::new(),
+            fixed_tags: HashSet::new(),
+            positioned: Cell::new(None),
+            sets: HashMap::new(),
+            forward_tags: Vec::new(),
+            backward_tags: Vec::new(),
+            replacements: Vec::new(),
+            line: 0,
+            columns: (0, 0),
+            namespace: String::new(),
+            section: 0,
+            relaxables: Vec::new(),
+            regions: config.segment_widths.iter().map(|_| Placement::Default).collect(),
+            bases: config.segment_widths.iter().map(|_| 0).collect(),
+            placed: config.segment_widths.iter().map(|_| Vec::new()).collect(),
+            entry: config.entry.as_ref().map(|tag| (tag.clone(), String::new(), 0)),
+            stream: None,
+            files: Vec::new(),
+            file: 0,
+            sources: config.segment_widths.iter().map(|_| Vec::new()).collect(),
+            definitions: HashMap::new(),
+            alignments: config.segment_widths.iter().map(|_| 1).collect(),
+            strict_alignments: config.segment_widths.iter().map(|_| 1).collect(),
+            recover: false,
+            errors: Vec::new(),
+            statements: 0,
+            profile: Vec::new(),
+            assertions: Vec::new(),
+            denied_warnings: HashSet::new(),
+            byte_swap: 1,
+            hooks: Vec::new(),
+            plugins: HashMap::new(),
+        }
+    }
+
+    /// Define a constant symbol as if it was set in the source before anything was parsed.
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.sets.insert(self.config.tag_key(name),
+                         (vec![value as usize; self.segments.len()], false));
+    }
+
+    /// Define a tag at a fixed position in every segment (like the address of a routine in ROM
+    /// or of a hardware register), which references to it resolve to and which linking doesn't
+    /// move. This is done after parsing, since the tag may not also be defined in the source.
+    pub fn define_tag(&mut self, name: &str, position: usize) {
+        let key = self.config.tag_key(name);
+        if self.fixed_tags.contains(&key) {
+            panic!("Error: External tag \"{}\" is defined more than once.", name);
+        }
+        if self.tags.contains_key(&key) {
+            panic!("Error: External tag \"{}\" is also defined in the source.", name);
+        }
+        self.tags.insert(key.clone(), vec![position; self.segments.len()]);
+        self.fixed_tags.insert(key);
+    }
+
+    /// Get the value of a predefined symbol: `__LINE__` for the current line, `__POS__` for the
+    /// position in segment 0, and `__POS_<segment>__` for the position in a segment by name or
+    /// index. Symbols set in the source take precedence over these.
+    fn predefined(&self, name: &str) -> Option<i64> {
+        match name {
+            "__LINE__" => Some(self.line as i64),
+            "__POS__" => {
+                self.note_position_use("__POS__");
+                Some(self.segment_len(0) as i64)
+            }
+            _ if name.starts_with("__POS_") && name.ends_with("__") && name.len() > 8 => {
+                self.config
+                    .find_segment(&name[6..name.len() - 2])
+                    .map(|segment| {
+                        self.note_position_use("__POS__");
+                        self.segment_len(segment) as i64
+                    })
+            }
+            _ => None,
+        }
+    }
+
+    /// Remember that a value on the current line was computed from its position.
+    fn note_position_use(&self, what: &'static str) {
+        if self.positioned.get().is_none() {
+            self.positioned.set(Some((self.file, self.line, what)));
+        }
+    }
+
+    /// Fail if a value was computed from its position, since something is about to move words.
+    fn check_unpositioned(&self, mover: &str) -> Result<(), String> {
+        if let Some((file, line, what)) = self.positioned.get() {
+            return Err(format!("Error: {} on {}:{} depends on its position, which {} changes.",
+                               what,
+                               self.files.get(file).map_or("<input>", |f| &f[..]),
+                               line,
+                               mover));
+        }
+        Ok(())
+    }
+
+    /// Fail if a value was computed from its position or words were aligned, since something is
+    /// about to move words after they were emitted.
+    fn check_unmoved(&self, mover: &str) -> Result<(), String> {
+        try!(self.check_unpositioned(mover));
+        let aligned = self.alignments.iter().zip(&self.strict_alignments).enumerate();
+        for (segment, (&words, &strict_words)) in aligned {
+            if max(words, strict_words) > 1 {
+                return Err(format!("Error: Words in segment {} are aligned to {} words, which {} \
+                                    changes.",
+                                   self.config.segment_name(segment),
+                                   max(words, strict_words),
+                                   mover));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the text of a predefined string, used in place of a string capture which is exactly
+    /// its name: `__FILE__` for the current input file and `__VERSION__` for the assembler's
+    /// version.
+    fn predefined_string(&self, name: &str) -> Option<String> {
+        match name {
+            "__FILE__" => Some(self.files.get(self.file).map_or("<input>", |f| &f[..]).to_string()),
+            "__VERSION__" => Some(env!("CARGO_PKG_VERSION").to_string()),
+            _ => None,
+        }
+    }
+
+    /// Get the constant symbols set so far, sorted by name, for sources which only define
+    /// symbols (like libraries loaded before the inputs).
+    pub fn constants(&self) -> Vec<(String, i64)> {
+        if self.segments.iter().any(|s| !s.is_empty()) || !self.tags.is_empty() ||
+           !self.replacements.is_empty() {
+            panic!("Error: Libraries may only set symbols, but this one emits words or creates \
+                    tags.");
+        }
+        let mut constants = self.sets
+            .iter()
+            .filter(|&(_, &(_, position))| !position)
+            .map(|(name, &(ref values, _))| (name.clone(), values[0] as i64))
+            .collect::<Vec<_>>();
+        constants.sort();
+        constants
+    }
+
+    /// Skip the rest of a statement when it has an error and keep parsing, collecting the errors
+    /// rather than panicking on the first one.
+    pub fn recover_errors(&mut self) {
+        self.recover = true;
+    }
+
+    /// Every error found while parsing with recovery, with the name of the file it was found in.
+    pub fn errors(&self) -> Vec<(&str, &Diagnostic)> {
+        self.errors
+            .iter()
+            .map(|&(file, ref d)| (self.files.get(file).map_or("<input>", |f| &f[..]), d))
+            .collect()
+    }
+
+    /// Record an error found while parsing with recovery, or return it otherwise.
+    fn recovered(&mut self, line: usize, message: String) -> Result<(), String> {
+        if !self.recover {
+            return Err(message);
+        }
+        self.errors.push((self.file,
+                          Diagnostic {
+                              line: line,
+                              message: message,
+                          }));
+        Ok(())
+    }
+
+    /// Report warnings of a category as errors, or warnings of every category for `all`.
+    pub fn deny_warning(&mut self, category: &str) {
+        self.denied_warnings.insert(category.to_string());
+    }
+
+    /// Count how often each rule is tried and matches and time how long trying it takes.
+    pub fn profile_rules(&mut self) {
+        self.profile = vec![RuleProfile::default(); self.config.rules.len()];
+    }
+
+    /// The profile of every rule in the order of the config, which is empty if rules aren't
+    /// profiled.
+    pub fn rule_profile(&self) -> &[RuleProfile] {
+        &self.profile
+    }
+
+    /// Reverse every group of some amount of bytes in outputs (e.g. 2 to swap byte pairs for a
+    /// programmer with the opposite byte order), without changing the words themselves.
+    pub fn swap_bytes(&mut self, group: usize) {
+        if group == 0 {
+            panic!("Error: Bytes can't be swapped in groups of 0.");
+        }
+        self.byte_swap = group;
+    }
+
+    /// Reverse the groups of bytes of an output if bytes are swapped.
+    fn swapped_bytes(&self, segment: usize, mut bytes: Vec<u8>) -> Vec<u8> {
+        if bytes.len() % self.byte_swap != 0 {
+            panic!("Error: The output of segment {} has {} bytes, which can't be swapped in \
+                    groups of {}.",
+                   self.config.segment_name(segment),
+                   bytes.len(),
+                   self.byte_swap);
+        }
+        for group in bytes.chunks_mut(self.byte_swap) {
+            group.reverse();
+        }
+        bytes
+    }
+
+    /// Print a warning of a category, or fail with it if the category is denied.
+    fn warn(&self, category: &str, message: &str) -> Result<(), String> {
+        if self.denied_warnings.contains(category) || self.denied_warnings.contains("all") {
+            return Err(format!("Error: {} (denied warning \"{}\").", message, category));
+        }
+        writeln!(io::stderr(), "Warning: {} [{}]", message, category)
+            .map_err(|e| format!("Error: Failed to write a warning: {}", e))
+    }
+
+    /// Add callbacks to be made as rules match and tags are defined.
+    pub fn add_hook(&mut self, hook: Box<ParseHook + 'a>) {
+        self.hooks.push(hook);
+    }
+
+    /// Register a capture kind to be used by `Plugin` captures with the given name.
+    pub fn register_capture(&mut self, name: &str, plugin: Box<CapturePlugin + 'a>) {
+        self.plugins.insert(name.to_string(), plugin);
+    }
+
+    /// Give every hook the words a rule emitted since the given positions.
+    fn run_rule_hooks(&mut self,
+                      rule_index: usize,
+                      caps: &Groups,
+                      text: &str,
+                      line: usize,
+                      positions: &[usize]) {
+        let rule = &self.config.rules[rule_index];
+        let captures = (1..caps.len())
+            .map(|i| caps.at(i).or_else(|| rule.capture_default(i - 1)))
+            .collect::<Vec<_>>();
+        let values = self.segments
+            .iter()
+            .enumerate()
+            .map(|(i, words)| &words[words.len() + positions[i] - self.segment_len(i)..])
+            .collect::<Vec<_>>();
+        let event = RuleEvent {
+            rule: rule.name(),
+            index: rule_index,
+            text: text,
+            captures: &captures,
+            values: &values,
+            positions: positions,
+            file: self.files.get(self.file).map_or("<input>", |f| &f[..]),
+            line: line,
+        };
+        for hook in &mut self.hooks {
+            hook.rule(&event);
+        }
+    }
+
+    /// The sources of the words of a segment.
+    pub fn sources(&self, segment: usize) -> &[Source] {
+        &self.sources[segment]
+    }
+
+    /// The name of an input file by its index in sources.
+    pub fn file_name(&self, file: usize) -> &str {
+        self.files.get(file).map_or("<input>", |f| &f[..])
+    }
+
+    /// Set the name of the input file that the next parsed input comes from.
+    pub fn set_file(&mut self, name: &str) {
+        self.file = self.files.len();
+        self.files.push(name.to_string());
+    }
+
+    /// Attribute every word which doesn't have a source yet to the given source, marking the
+    /// first of them in each segment as the start.
+    fn sync_sources(&mut self, source: Source) {
+        for (sources, segment) in self.sources.iter_mut().zip(&self.segments) {
+            let mut source = Source { start: true, ..source };
+            while sources.len() < segment.len() {
+                sources.push(source);
+                source.start = false;
+            }
+        }
+    }
+
+    /// Attribute every word which doesn't have a source yet to padding, which isn't emitted from
+    /// a line or by a rule, so it isn't taken for the start of what follows it.
+    fn sync_padding(&mut self) {
+        let source = Source {
+            file: self.file,
+            line: 0,
+            rule: None,
+            start: false,
+        };
+        for (sources, segment) in self.sources.iter_mut().zip(&self.segments) {
+            while sources.len() < segment.len() {
+                sources.push(source);
+            }
+        }
+    }
+
+    /// Write a JSON source map of where each word of every segment came from.
+    pub fn write_source_map<W>(&self, w: &mut W)
+        where W: Write
+    {
+        use serde_json::to_writer;
+        let map = SourceMap {
+            config: self.config.identity(),
+            files: if self.files.is_empty() {
+                vec![String::from("<input>")]
+            } else {
+                self.files.clone()
+            },
+            rules: self.config.rules.iter().map(|r| r.name().to_string()).collect(),
+            segments: self.sources.clone(),
+        };
+        to_writer(w, &map)
+            .unwrap_or_else(|e| panic!("Error: Failed to write the source map: {}", e));
+    }
+
+    /// Write the byte address of every named tag in a segment in an emulator's label format.
+    pub fn write_symbols<W>(&self, format: SymbolFormat, segment: usize, w: &mut W)
+        where W: Write
+    {
+        let width = self.config.segment_widths[segment];
+        let base = self.config.segments[segment].base_address;
+        let mut symbols = self.tags
+            .iter()
+            .map(|(name, positions)| (base + (positions[segment] * width) as u64, name))
+            .collect::<Vec<_>>();
+        symbols.sort();
+        writeln!(w, "{} Config: {}", format.comment(), self.config.identity())
+            .unwrap_or_else(|e| panic!("Error: Failed to write the symbol file: {}", e));
+        for (address, name) in symbols {
+            match format {
+                SymbolFormat::Vice => writeln!(w, "al C:{:04x} .{}", address, name),
+                SymbolFormat::NoCash => writeln!(w, "{:08X} {}", address, name),
+                SymbolFormat::Mame => writeln!(w, "comadd {:X},{}", address, name),
+            }
+            .unwrap_or_else(|e| panic!("Error: Failed to write the symbol file: {}", e));
+        }
+    }
+
+    /// Gather statistics about every segment.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            tags: self.tags.len(),
+            statements: self.statements,
+            segments: self.config
+                .segments
+                .iter()
+                .enumerate()
+                .map(|(index, sc)| {
+                    let words = self.segment_len(index);
+                    SegmentStats {
+                        words: words,
+                        bytes: words * self.config.segment_widths[index],
+                        capacity: sc.max_size,
+                        used: sc.max_size.map(|max| 100.0 * words as f64 / max as f64),
+                        replacements: self.replacements
+                            .iter()
+                            .filter(|r| r.add_segment == index)
+                            .count(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// The name of the tag a tag used in a namespace refers to, which is the tag of that
+    /// namespace before the global one.
+    fn qualified_tag(&self, namespace: &str, tag: &str) -> String {
+        let separator = &self.config.namespace_separator;
+        let qualified = format!("{}{}{}", namespace, separator, tag);
+        if !namespace.is_empty() && !tag.contains(&separator[..]) &&
+           self.tags.contains_key(&qualified) {
+            qualified
+        } else {
+            tag.to_string()
+        }
+    }
+
+    /// Every named tag which isn't referred to by a rule, the entry point, a vector, or an
+    /// assertion, sorted by name.
+    pub fn unused_tags(&self) -> Vec<&str> {
+        let config = self.config;
+        let mut used = HashSet::new();
+        for r in self.replacements.iter().chain(self.relaxables.iter().flat_map(|x| {
+            x.replacements.iter().chain(x.long_replacements.iter())
+        })) {
+            used.insert(self.qualified_tag(&r.namespace, &r.tag));
+        }
+        if let Some((ref tag, ref namespace, _)) = self.entry {
+            used.insert(self.qualified_tag(namespace, tag));
+        }
+        for vector in &config.vectors {
+            used.insert(config.tag_key(&vector.tag));
+        }
+        for assertion in &self.assertions {
+            let symbols = RefCell::new(Vec::new());
+            let _ = evaluate(&assertion.expression, &|symbol: &str| {
+                if !assertion.bindings.contains_key(symbol) {
+                    symbols.borrow_mut().push(config.tag_key(symbol));
+                }
+                Some(0)
+            });
+            for symbol in symbols.into_inner() {
+                used.insert(self.qualified_tag(&assertion.namespace, &symbol));
+            }
+        }
+        let mut unused = self.tags
+            .keys()
+            .filter(|name| !used.contains(*name))
+            .map(|name| &name[..])
+            .collect::<Vec<_>>();
+        unused.sort();
+        unused
+    }
+
+    /// Write a cross-reference listing of every named tag with its byte address in a segment, where
+    /// it was defined, and every line that refers to it.
+    pub fn write_xref<W>(&self, order: XrefOrder, segment: usize, w: &mut W)
+        where W: Write
+    {
+        let mut references: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        for r in self.replacements.iter().chain(self.relaxables.iter().flat_map(|x| {
+            x.replacements.iter()
+        })) {
+            if anonymous_label(self.config, &r.tag).is_some() {
+                continue;
+            }
+            let name = self.qualified_tag(&r.namespace, &r.tag);
+            if let Some(source) = self.sources[r.add_segment].get(r.index) {
+                references.entry(name).or_insert_with(Vec::new).push((source.file, source.line));
+            }
+        }
+
+        let width = self.config.segment_widths[segment];
+        let base = self.config.segments[segment].base_address;
+        let mut tags = self.tags
+            .iter()
+            .map(|(name, positions)| (base + (positions[segment] * width) as u64, &name[..]))
+            .collect::<Vec<_>>();
+        match order {
+            XrefOrder::Name => tags.sort_by(|a, b| a.1.cmp(b.1)),
+            XrefOrder::Address => tags.sort(),
+        }
+        let location = |&(file, line): &(usize, usize)| {
+            format!("{}:{}", self.files.get(file).map_or("<input>", |f| &f[..]), line)
+        };
+        writeln!(w, "Config: {}", self.config.identity())
+            .unwrap_or_else(|e| panic!("Error: Failed to write the xref listing: {}", e));
+        for (address, name) in tags {
+            let mut lines = references.remove(name).unwrap_or_else(Vec::new);
+            lines.sort();
+            lines.dedup();
+            writeln!(w,
+                     "{} {:04x} {}\n    {}",
+                     name,
+                     address,
+                     self.definitions.get(name).map_or(String::from("<config>"), &location),
+                     if lines.is_empty() {
+                         String::from("(unreferenced)")
+                     } else {
+                         lines.iter().map(&location).collect::<Vec<_>>().join(" ")
+                     })
+                .unwrap_or_else(|e| panic!("Error: Failed to write the xref listing: {}", e));
+        }
+    }
+
+    /// Write every word of a segment which differs from an existing output of it in a format as
+    /// its byte address, old word, new word, and the line that emitted it, returning the amount
+    /// of differing words. Only formats with one word per segment word can be compared.
+    pub fn write_diff<W>(&self, format: OutputFormat, segment: usize, existing: &[u8], w: &mut W)
+                         -> usize
+        where W: Write
+    {
+        use byteorder::{ByteOrder, LittleEndian, BigEndian};
+        let width = self.config.segment_widths[segment];
+        let old = match format {
+            OutputFormat::LittleEndian => {
+                existing.chunks(width).map(|c| LittleEndian::read_uint(c, c.len())).collect()
+            }
+            OutputFormat::BigEndian => {
+                existing.chunks(width).map(|c| BigEndian::read_uint(c, c.len())).collect()
+            }
+            OutputFormat::HexList => {
+                String::from_utf8_lossy(existing)
+                    .lines()
+                    .map(|l| u64::from_str_radix(l.trim(), 16).unwrap_or(0))
+                    .collect::<Vec<_>>()
+            }
+            _ => {
+                panic!("Error: Only little-endian, big-endian, and hex-list outputs can be \
+                        compared word by word.")
+            }
+        };
+        let new = &self.segments[segment];
+        let base = self.config.segments[segment].base_address;
+        let show = |word: Option<&u64>| word.map_or_else(|| format!("{:-<1$}", "", width * 2),
+                                                         |w| format!("{:01$X}", w, width * 2));
+        let mut differing = 0;
+        for index in 0..max(old.len(), new.len()) {
+            if old.get(index) == new.get(index) {
+                continue;
+            }
+            differing += 1;
+            let source = match self.sources[segment].get(index) {
+                Some(source) if source.line != 0 => {
+                    format!("{}:{}",
+                            self.files.get(source.file).map_or("<input>", |f| &f[..]),
+                            source.line)
+                }
+                Some(_) => String::from("(padding)"),
+                None => String::from("(removed)"),
+            };
+            writeln!(w,
+                     "{:08X}  {} -> {}  {}",
+                     base + (index * width) as u64,
+                     show(old.get(index)),
+                     show(new.get(index)),
+                     source)
+                .unwrap_or_else(|e| panic!("Error: Failed to write the diff: {}", e));
+        }
+        differing
+    }
+
+    /// Write a dump of a segment with the byte address of each line, the words in hex, and the
+    /// big-endian bytes of the words as ASCII ('.' where they aren't printable).
+    pub fn write_dump<W>(&self, segment: usize, w: &mut W)
+        where W: Write
+    {
+        use byteorder::{ByteOrder, BigEndian};
+        let width = self.config.segment_widths[segment];
+        let base = self.config.segments[segment].base_address;
+        let per_line = max(1, 16 / width);
+        let mut bytes = [0u8; 8];
+        for (line, words) in self.segments[segment].chunks(per_line).enumerate() {
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for word in words {
+                BigEndian::write_u64(&mut bytes, *word);
+                hex.push_str(&format!("{:01$X} ", word, width * 2));
+                ascii.extend(bytes[(8 - width)..].iter().map(|&b| {
+                    if b >= 0x20 && b < 0x7F { b as char } else { '.' }
+                }));
+            }
+            writeln!(w,
+                     "{0:08X}  {1:2$} |{3}|",
+                     base + (line * per_line * width) as u64,
+                     hex,
+                     per_line * (width * 2 + 1),
+                     ascii)
+                .unwrap_or_else(|e| panic!("Error: Failed to write the dump: {}", e));
+        }
+    }
+
+    /// Write a ctags file of where every named tag was defined.
+    pub fn write_tags<W>(&self, w: &mut W)
+        where W: Write
+    {
+        let mut definitions = self.definitions.iter().collect::<Vec<_>>();
+        definitions.sort();
+        let mut tags = String::from("!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+                                     !_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n");
+        for (name, &(file, line)) in definitions {
+            tags.push_str(&format!("{}\t{}\t{};\"\tl\n",
+                                   name,
+                                   self.files.get(file).map_or("<input>", |f| &f[..]),
+                                   line));
+        }
+        w.write_all(tags.as_bytes()).unwrap_or_else(|e| {
+            panic!("Error: Failed to write the tags file: {}", e)
+        });
+    }
+
+    /// Write words to a stream as they are parsed rather than keeping them until output.
+    pub fn stream_to(&mut self, stream: Stream) {
+        if self.config.segments.iter().any(|sc| !sc.header.is_empty() || !sc.trailer.is_empty()) {
+            panic!("Error: Segment headers and trailers can't be written in streaming mode.");
+        }
+        if self.config.segments.iter().any(|sc| sc.mirrors.map_or(false, |m| m > 1)) {
+            panic!("Error: Mirrored segments can't be written in streaming mode.");
+        }
+        self.stream = Some(stream);
+    }
+
+    /// Write out every word still kept in memory in streaming mode. This should be called after
+    /// linking.
+    pub fn finish_stream(&mut self) {
+        self.flush_stream(true);
+    }
+
+    /// Write the words of segments which have buffered enough of them (or all of them).
+    fn flush_stream(&mut self, all: bool) {
+        if let Some(ref mut stream) = self.stream {
+            for (index, (segment, sources)) in self.segments
+                .iter_mut()
+                .zip(self.sources.iter_mut())
+                .enumerate() {
+                if all || segment.len() >= stream.threshold {
+                    stream.write(index, segment);
+                    segment.clear();
+                    sources.clear();
+                }
+            }
+        }
+    }
+
+    /// The position after the last word of a segment, which includes words already written in
+    /// streaming mode and the start of the region being emitted to.
+    fn segment_len(&self, segment: usize) -> usize {
+        self.bases[segment] + self.stream.as_ref().map_or(0, |s| s.flushed[segment]) +
+        self.segments[segment].len()
+    }
+
+    /// Set aside the words of the region being emitted to in a segment and continue emitting to
+    /// another region where it was left off.
+    fn select_region(&mut self, segment: usize, region: Placement) {
+        let words = mem::replace(&mut self.segments[segment], Vec::new());
+        let sources = mem::replace(&mut self.sources[segment], Vec::new());
+        self.placed[segment].push(Placed {
+            region: self.regions[segment],
+            start: self.bases[segment],
+            words: words,
+            sources: sources,
+        });
+        if let Some(index) = self.placed[segment].iter().position(|p| p.region == region) {
+            let placed = self.placed[segment].remove(index);
+            self.segments[segment] = placed.words;
+            self.sources[segment] = placed.sources;
+        }
+        let sc = &self.config.segments[segment];
+        self.regions[segment] = region;
+        self.bases[segment] = match region {
+            Placement::Default => 0,
+            Placement::Region(r) => sc.regions[r].start,
+            Placement::Bank(bank) => bank * sc.banks.as_ref().unwrap().size,
+        };
+    }
+
+    /// Put the words of every region at their positions in their segments, filling the gaps.
+    fn place_regions(&mut self) -> Result<(), String> {
+        let config = self.config;
+        for segment in 0..self.segments.len() {
+            if self.placed[segment].is_empty() {
+                continue;
+            }
+            self.select_region(segment, Placement::Default);
+            let mut placed = mem::replace(&mut self.placed[segment], Vec::new());
+            placed.push(Placed {
+                region: Placement::Default,
+                start: 0,
+                words: mem::replace(&mut self.segments[segment], Vec::new()),
+                sources: mem::replace(&mut self.sources[segment], Vec::new()),
+            });
+            placed.retain(|p| !p.words.is_empty());
+            placed.sort_by_key(|p| p.start);
+            let sc = &config.segments[segment];
+            let name = |region: Placement| {
+                match region {
+                    Placement::Default => String::from("the default region"),
+                    Placement::Region(r) => format!("region \"{}\"", sc.regions[r].name),
+                    Placement::Bank(bank) => format!("bank {}", bank),
+                }
+            };
+            let mut previous = Placement::Default;
+            for p in placed {
+                let size = match p.region {
+                    Placement::Default => None,
+                    Placement::Region(r) => sc.regions[r].size,
+                    Placement::Bank(_) => sc.banks.as_ref().map(|b| b.size),
+                };
+                if let Some(size) = size {
+                    if p.words.len() > size {
+                        return Err(format!("Error: In segment {}, {} holds {} words, which \
+                                            exceeds its size of {}.",
+                                           self.config.segment_name(segment),
+                                           name(p.region),
+                                           p.words.len(),
+                                           size));
+                    }
+                }
+                if p.start < self.segments[segment].len() {
+                    return Err(format!("Error: In segment {}, {} overlaps {}.",
+                                       self.config.segment_name(segment),
+                                       name(p.region),
+                                       name(previous)));
+                }
+                let gap = p.start - self.segments[segment].len();
+                pad(&mut self.segments[segment], gap, &sc.fill_words());
+                self.sources[segment].extend(vec![Source {
+                                                      file: self.file,
+                                                      line: 0,
+                                                      rule: None,
+                                                      start: false,
+                                                  };
+                                                  gap]);
+                self.segments[segment].extend(p.words);
+                self.sources[segment].extend(p.sources);
+                previous = p.region;
+            }
+        }
+        Ok(())
+    }
+
+    /// The current position in every segment.
+    fn positions(&self) -> Vec<usize> {
+        (0..self.segments.len()).map(|i| self.segment_len(i)).collect()
+    }
+
+    /// Take everything collected while parsing so it can be merged into another parser.
+    pub fn into_parsed(self) -> Parsed {
+        if self.regions.iter().any(|&r| r != Placement::Default) ||
+           self.placed.iter().any(|p| !p.is_empty()) {
+            panic!("Error: Regions and banks can't be used when inputs are parsed separately.");
+        }
+        self.check_unpositioned("parsing inputs separately").unwrap_or_else(|e| panic!("{}", e));
+        Parsed {
+            segments: self.segments,
+            tags: self.tags,
+            forward_tags: self.forward_tags,
+            backward_tags: self.backward_tags,
+            replacements: self.replacements,
+            entry: self.entry,
+            files: self.files,
+            sources: self.sources,
+            definitions: self.definitions,
+            alignments: self.alignments,
+            strict_alignments: self.strict_alignments,
+            relaxables: self.relaxables,
+            errors: self.errors,
+            statements: self.statements,
+            profile: self.profile,
+            assertions: self.assertions,
+        }
+    }
+
+    /// Merge inputs parsed separately (possibly on another thread) as if they were parsed after
+    /// everything already parsed. Redefinable symbols and the namespace don't carry over between
+    /// separately parsed inputs.
+    pub fn merge(&mut self, parsed: Parsed) {
+        if self.stream.is_some() {
+            panic!("Error: Separately parsed inputs can't be merged in streaming mode.");
+        }
+        // Keep the aligned words of the merged input aligned.
+        let file = self.file;
+        for (index, &words) in parsed.alignments.iter().enumerate() {
+            let len = self.segments[index].len();
+            pad(&mut self.segments[index],
+                (words - len % words) % words,
+                &self.config.segments[index].fill_words());
+            self.alignments[index] = max(self.alignments[index], words);
+        }
+        for (index, &words) in parsed.strict_alignments.iter().enumerate() {
+            if self.segments[index].len() % words != 0 {
+                panic!("Error: An input with words which must start at a multiple of {} in \
+                        segment {} was merged at {}.",
+                       words,
+                       self.config.segment_name(index),
+                       self.segments[index].len());
+            }
+            self.strict_alignments[index] = max(self.strict_alignments[index], words);
+        }
+        self.sync_sources(Source {
+            file: file,
+            line: 0,
+            rule: None,
+            start: false,
+        });
+        let offsets = self.segments.iter().map(|v| v.len()).collect::<Vec<_>>();
+        let shift = |positions: &mut Vec<usize>| {
+            for (pos, offset) in positions.iter_mut().zip(&offsets) {
+                *pos += *offset;
+            }
+        };
+
+        for (segment, mut other) in self.segments.iter_mut().zip(parsed.segments) {
+            segment.append(&mut other);
+        }
+        let file_offset = self.files.len();
+        self.files.extend(parsed.files);
+        for (sources, other) in self.sources.iter_mut().zip(parsed.sources) {
+            sources.extend(other.into_iter().map(|mut source| {
+                source.file += file_offset;
+                source
+            }));
+        }
+        self.check_segment_sizes(0).unwrap_or_else(|e| panic!("{}", e));
+        for (name, (file, line)) in parsed.definitions {
+            self.definitions.insert(name, (file + file_offset, line));
+        }
+
+        for (name, mut positions) in parsed.tags {
+            if self.tags.contains_key(&name) {
+                panic!("Error: Tag \"{}\" is defined in more than one input.", name);
+            }
+            shift(&mut positions);
+            self.tags.insert(name, positions);
+        }
+
+        let forward_count = self.forward_tags.len();
+        let backward_count = self.backward_tags.len();
+        for (depth, mut positions) in parsed.forward_tags {
+            shift(&mut positions);
+            self.forward_tags.push((depth, positions));
+        }
+        for (depth, mut positions) in parsed.backward_tags {
+            shift(&mut positions);
+            self.backward_tags.push((depth, positions));
+        }
+
+        let config = self.config;
+        let move_replacement = |r: &mut Replacement| {
+            r.index += offsets[r.add_segment];
+            r.reference += offsets[r.pos_segment];
+            if r.relative {
+                r.pos_offset -= offsets[r.pos_segment] as isize;
+            }
+            if let Some(ref mut bound) = r.bound {
+                shift(bound);
+            }
+            match anonymous_label(config, &r.tag) {
+                Some(Anonymous::Forward(_)) => r.anonymous_index += forward_count,
+                Some(Anonymous::Backward(_)) => r.anonymous_index += backward_count,
+                None => {}
+            }
+        };
+        for mut r in parsed.replacements {
+            move_replacement(&mut r);
+            self.replacements.push(r);
+        }
+        for mut relaxable in parsed.relaxables {
+            shift(&mut relaxable.positions);
+            for r in relaxable.replacements
+                .iter_mut()
+                .chain(relaxable.long_replacements.iter_mut()) {
+                move_replacement(r);
+            }
+            relaxable.file += file_offset;
+            self.relaxables.push(relaxable);
+        }
+        self.errors.extend(parsed.errors.into_iter().map(|(file, d)| (file + file_offset, d)));
+        for mut assertion in parsed.assertions {
+            assertion.file += file_offset;
+            self.assertions.push(assertion);
+        }
+        self.statements += parsed.statements;
+        if !self.profile.is_empty() {
+            for (profile, merged) in self.profile.iter_mut().zip(parsed.profile) {
+                profile.attempts += merged.attempts;
+                profile.matches += merged.matches;
+                profile.nanoseconds += merged.nanoseconds;
+            }
+        }
+
+        // An entry point declared in the source overrides earlier ones.
+        if parsed.entry.as_ref().map_or(false, |e| e.2 != 0) {
+            self.warn_entry_replaced(parsed.entry.as_ref().unwrap())
+                .unwrap_or_else(|e| panic!("{}", e));
+            self.entry = parsed.entry;
+        }
+    }
+
+    /// Warn that an entry point declared in the source replaces another one declared in it.
+    fn warn_entry_replaced(&self, entry: &(String, String, usize)) -> Result<(), String> {
+        if let Some((ref tag, _, line)) = self.entry {
+            if line != 0 {
+                return self.warn("entry",
+                                 &format!("Entry point \"{}\" declared on line {} replaces entry \
+                                           point \"{}\" declared on line {}",
+                                          entry.0,
+                                          entry.2,
+                                          tag,
+                                          line));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get every named tag with its positions.
+    pub fn tags(&self) -> &HashMap<String, Vec<usize>> {
+        &self.tags
+    }
+
+    /// Get the positions of a global or qualified tag.
+    pub fn tag_positions(&self, tag: &str) -> Option<&Vec<usize>> {
+        lookup_tag(&self.tags,
+                   &self.config.namespace_separator,
+                   "",
+                   &self.config.tag_key(tag))
+    }
+
+    /// Get the byte address of the entry point in a segment if an entry point was declared.
+    pub fn entry_address(&self, segment: usize) -> Option<u64> {
+        self.entry.as_ref().map(|&(ref tag, ref namespace, line)| {
+            let pos = lookup_tag(&self.tags, &self.config.namespace_separator, namespace, tag)
+                .unwrap_or_else(|| {
+                    if line == 0 {
+                        panic!("Error: Entry point tag \"{}\" from the config was never defined.",
+                               tag);
+                    } else {
+                        panic!("Error: Entry point tag \"{}\" declared on line {} was never \
+                                defined.",
+                               tag,
+                               line);
+                    }
+                })[segment];
+            (pos * self.config.segment_widths[segment]) as u64
+        })
+    }
+
+    /// Get the words of every segment.
+    pub fn segments(&self) -> &[Vec<u64>] {
+        &self.segments
+    }
+
+    /// Get the bytes of the header or trailer fields of a segment's image with the given data,
+    /// where the fields start at an offset into an image of a total length.
+    fn image_fields(&self,
+                    segment: usize,
+                    fields: &[ImageField],
+                    data: &[u8],
+                    offset: usize,
+                    total: usize)
+                    -> Vec<u8> {
+        let words = self.segments[segment].len() as i64;
+        let base_address = self.config.segments[segment].base_address as i64;
+        let lookup = |name: &str| match name {
+            "length" => Some(data.len() as i64),
+            "words" => Some(words),
+            "base_address" => Some(base_address),
+            "total_length" => Some(total as i64),
+            "sum" => Some(data.iter().fold(0i64, |sum, &b| sum.wrapping_add(b as i64))),
+            "xor" => Some(data.iter().fold(0, |xor, &b| xor ^ b) as i64),
+            "crc32" => Some(crc32(data) as i64),
+            _ => None,
+        };
+        let mut bytes = Vec::new();
+        for field in fields {
+            match *field {
+                ImageField::Pad { offset: to, fill } => {
+                    let len = offset + bytes.len();
+                    if to < len {
+                        panic!("Error: Segment {} pads its image to {} bytes, but it already has \
+                                {}.",
+                               self.config.segment_name(segment),
+                               to,
+                               len);
+                    }
+                    bytes.extend((len..to).map(|_| fill));
+                }
+                ImageField::Bytes(ref b) => bytes.extend_from_slice(b),
+                ImageField::Value { ref expression, size, big_endian } => {
+                    let value = evaluate(expression, &lookup).unwrap_or_else(|e| {
+                        panic!("Error: Invalid field of segment {}: {}",
+                               self.config.segment_name(segment),
+                               e)
+                    }) as u64;
+                    let mut value_bytes = (0..size)
+                        .map(|i| value.checked_shr(i as u32 * 8).unwrap_or(0) as u8)
+                        .collect::<Vec<_>>();
+                    if big_endian {
+                        value_bytes.reverse();
+                    }
+                    bytes.extend(value_bytes);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The words of a segment's image, which repeats them if the segment is mirrored.
+    fn image_words<'b>(&'b self, segment: usize) -> Take<Cycle<slice::Iter<'b, u64>>> {
+        let words = &self.segments[segment];
+        words.iter().cycle().take(words.len() * self.config.segments[segment].mirrors.unwrap_or(1))
+    }
+
+    /// Get the big-endian byte stream of a segment.
+    fn segment_bytes(&self, segment: usize) -> Vec<u8> {
+        use byteorder::{ByteOrder, BigEndian};
+        let mut bytes = [0u8; 8];
+        let width = self.config.segment_widths[segment];
+        let mut v = Vec::with_capacity(self.segments[segment].len() * width);
+        for val in self.image_words(segment) {
+            BigEndian::write_u64(&mut bytes, *val);
+            v.extend_from_slice(&bytes[(8 - width)..8]);
+        }
+        self.swapped_bytes(segment, v)
+    }
+
+    /// Get the positions of the tag a tag reference refers to.
+    fn target<'b>(&'b self, r: &'b Replacement) -> Result<&'b Vec<usize>, String> {
+        let anonymous = anonymous_label(self.config, &r.tag);
+        if let Some(ref bound) = r.bound {
+            Ok(bound)
+        } else if let Some(Anonymous::Forward(depth)) = anonymous {
+            // The nearest forward label of the same depth defined after the reference.
+            self.forward_tags[r.anonymous_index..]
+                .iter()
+                .find(|e| e.0 == depth)
+                .map(|e| &e.1)
+                .ok_or_else(|| {
+                    format!("Error: Forward anonymous tag \"{}\" used on line {} was never \
+                             defined.",
+                            r.tag,
+                            r.line)
+                })
+        } else if let Some(Anonymous::Backward(depth)) = anonymous {
+            // The nearest backward label of the same depth defined before the reference.
+            self.backward_tags[..r.anonymous_index]
+                .iter()
+                .rev()
+                .find(|e| e.0 == depth)
+                .map(|e| &e.1)
+                .ok_or_else(|| {
+                    format!("Error: Backward anonymous tag \"{}\" used on line {} was never \
+                             defined.",
+                            r.tag,
+                            r.line)
+                })
+        } else {
+            // Get the tag offset vector corresponding to the replacement.
+            lookup_tag(&self.tags,
+                       &self.config.namespace_separator,
+                       &r.namespace,
+                       &r.tag)
+                .ok_or_else(|| {
+                    format!("Error: Tag \"{}\" used on line {} never defined.", r.tag, r.line)
+                })
+        }
+    }
+
+    /// Get the position a tag reference refers to plus its offset.
+    fn resolve(&self, r: &Replacement) -> Result<isize, String> {
+        let position = try!(self.target(r))[r.pos_segment];
+        try!(check_target_alignment(&r.tag, r.line, position, r.target_alignment));
+        let position = match self.config.segments[r.pos_segment].banks {
+            Some(ref banks) => {
+                if r.bank == BankUse::Near && position / banks.size != r.reference_bank {
+                    return Err(format!("Error: Tag \"{}\" used on line {} is in bank {}, but the \
+                                        reference is in bank {}.",
+                                       r.tag,
+                                       r.line,
+                                       position / banks.size,
+                                       r.reference_bank));
+                }
+                bank_address(banks, r.bank, position)
+            }
+            None => position,
+        };
+        Ok((position as isize).wrapping_add(r.pos_offset))
+    }
+
+    /// Move everything at or after a position in a segment by an amount of words.
+    fn shift_positions(&mut self, segment: usize, at: usize, amount: isize) {
+        let shift = |position: &mut usize| if *position >= at {
+            *position = (*position as isize + amount) as usize;
+        };
+        for (name, positions) in &mut self.tags {
+            if !self.fixed_tags.contains(name) {
+                shift(&mut positions[segment]);
+            }
+        }
+        for &mut (_, ref mut positions) in self.forward_tags
+            .iter_mut()
+            .chain(self.backward_tags.iter_mut()) {
+            shift(&mut positions[segment]);
+        }
+        let mut replacements = self.replacements.iter_mut().collect::<Vec<_>>();
+        for relaxable in &mut self.relaxables {
+            shift(&mut relaxable.positions[segment]);
+            replacements.extend(relaxable.replacements
+                .iter_mut()
+                .chain(relaxable.long_replacements.iter_mut()));
+        }
+        for r in replacements {
+            if r.add_segment == segment {
+                shift(&mut r.index);
+            }
+            if r.pos_segment == segment && r.reference >= at {
+                r.reference = (r.reference as isize + amount) as usize;
+                if r.relative {
+                    r.pos_offset -= amount;
+                }
+            }
+            if let Some(ref mut bound) = r.bound {
+                shift(&mut bound[segment]);
+            }
+        }
+    }
+
+    /// Replace instructions whose tag references don't fit in their short encoding with their
+    /// long encoding until everything fits, moving everything after them.
+    fn relax(&mut self) -> Result<(), String> {
+        let config = self.config;
+        loop {
+            let mut relaxed = false;
+            for index in 0..self.relaxables.len() {
+                {
+                    let relaxable = &self.relaxables[index];
+                    let relax = config.rules[relaxable.rule].relax.as_ref().unwrap();
+                    if relaxable.relaxed {
+                        continue;
+                    }
+                    let mut fits = true;
+                    for r in &relaxable.replacements {
+                        let value = try!(self.resolve(r)) as i64;
+                        fits = fits && value >= relax.min && value <= relax.max;
+                    }
+                    if fits {
+                        continue;
+                    }
+                }
+                // The config keeps rules whose values depend on positions out, but `__POS__`
+                // can only be found while parsing.
+                try!(self.check_unmoved("relaxation"));
+                let long = mem::replace(&mut self.relaxables[index].long, Vec::new());
+                let long_replacements = mem::replace(&mut self.relaxables[index].long_replacements,
+                                                     Vec::new());
+                let positions = self.relaxables[index].positions.clone();
+                let lengths = self.relaxables[index].lengths.clone();
+                let source = Source {
+                    file: self.relaxables[index].file,
+                    line: self.relaxables[index].line,
+                    rule: Some(self.relaxables[index].long_rule),
+                    start: false,
+                };
+                for (segment, words) in long.into_iter().enumerate() {
+                    let (position, length) = (positions[segment], lengths[segment]);
+                    let amount = words.len() as isize - length as isize;
+                    let tail = self.segments[segment].split_off(position + length);
+                    let source_tail = self.sources[segment].split_off(position + length);
+                    self.segments[segment].truncate(position);
+                    self.sources[segment].truncate(position);
+                    self.sources[segment]
+                        .extend((0..words.len()).map(|i| Source { start: i == 0, ..source }));
+                    self.sources[segment].extend(source_tail);
+                    self.segments[segment].extend(words);
+                    self.segments[segment].extend(tail);
+                    self.shift_positions(segment, position + length, amount);
+                }
+                self.relaxables[index].replacements = long_replacements;
+                self.relaxables[index].relaxed = true;
+                relaxed = true;
+            }
+            if !relaxed {
+                break;
+            }
+        }
+        for relaxable in self.relaxables.drain(..) {
+            self.replacements.extend(relaxable.replacements);
+        }
+        Ok(())
+    }
+
+    /// Remove the words from each named tag up to the next one if nothing reachable from the
+    /// words before the first tag, the entry point, or the given root tags refers to them. Tags
+    /// are ordered by their position in the given segment. Returns the removed tags with the
+    /// amount of words removed from that segment.
+    pub fn remove_unreferenced(&mut self,
+                               segment: usize,
+                               roots: &[&str])
+                               -> Result<Vec<(String, usize)>, String> {
+        if self.stream.is_some() {
+            return Err(String::from("Error: Unreferenced tags can't be removed in streaming \
+                                     mode."));
+        }
+        if self.regions.iter().any(|&r| r != Placement::Default) ||
+           self.placed.iter().any(|p| !p.is_empty()) {
+            return Err(String::from("Error: Unreferenced tags can't be removed when regions or \
+                                     banks are used."));
+        }
+        // Tags at the same position share the words up to the next position. Fixed tags aren't
+        // in the output, so they don't start any words. Ties are broken by the positions in the
+        // other segments so the tag kept for a region doesn't depend on the order of the map.
+        let mut starts = self.tags
+            .iter()
+            .filter(|&(name, _)| !self.fixed_tags.contains(name))
+            .map(|(_, positions)| positions.clone())
+            .collect::<Vec<_>>();
+        starts.sort_by(|a, b| (a[segment], a).cmp(&(b[segment], b)));
+        let mut regions: Vec<Vec<usize>> = Vec::new();
+        for positions in starts {
+            if regions.last().map_or(true, |last| last[segment] != positions[segment]) {
+                regions.push(positions);
+            }
+        }
+
+        let mut live = vec![false; regions.len()];
+        {
+            let region_of = |s: usize, position: usize| {
+                regions.iter().rposition(|r| r[s] <= position)
+            };
+            let mut edges = Vec::new();
+            for r in self.replacements.iter().chain(self.relaxables.iter().flat_map(|x| {
+                x.replacements.iter().chain(x.long_replacements.iter())
+            })) {
+                // Fixed tags aren't in any region, so references to them keep nothing alive.
+                if !self.fixed_tags.contains(&self.qualified_tag(&r.namespace, &r.tag)) {
+                    let target = try!(self.target(r));
+                    edges.push((region_of(r.add_segment, r.index),
+                                region_of(r.pos_segment, target[r.pos_segment])));
+                }
+            }
+            let mut pending = vec![None];
+            if let Some((ref tag, ref namespace, _)) = self.entry {
+                if let Some(positions) = lookup_tag(&self.tags,
+                                                    &self.config.namespace_separator,
+                                                    namespace,
+                                                    tag) {
+                    if !self.fixed_tags.contains(&self.qualified_tag(namespace, tag)) {
+                        pending.push(region_of(segment, positions[segment]));
+                    }
+                }
+            }
+            for root in roots {
+                let key = self.config.tag_key(root);
+                let positions = try!(self.tags
+                    .get(&key)
+                    .ok_or_else(|| format!("Error: Root tag \"{}\" was never defined.", root)));
+                if !self.fixed_tags.contains(&key) {
+                    pending.push(region_of(segment, positions[segment]));
+                }
+            }
+            let mut visited_start = false;
+            while let Some(region) = pending.pop() {
+                match region {
+                    Some(index) if !live[index] => live[index] = true,
+                    None if !visited_start => visited_start = true,
+                    _ => continue,
+                }
+                pending.extend(edges.iter().filter(|e| e.0 == region).map(|e| e.1));
+            }
+        }
+
+        // Removing words moves the words of every live tag after them.
+        if (0..regions.len()).any(|i| !live[i] && live[i + 1..].iter().any(|&l| l)) {
+            try!(self.check_unmoved("removing unreferenced tags"));
+        }
+        let mut removed = Vec::new();
+        for index in (0..regions.len()).rev().filter(|&i| !live[i]) {
+            let mut names = self.tags
+                .iter()
+                .filter(|&(name, p)| {
+                    p[segment] == regions[index][segment] && !self.fixed_tags.contains(name)
+                })
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            for s in 0..self.segments.len() {
+                let start = regions[index][s];
+                let end = regions.get(index + 1).map_or(self.segments[s].len(), |r| r[s]);
+                if s == segment {
+                    // Regions are removed last first, so everything is reversed at the end.
+                    for name in names.iter().rev() {
+                        removed.push((name.clone(), end - start));
+                    }
+                }
+                self.replacements
+                    .retain(|r| r.add_segment != s || r.index < start || r.index >= end);
+                self.relaxables.retain(|r| {
+                    r.lengths[s] == 0 || r.positions[s] < start || r.positions[s] >= end
+                });
+                self.segments[s].drain(start..end);
+                self.sources[s].drain(start..end);
+                self.shift_positions(s, end, start as isize - end as isize);
+            }
+            for name in &names {
+                self.tags.remove(name);
+                self.definitions.remove(name);
+            }
+        }
+        removed.reverse();
+        Ok(removed)
     }
-}
 
-impl<'a> Parser<'a> {
-    pub fn new(config: &'a Config) -> Self {
-        Parser {
-            config: config,
-            segments: {
-                let mut v = Vec::new();
-                for _ in 0..config.segment_widths.len() {
-                    v.push(Vec::new());
+    /// Rewrite the words matching the peephole patterns of the config.
+    fn rewrite_peepholes(&mut self) -> Result<(), String> {
+        let config = self.config;
+        if !config.peepholes.is_empty() && self.stream.is_some() {
+            return Err(String::from("Error: Peepholes can't be used in streaming mode."));
+        }
+        for peephole in &config.peepholes {
+            let segment = peephole.segment;
+            let length = peephole.pattern.len();
+            let mut index = 0;
+            while index + length <= self.segments[segment].len() {
+                if !self.peephole_matches(peephole, index) {
+                    index += 1;
+                    continue;
                 }
-                v
-            },
-            tags: HashMap::new(),
-            plus_tags: Vec::new(),
-            minus_tags: Vec::new(),
-            replacements: Vec::new(),
+                if peephole.replacement.len() != length {
+                    try!(self.check_unmoved("peephole rewriting"));
+                }
+                let source = self.sources[segment][index];
+                let tail = self.segments[segment].split_off(index + length);
+                let source_tail = self.sources[segment].split_off(index + length);
+                self.segments[segment].truncate(index);
+                self.sources[segment].truncate(index);
+                self.segments[segment].extend(peephole.replacement.iter().cloned());
+                self.sources[segment].extend((0..peephole.replacement.len())
+                    .map(|i| Source { start: i == 0, ..source }));
+                self.segments[segment].extend(tail);
+                self.sources[segment].extend(source_tail);
+                self.shift_positions(segment,
+                                     index + length,
+                                     peephole.replacement.len() as isize - length as isize);
+                index += peephole.replacement.len();
+            }
         }
+        Ok(())
+    }
+
+    /// Check if the words at a position match a peephole pattern and are safe to rewrite, which
+    /// they are if they start and end where the words of rules do and no tag is between them.
+    fn peephole_matches(&self, peephole: &Peephole, index: usize) -> bool {
+        let segment = peephole.segment;
+        let sources = &self.sources[segment];
+        let end = index + peephole.pattern.len();
+        if !(sources[index].start && sources[index].rule.is_some()) ||
+           sources.get(end).map_or(false, |s| !s.start) {
+            return false;
+        }
+        let words = &self.segments[segment][index..end];
+        let matched = peephole.pattern.iter().zip(words).all(|(pattern, &word)| {
+            word & pattern.mask == pattern.value & pattern.mask &&
+            pattern.same_as.map_or(true, |other| {
+                word & !pattern.mask == words[other] & !pattern.mask
+            })
+        });
+        let inside = |position: usize| position >= index && position < end;
+        let between = |positions: &Vec<usize>| {
+            positions[segment] > index && positions[segment] < end
+        };
+        matched &&
+        !self.tags
+            .iter()
+            .filter(|&(name, _)| !self.fixed_tags.contains(name))
+            .map(|(_, positions)| positions)
+            .chain(self.forward_tags.iter().map(|t| &t.1))
+            .chain(self.backward_tags.iter().map(|t| &t.1))
+            .any(&between) &&
+        !self.replacements
+            .iter()
+            .chain(self.relaxables.iter().flat_map(|r| r.replacements.iter()))
+            .any(|r| {
+                r.add_segment == segment && inside(r.index) ||
+                r.bound.as_ref().map_or(false, &between)
+            }) &&
+        !self.relaxables.iter().any(|r| {
+            r.lengths[segment] != 0 && r.positions[segment] < end &&
+            r.positions[segment] + r.lengths[segment] > index
+        })
     }
 
     pub fn link(&mut self) {
+        self.link_all().unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Link the program, returning the first error found.
+    fn link_all(&mut self) -> Result<(), String> {
+        self.line = 0;
+        if (!self.relaxables.is_empty() || !self.config.peepholes.is_empty()) &&
+           (self.regions.iter().any(|&r| r != Placement::Default) ||
+            self.placed.iter().any(|p| !p.is_empty())) {
+            return Err(String::from("Error: Relaxation and peepholes can't be combined with \
+                                     regions or banks."));
+        }
+        try!(self.place_regions());
+        try!(self.rewrite_peepholes());
+        try!(self.relax());
         // Iterate through every replacement.
-        'outer: for r in &self.replacements {
-            if r.tag.chars().all(|c| c == '+') {
-                for e in &self.plus_tags {
-                    // Ensure they have the same amount of pluses and that the relevant segment is at least as high.
-                    if e.0 == r.tag.len() && e.1[r.add_segment] >= r.index {
-                        self.segments[r.add_segment][r.index] +=
-                            shift_left_or_right((e.1[r.pos_segment] as isize + r.pos_offset) as u64, r.shift);
+        for r in &self.replacements {
+            let position = try!(self.resolve(r)) as u64;
+            let position = try!(fit_width(position, r.width, r.overflow)
+                .ok_or_else(|| too_wide(position, r.line, r.width)));
+            let value = shift_left_or_right(position, r.shift);
 
-                        continue 'outer;
-                    }
+            // Words that were already streamed out are patched in their file.
+            let config = self.config;
+            let mut error = None;
+            {
+                let mut add = |word| {
+                    combine_value(word, value, r.combine, r.overflow, r.width, r.shift)
+                        .unwrap_or_else(|| {
+                            error = Some(overflowed(value, r.line, r.width, config, r.add_segment));
+                            word
+                        })
+                };
+                let flushed = self.stream.as_ref().map_or(0, |s| s.flushed[r.add_segment]);
+                if r.index < flushed {
+                    self.stream.as_mut().unwrap().patch(r.add_segment, r.index, add);
+                } else {
+                    let word = &mut self.segments[r.add_segment][r.index - flushed];
+                    *word = add(*word);
+                }
+            }
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+        try!(self.write_vectors());
+        try!(self.check_word_widths());
+        try!(self.check_assertions());
+        try!(self.check_linked_sizes());
+
+        // Pad every segment that has an image size with its fill word.
+        for (index, (segment, sc)) in self.segments
+            .iter_mut()
+            .zip(&self.config.segments)
+            .enumerate() {
+            let flushed = self.stream.as_ref().map_or(0, |s| s.flushed[index]);
+            if let Some(image_size) = sc.image_size {
+                let len = flushed + segment.len();
+                if len < image_size {
+                    pad(segment, image_size - len, &sc.fill_words());
+                }
+            }
+        }
+        let file = self.file;
+        self.sync_sources(Source {
+            file: file,
+            line: 0,
+            rule: None,
+            start: false,
+        });
+        Ok(())
+    }
+
+    /// Check the sizes of the segments after linking, which relaxation and vectors can change,
+    /// against their maximum sizes and the segments they must be the same size as.
+    fn check_linked_sizes(&self) -> Result<(), String> {
+        let config = self.config;
+        for (index, sc) in config.segments.iter().enumerate() {
+            let len = self.segment_len(index);
+            if let Some(max_size) = sc.max_size {
+                if len > max_size {
+                    return Err(format!("Error: Segment {} has {} words after linking, which \
+                                        exceeds its maximum size of {}.",
+                                       config.segment_name(index),
+                                       len,
+                                       max_size));
+                }
+            }
+            if let Some(other) = sc.same_size_as {
+                let other_len = self.segment_len(other);
+                if len != other_len {
+                    return Err(format!("Error: Segment {} has {} words after linking, but \
+                                        segment {} has {} and they must be the same size.",
+                                       config.segment_name(index),
+                                       len,
+                                       config.segment_name(other),
+                                       other_len));
                 }
-                panic!("Error: Forward + tag used on line {} was never defined.",
-                       r.line);
-            } else if r.tag.chars().all(|c| c == '-') {
-                for e in self.minus_tags.iter().rev() {
-                    // Ensure they have the same amount of minuses and that the relevant segment is lower.
-                    if e.0 == r.tag.len() && e.1[r.add_segment] < r.index {
-                        self.segments[r.add_segment][r.index] +=
-                            shift_left_or_right((e.1[r.pos_segment] as isize + r.pos_offset) as u64, r.shift);
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate every assertion with the linked positions of tags and fail on the first one
+    /// which doesn't hold.
+    fn check_assertions(&self) -> Result<(), String> {
+        let config = self.config;
+        for assertion in &self.assertions {
+            let value = try!(evaluate(&assertion.expression, &|symbol: &str| {
+                    assertion.bindings
+                        .get(symbol)
+                        .cloned()
+                        .or_else(|| {
+                            lookup_tag(&self.tags,
+                                       &config.namespace_separator,
+                                       &assertion.namespace,
+                                       &config.tag_key(symbol))
+                                .map(|positions| positions[assertion.segment] as i64)
+                        })
+                })
+                .map_err(|e| {
+                    format!("Error: Failed to evaluate the assertion \"{}\" at {}:{}: {}",
+                            assertion.expression,
+                            self.files.get(assertion.file).map_or("<input>", |f| &f[..]),
+                            assertion.line,
+                            e)
+                }));
+            if value == 0 {
+                return Err(format!("Error: Assertion \"{}\" failed at {}:{}: {}",
+                                   assertion.expression,
+                                   self.files.get(assertion.file).map_or("<input>", |f| &f[..]),
+                                   assertion.line,
+                                   assertion.message));
+            }
+        }
+        Ok(())
+    }
 
-                        continue 'outer;
+    /// Warn about every word too wide for its segment, which outputs would cut off. Negative
+    /// values which fit in two's complement are allowed, and words already written in streaming
+    /// mode aren't checked.
+    fn check_word_widths(&self) -> Result<(), String> {
+        for (index, words) in self.segments.iter().enumerate() {
+            let bits = self.config.segment_widths[index] * 8;
+            if bits >= 64 {
+                continue;
+            }
+            let flushed = self.stream.as_ref().map_or(0, |s| s.flushed[index]);
+            for (position, &word) in words.iter().enumerate() {
+                if word >> bits == 0 || (word as i64) >> (bits - 1) == -1 {
+                    continue;
+                }
+                let from = match self.sources[index].get(position) {
+                    Some(source) if source.line != 0 => {
+                        format!(" from {}:{}",
+                                self.files.get(source.file).map_or("<input>", |f| &f[..]),
+                                source.line)
                     }
+                    _ => String::new(),
+                };
+                try!(self.warn("width",
+                               &format!("Word {} of segment {}{} is 0x{:X}, which doesn't fit in \
+                                         {} bits",
+                                        flushed + position,
+                                        self.config.segment_name(index),
+                                        from,
+                                        word,
+                                        bits)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the position of the tag of every vector into its slot, padding the segment up to it.
+    fn write_vectors(&mut self) -> Result<(), String> {
+        if self.config.vectors.is_empty() {
+            return Ok(());
+        }
+        if self.stream.is_some() {
+            return Err(String::from("Error: Vectors can't be written in streaming mode."));
+        }
+        let config = self.config;
+        for vector in &config.vectors {
+            let position = match self.tags.get(&config.tag_key(&vector.tag)) {
+                Some(positions) => positions[vector.from_segment.unwrap_or(vector.segment)],
+                None => {
+                    return Err(format!("Error: The vector at {} in segment {} has no handler, \
+                                        since tag \"{}\" isn't defined.",
+                                       vector.slot,
+                                       config.segment_name(vector.segment),
+                                       vector.tag))
                 }
-                panic!("Error: Backward - tag used on line {} was never defined.",
-                       r.line);
-            } else {
-                // Get the tag offset vector corresponding to the replacement.
-                let tag = self.tags.get(&r.tag).unwrap_or_else(|| {
-                    panic!("Error: Tag \"{}\" used on line {} never defined.",
-                           r.tag,
-                           r.line);
-                });
-                self.segments[r.add_segment][r.index] +=
-                    shift_left_or_right((tag[r.pos_segment] as isize + r.pos_offset) as u64,
-                                        r.shift);
+            };
+            let segment = &mut self.segments[vector.segment];
+            let sources = &self.sources[vector.segment];
+            let end = vector.slot + vector.words;
+            if sources[min(vector.slot, sources.len())..min(end, sources.len())]
+                .iter()
+                .any(|s| s.line != 0) {
+                return Err(format!("Error: The vector for \"{}\" at {} in segment {} overlaps \
+                                    emitted words.",
+                                   vector.tag,
+                                   vector.slot,
+                                   config.segment_name(vector.segment)));
+            }
+            if segment.len() < end {
+                let len = segment.len();
+                pad(segment, end - len, &config.segments[vector.segment].fill_words());
+            }
+            let bits = config.segment_widths[vector.segment] as u32 * 8;
+            let mask = 1u64.checked_shl(bits).map_or(!0, |m| m - 1);
+            for word in 0..vector.words {
+                let shift = if vector.big_endian {
+                    (vector.words - 1 - word) as u32 * bits
+                } else {
+                    word as u32 * bits
+                };
+                segment[vector.slot + word] = (position as u64).checked_shr(shift).unwrap_or(0) &
+                                              mask;
             }
         }
+        let file = self.file;
+        self.sync_sources(Source {
+            file: file,
+            line: 0,
+            rule: None,
+            start: false,
+        });
+        Ok(())
+    }
+
+    /// Parse a source, returning every error found rather than panicking. The parser shouldn't
+    /// be used further if this fails.
+    pub fn try_parse_str(&mut self, source: &str) -> Result<(), Vec<Diagnostic>> {
+        let recover = mem::replace(&mut self.recover, true);
+        let result = self.parse_lines(source.as_bytes());
+        self.recover = recover;
+        let mut errors = mem::replace(&mut self.errors, Vec::new())
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect::<Vec<_>>();
+        if let Err(message) = result {
+            errors.push(Diagnostic {
+                line: self.line,
+                message: message,
+            });
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Link the program, returning errors rather than panicking.
+    pub fn try_link(&mut self) -> Result<(), Vec<Diagnostic>> {
+        self.link_all().map_err(|message| {
+            vec![Diagnostic {
+                     line: 0,
+                     message: message,
+                 }]
+        })
     }
 
     pub fn parse<B>(&mut self, bufread: B)
         where B: BufRead
     {
+        self.parse_lines(bufread).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Parse every line of an input, returning the first error which isn't recovered from.
+    fn parse_lines<B>(&mut self, bufread: B) -> Result<(), String>
+        where B: BufRead
+    {
+        // A logical line being joined from continued physical lines and the line it started on.
+        let mut pending: Option<(String, usize)> = None;
+        // The line that the currently open block comment started on.
+        let mut block_comment: Option<usize> = None;
         for (index, line) in bufread.lines().enumerate() {
-            let line = line.unwrap_or_else(|e| panic!("Error: Failed to read from buffer: {}", e));
+            let line = try!(line.map_err(|e| format!("Error: Failed to read from buffer: {}", e)));
 
-            // Remove everything after the first #, which denotes a comment.
-            let line = line.splitn(2, '#').next().unwrap();
+            let mut in_block = block_comment.is_some();
+            let line = strip_comments(self.config, &line, &mut in_block);
+            if !in_block {
+                block_comment = None;
+            } else if block_comment.is_none() {
+                block_comment = Some(index + 1);
+            }
+            let line = line.as_str();
 
-            if self.config.split_whitespace {
-                for word in line.split_whitespace() {
-                    self.parse_segment(word, index + 1);
+            let (mut logical, start) = pending.take().unwrap_or_else(|| (String::new(), index + 1));
+            if let Some(ref continuation) = self.config.line_continuation {
+                let trimmed = line.trim_right();
+                if trimmed.ends_with(continuation.as_str()) {
+                    logical.push_str(&trimmed[..trimmed.len() - continuation.len()]);
+                    logical.push(' ');
+                    pending = Some((logical, start));
+                    continue;
                 }
-            } else {
-                self.parse_segment(line, index + 1);
             }
+            logical.push_str(line);
+            try!(self.parse_line(&logical, start));
+        }
+        if let Some(start) = block_comment {
+            try!(self.recovered(start,
+                                format!("Error: Block comment starting on line {} is never \
+                                         closed.",
+                                        start)));
+        }
+        // The last line may still be continued at the end of the input.
+        if let Some((logical, start)) = pending {
+            try!(self.parse_line(&logical, start));
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: usize) -> Result<(), String> {
+        self.line = line_number;
+        for (statement, offset) in split_statements(self.config, line) {
+            // Columns are counted from the start of the line rather than the statement.
+            let base = column(line, offset) - 1;
+            if let Err(message) = self.parse_statement(statement, base, line_number) {
+                try!(self.recovered(line_number, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_statement(&mut self,
+                       statement: &str,
+                       base: usize,
+                       line_number: usize)
+                       -> Result<(), String> {
+        if !self.config.token_classes.is_empty() {
+            let tokens = try!(tokenize(self.config, statement).map_err(|column| {
+                format!("Error: No token class matches the text on line {} at column {}.",
+                        line_number,
+                        base + column)
+            }));
+            if let (Some(first), Some(last)) = (tokens.first(), tokens.last()) {
+                self.columns = (base + first.1, base + last.2);
+            }
+            let statement = tokens.iter().map(|t| t.0).collect::<Vec<_>>().join(" ");
+            self.parse_segment(&statement, line_number)
+        } else if self.config.split_whitespace {
+            for (word, start) in words(statement) {
+                self.columns = (base + column(statement, start),
+                                base + column(statement, start + word.len()) - 1);
+                try!(self.parse_segment(word, line_number));
+            }
+            Ok(())
+        } else {
+            self.columns = (base + 1, base + column(statement, statement.len()) - 1);
+            self.parse_segment(statement, line_number)
+        }
+    }
+
+    /// Write every segment into one Intel HEX or S-record image at its base address, with the
+    /// entry point in segment 0.
+    pub fn output_combined<W>(&self, format: OutputFormat, w: &mut W)
+        where W: Write
+    {
+        let mut blocks = (0..self.segments.len())
+            .map(|segment| {
+                Block {
+                    segment: segment,
+                    address: self.config.segments.get(segment).map_or(0, |sc| sc.base_address),
+                    bytes: self.segment_bytes(segment),
+                }
+            })
+            .filter(|b| !b.bytes.is_empty())
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|b| b.address);
+        for pair in blocks.windows(2) {
+            if pair[0].address + pair[0].bytes.len() as u64 > pair[1].address {
+                panic!("Error: Segments {} and {} overlap in the combined image.",
+                       pair[0].segment,
+                       pair[1].segment);
+            }
+        }
+        let entry = self.entry_address(0).map(|entry| {
+            entry + self.config.segments.get(0).map_or(0, |sc| sc.base_address)
+        });
+        match format {
+            OutputFormat::IntelHex => write_intel_hex(w, &blocks, entry),
+            OutputFormat::SRecord => write_srecords(w, &blocks, entry),
+            _ => panic!("Error: Only Intel HEX and S-records can hold every segment in one file."),
         }
     }
 
@@ -126,38 +2617,44 @@ impl<'a> Parser<'a> {
     {
         use byteorder::{ByteOrder, LittleEndian, BigEndian};
         match format {
-            OutputFormat::LittleEndian => {
+            OutputFormat::LittleEndian | OutputFormat::BigEndian => {
                 // Allocate enough bytes to store a u64
                 let mut bytes = [0; 8];
                 let width = self.config.segment_widths[segment];
-                for val in &self.segments[segment] {
-                    LittleEndian::write_u64(&mut bytes, *val);
-                    w.write_all(&bytes[0..width]).unwrap_or_else(|e| {
-                        panic!("Error: Writing to output file for segment {} failed: {}",
-                               segment,
-                               e);
-                    });
+                let mut data = Vec::with_capacity(self.segments[segment].len() * width);
+                for val in self.image_words(segment) {
+                    // The word is in the low bytes, which come last in big-endian order.
+                    if let OutputFormat::LittleEndian = format {
+                        LittleEndian::write_u64(&mut bytes, *val);
+                        data.extend_from_slice(&bytes[0..width]);
+                    } else {
+                        BigEndian::write_u64(&mut bytes, *val);
+                        data.extend_from_slice(&bytes[(8 - width)..8]);
+                    }
                 }
-            }
-            OutputFormat::BigEndian => {
-                // Allocate enough bytes to store a u64
-                let mut bytes = [0; 8];
-                let width = self.config.segment_widths[segment];
-                for val in &self.segments[segment] {
-                    BigEndian::write_u64(&mut bytes, *val);
-                    w.write_all(&bytes[0..width]).unwrap_or_else(|e| {
+                let sc = &self.config.segments[segment];
+                let header_len = fields_len(&sc.header, 0);
+                let total = header_len + data.len() +
+                            fields_len(&sc.trailer, header_len + data.len());
+                let header = self.image_fields(segment, &sc.header, &data, 0, total);
+                let trailer =
+                    self.image_fields(segment, &sc.trailer, &data, header_len + data.len(), total);
+                let mut image = header;
+                image.extend(data);
+                image.extend(trailer);
+                w.write_all(&self.swapped_bytes(segment, image))
+                    .unwrap_or_else(|e| {
                         panic!("Error: Writing to output file for segment {} failed: {}",
                                segment,
                                e);
                     });
-                }
             }
             OutputFormat::HexList => {
                 use std::iter::FromIterator;
                 // Allocate enough bytes to store a u64
                 let mut bytes = [0u8; 8];
                 let width = self.config.segment_widths[segment];
-                for val in &self.segments[segment] {
+                for val in self.image_words(segment) {
                     BigEndian::write_u64(&mut bytes, *val);
                     w.write_all((String::from_iter(bytes[(8 - width)..8]
                                 .iter()
@@ -171,150 +2668,1121 @@ impl<'a> Parser<'a> {
                         });
                 }
             }
+            OutputFormat::IntelHex => {
+                write_intel_hex(w,
+                                &[Block {
+                                      segment: segment,
+                                      address: 0,
+                                      bytes: self.segment_bytes(segment),
+                                  }],
+                                self.entry_address(segment));
+            }
+            OutputFormat::SRecord => {
+                write_srecords(w,
+                               &[Block {
+                                     segment: segment,
+                                     address: 0,
+                                     bytes: self.segment_bytes(segment),
+                                 }],
+                               self.entry_address(segment));
+            }
+            OutputFormat::Elf => {
+                write_elf(w,
+                          segment,
+                          &self.segment_bytes(segment),
+                          self.config.segments[segment].base_address,
+                          self.config.segment_widths[segment],
+                          self.entry_address(segment),
+                          &self.config.identity(),
+                          &self.files,
+                          &self.sources[segment]);
+            }
         }
     }
 
-    pub fn parse_segment(&mut self, segment: &str, line: usize) {
-        if segment.is_empty() {
-            return;
+    /// Parse one statement, leaving everything as it was before it if it fails.
+    pub fn parse_segment(&mut self, segment: &str, line: usize) -> Result<(), String> {
+        let checkpoint = self.checkpoint();
+        let result = self.apply_segment(segment, line);
+        if result.is_err() {
+            self.restore(checkpoint);
+        }
+        result
+    }
+
+    /// Remember how much of everything a statement adds to there is now.
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            words: self.segments.iter().map(|words| words.len()).collect(),
+            sources: self.sources.iter().map(|sources| sources.len()).collect(),
+            replacements: self.replacements.len(),
+            relaxables: self.relaxables.len(),
+            assertions: self.assertions.len(),
+            forward_tags: self.forward_tags.len(),
+            backward_tags: self.backward_tags.len(),
+            alignments: self.alignments.clone(),
+            strict_alignments: self.strict_alignments.clone(),
+            positioned: self.positioned.get(),
         }
-        if self.attempt_tag_create(segment, line) {
-            return;
+    }
+
+    /// Undo everything added since a checkpoint.
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        for (words, &len) in self.segments.iter_mut().zip(&checkpoint.words) {
+            words.truncate(len);
         }
-        if self.attempt_rules(segment, line) {
-            return;
+        for (sources, &len) in self.sources.iter_mut().zip(&checkpoint.sources) {
+            sources.truncate(len);
         }
-        panic!("Error: Unrecognized symbol \"{}\" on line {}.",
-               segment,
-               line);
+        self.replacements.truncate(checkpoint.replacements);
+        self.relaxables.truncate(checkpoint.relaxables);
+        self.assertions.truncate(checkpoint.assertions);
+        self.forward_tags.truncate(checkpoint.forward_tags);
+        self.backward_tags.truncate(checkpoint.backward_tags);
+        self.alignments = checkpoint.alignments;
+        self.strict_alignments = checkpoint.strict_alignments;
+        self.positioned.set(checkpoint.positioned);
     }
 
-    fn attempt_tag_create(&mut self, segment: &str, line: usize) -> bool {
-        for tc in &self.config.tag_creates {
-            if let Some(caps) = tc.regex.as_ref().unwrap().captures(segment) {
-                let s = caps.at(1).unwrap();
-                if s.chars().all(|c| c == '+') {
-                    self.plus_tags.push((s.len(),
-                                         self.segments
-                        .iter()
-                        .map(|v| v.len())
-                        .collect()));
-                } else if s.chars().all(|c| c == '-') {
-                    self.minus_tags.push((s.len(),
-                                          self.segments
-                        .iter()
-                        .map(|v| v.len())
-                        .collect()));
+    fn apply_segment(&mut self, segment: &str, line: usize) -> Result<(), String> {
+        if segment.is_empty() {
+            return Ok(());
+        }
+        if try!(self.attempt_directives(segment, line)) {
+            self.statements += 1;
+            return Ok(());
+        }
+        if try!(self.attempt_tag_create(segment, line)) {
+            self.statements += 1;
+            return Ok(());
+        }
+        let positions = if self.hooks.is_empty() {
+            Vec::new()
+        } else {
+            self.positions()
+        };
+        let attempt = self.attempt_rules(segment);
+        if let Ok((rule, ref caps)) = attempt {
+            let config = self.config;
+            let missing = self.missing_requirements(&config.rules[rule]);
+            if !missing.is_empty() {
+                return Err(format!("Error: \"{}\" on line {} is not enabled because it requires \
+                                    {} to be defined.",
+                                   segment,
+                                   line,
+                                   missing.join(", ")));
+            }
+            if let Some(ref relax) = config.rules[rule].relax {
+                try!(self.apply_relaxable(rule, relax, caps, segment, line));
+            } else {
+                try!(self.apply_rule(&config.rules[rule], caps, segment, line));
+            }
+            let source = Source {
+                file: self.file,
+                line: line,
+                rule: Some(rule),
+                start: false,
+            };
+            self.sync_sources(source);
+            try!(self.check_segment_sizes(line));
+            self.statements += 1;
+            if !self.hooks.is_empty() {
+                // The rule's words start after any padding for its alignment.
+                let mut positions = positions;
+                let section = if config.rules[rule].follows_section {
+                    self.section
                 } else {
-                    use std::collections::hash_map::Entry;
-                    match self.tags.entry(s.to_string()) {
-                        Entry::Occupied(_) => {
-                            panic!("Attempted to create duplicate tag \"{}\" on line {}",
-                                   s,
-                                   line)
-                        }
-                        Entry::Vacant(v) => {
-                            v.insert(self.segments
-                                .iter()
-                                .map(|v| v.len())
-                                .collect());
-                        }
-                    }
+                    0
+                };
+                for alignment in &config.rules[rule].alignments {
+                    let position = &mut positions[swapped(alignment.segment, section)];
+                    *position = (*position + alignment.words - 1) / alignment.words *
+                                alignment.words;
                 }
-                return true;
+                self.run_rule_hooks(rule, caps, segment, line, &positions);
             }
+            self.flush_stream(false);
+            return Ok(());
         }
-        false
+        if let Err(Some(ref failure)) = attempt {
+            return Err(format!("Error: Expected {} in \"{}\" on line {}, column {}.",
+                               failure.expected(),
+                               segment,
+                               line,
+                               self.columns.0 + segment[..failure.position].chars().count()));
+        }
+        // Suggest the rules for text starting with the same letters and digits.
+        let end = segment.find(|c: char| !c.is_alphanumeric()).unwrap_or(segment.len());
+        let mnemonic = segment[..end].to_lowercase();
+        let suggestions = self.config
+            .rules
+            .iter()
+            .filter(|r| end != 0 && r.mnemonic().to_lowercase() == mnemonic)
+            .map(|r| r.syntax.as_ref().map_or_else(|| r.name(), |s| &s[..]))
+            .take(3)
+            .collect::<Vec<_>>();
+        Err(format!("Error: Unrecognized symbol \"{}\" on line {}, columns {}-{}.{}",
+                    segment,
+                    line,
+                    self.columns.0,
+                    self.columns.1,
+                    if suggestions.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" Did you mean {}?", suggestions.join(" or "))
+                    }))
     }
 
-    fn attempt_rules(&mut self, segment: &str, line: usize) -> bool {
-        let config = &self.config;
-        for rule in &config.rules {
-            if let Some(caps) = rule.regex.as_ref().unwrap().captures(segment) {
-                let mut segvals = rule.segment_values.clone();
-                for self_reference in &rule.self_references {
-                    segvals[self_reference.add_segment][self_reference.add_index] +=
-                        if self_reference.shift < 0 {
-                            (self.segments[self_reference.from_segment].len() as u64) >>
-                            ((-self_reference.shift) as u32)
-                        } else {
-                            (self.segments[self_reference.from_segment].len() as u64) <<
-                            (self_reference.shift as u32)
-                        };
+    /// Check that no segment exceeds its maximum size, where line 0 means inputs were merged.
+    fn check_segment_sizes(&self, line: usize) -> Result<(), String> {
+        for (index, sc) in self.config.segments.iter().enumerate() {
+            let len = self.segment_len(index);
+            if let Some(max_size) = sc.max_size {
+                if len > max_size {
+                    return Err(format!("Error: Segment {} grew to {} words {}, which exceeds its \
+                                        maximum size of {}.",
+                                       index,
+                                       len,
+                                       if line == 0 {
+                                           String::from("when merging inputs")
+                                       } else {
+                                           format!("on line {}", line)
+                                       },
+                                       max_size));
+                }
+            }
+            if let Some(bits) = sc.address_bits {
+                let end = (len as u64)
+                    .checked_mul(self.config.segment_widths[index] as u64)
+                    .and_then(|bytes| bytes.checked_add(sc.base_address));
+                if end.map_or(true, |end| bits < 64 && end > 1 << bits) {
+                    return Err(format!("Error: Segment {} passes the end of its {}-bit address \
+                                        space {}.",
+                                       index,
+                                       bits,
+                                       if line == 0 {
+                                           String::from("when merging inputs")
+                                       } else {
+                                           format!("on line {}", line)
+                                       }));
                 }
-                for (index, capture) in rule.captures.iter().enumerate() {
-                    use std::mem::transmute;
-                    let cap_string = caps.at(index + 1).unwrap();
-                    match *capture {
-                        Capture::Tag { ref feedbacks } => {
-                            for feedback in feedbacks {
-                                self.replacements.push(Replacement {
-                                    line: line,
-                                    shift: feedback.shift,
-                                    add_segment: feedback.add_segment,
-                                    index: self.segments[feedback.add_segment].len() +
-                                           feedback.add_index,
-                                    tag: String::from(cap_string),
-                                    pos_segment: feedback.from_segment,
-                                    pos_offset: if feedback.relative {
-                                        feedback.offset -
-                                        self.segments[feedback.from_segment].len() as isize
-                                    } else {
-                                        feedback.offset
-                                    },
-                                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate an expression from the source with the redefinable symbols in scope.
+    fn evaluate_symbols(&self,
+                        expression: &str,
+                        segment: &str,
+                        line: usize)
+                        -> Result<i64, String> {
+        let config = self.config;
+        evaluate(expression, &|symbol: &str| {
+                lookup_tag(&self.sets,
+                           &config.namespace_separator,
+                           &self.namespace,
+                           &config.tag_key(symbol))
+                    .map(|v| v.0[0] as i64)
+                    .or_else(|| self.predefined(symbol))
+            })
+            .map_err(|e| {
+                format!("Error: Failed to evaluate \"{}\" on line {}: {}", segment, line, e)
+            })
+    }
+
+    fn attempt_directives(&mut self, segment: &str, line: usize) -> Result<bool, String> {
+        let config = self.config;
+        for dr in &config.directives {
+            if let Some(caps) = dr.regex.as_ref().unwrap().captures(segment) {
+                let group = |index| required_group(&caps, index, segment, line);
+                match dr.directive {
+                    Directive::Entry => {
+                        let entry = (self.config.tag_key(try!(group(1))),
+                                     self.namespace.clone(),
+                                     line);
+                        try!(self.warn_entry_replaced(&entry));
+                        self.entry = Some(entry);
+                    }
+                    Directive::Warning => {
+                        let message = format!("{} on line {}", try!(group(1)), line);
+                        try!(self.warn("directive", &message));
+                    }
+                    Directive::Namespace => {
+                        self.namespace = self.config.tag_key(try!(group(1)));
+                    }
+                    Directive::Assert => {
+                        let expression = try!(group(1));
+                        let bindings = RefCell::new(HashMap::new());
+                        // Tags aren't known yet, so they evaluate to 0 here and are looked up
+                        // when linking.
+                        let _ = evaluate(expression, &|symbol: &str| {
+                            let value = lookup_tag(&self.sets,
+                                                   &config.namespace_separator,
+                                                   &self.namespace,
+                                                   &config.tag_key(symbol))
+                                .map(|v| v.0[0] as i64)
+                                .or_else(|| self.predefined(symbol));
+                            if let Some(value) = value {
+                                bindings.borrow_mut().insert(symbol.to_string(), value);
                             }
+                            Some(value.unwrap_or(0))
+                        });
+                        self.assertions.push(Assertion {
+                            expression: expression.to_string(),
+                            message: caps.at(2).unwrap_or("assertion failed").to_string(),
+                            bindings: bindings.into_inner(),
+                            namespace: self.namespace.clone(),
+                            segment: dr.segment,
+                            file: self.file,
+                            line: line,
+                        });
+                    }
+                    Directive::Bank => {
+                        let bank = try!(self.evaluate_symbols(try!(group(1)), segment, line));
+                        if bank < 0 {
+                            return Err(format!("Error: Negative bank {} selected on line {}.",
+                                               bank,
+                                               line));
                         }
-                        Capture::Str { add_segment } => {
-                            for c in cap_string.chars() {
-                                self.segments[add_segment].push(c as u64);
-                            }
+                        if self.stream.is_some() {
+                            return Err(String::from("Error: Banks can't be used in streaming \
+                                                     mode."));
                         }
-                        Capture::Num { ref feedbacks, ref base } => {
-                            let pval = i64::from_str_radix(cap_string, *base).unwrap_or_else(|e| {
-                                panic!("Error: Failed to parse captured string \"{}\" from \
-                                        \"{}\" on line {}: {}",
-                                       cap_string,
-                                       segment,
-                                       line,
-                                       e);
-                            });
-                            let val: u64 = unsafe { transmute(pval) };
-
-                            for feedback in feedbacks {
-                                let mut shiftval = shift_left_or_right(val, feedback.shift);
-                                if feedback.negate {
-                                    shiftval = !shiftval + 1;
-                                }
-                                if feedback.fill {
-                                    let baseval = segvals[feedback.segment][feedback.index];
-                                    let fill_amount = shiftval as isize + feedback.fill_offset;
-                                    if fill_amount.is_negative() {
-                                        panic!("Error: Got a negative fill amount!");
-                                    } else if feedback.align {
-                                        while self.segments[feedback.segment].len() <
-                                              fill_amount as usize {
-                                            self.segments[feedback.segment].push(baseval);
-                                        }
-                                    } else {
-                                        for _ in 0..fill_amount {
-                                            self.segments[feedback.segment].push(baseval);
-                                        }
-                                    }
-                                    segvals[feedback.segment].pop();
-                                } else {
-                                    segvals[feedback.segment][feedback.index] += shiftval;
-                                }
+                        self.select_region(dr.segment, Placement::Bank(bank as usize));
+                    }
+                    Directive::Region => {
+                        let name = try!(group(1));
+                        let (index, region) = try!(config.segments
+                            .iter()
+                            .enumerate()
+                            .filter_map(|(i, sc)| {
+                                sc.regions.iter().position(|r| r.name == name).map(|r| (i, r))
+                            })
+                            .next()
+                            .ok_or_else(|| {
+                                format!("Error: Region \"{}\" selected on line {} is not declared.",
+                                        name,
+                                        line)
+                            }));
+                        if self.stream.is_some() {
+                            return Err(String::from("Error: Regions can't be used in streaming \
+                                                     mode."));
+                        }
+                        self.select_region(index, Placement::Region(region));
+                    }
+                    Directive::Section => {
+                        let section = try!(self.evaluate_symbols(try!(group(1)), segment, line));
+                        if section < 0 || section as usize >= self.segments.len() {
+                            return Err(format!("Error: Section {} selected on line {} is not a \
+                                                segment.",
+                                               section,
+                                               line));
+                        }
+                        self.section = section as usize;
+                    }
+                    Directive::Set => {
+                        let name = qualify(&self.config.namespace_separator,
+                                           &self.namespace,
+                                           &self.config.tag_key(try!(group(1))));
+                        let value = try!(self.evaluate_symbols(try!(group(2)), segment, line));
+                        self.sets.insert(name, (vec![value as usize; self.segments.len()], false));
+                    }
+                    Directive::Repeat => {
+                        let count = try!(self.evaluate_symbols(try!(group(1)), segment, line));
+                        let value = try!(self.evaluate_symbols(try!(group(2)), segment, line));
+                        if count < 0 {
+                            return Err(format!("Error: Negative repeat count {} on line {}.",
+                                               count,
+                                               line));
+                        }
+                        try!(check_fill(count as usize, line));
+                        pad(&mut self.segments[dr.segment], count as usize, &[value as u64]);
+                        let source = Source {
+                            file: self.file,
+                            line: line,
+                            rule: None,
+                            start: false,
+                        };
+                        self.sync_sources(source);
+                        try!(self.check_segment_sizes(line));
+                        self.flush_stream(false);
+                    }
+                    Directive::Space => {
+                        let count = try!(self.evaluate_symbols(try!(group(1)), segment, line));
+                        if count < 0 {
+                            return Err(format!("Error: Negative amount of words {} to reserve on \
+                                                line {}.",
+                                               count,
+                                               line));
+                        }
+                        let fill = match caps.at(2) {
+                            Some(value) => {
+                                vec![try!(self.evaluate_symbols(value, segment, line)) as u64]
                             }
+                            None => self.config.segments[dr.segment].fill_words(),
+                        };
+                        try!(check_fill(count as usize, line));
+                        pad(&mut self.segments[dr.segment], count as usize, &fill);
+                        let source = Source {
+                            file: self.file,
+                            line: line,
+                            rule: None,
+                            start: false,
+                        };
+                        self.sync_sources(source);
+                        try!(self.check_segment_sizes(line));
+                        self.flush_stream(false);
+                    }
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn attempt_tag_create(&mut self, segment: &str, line: usize) -> Result<bool, String> {
+        for tc in &self.config.tag_rules {
+            if let Some(caps) = tc.regex.as_ref().unwrap().captures(segment) {
+                let s = &self.config.tag_key(try!(required_group(&caps, 1, segment, line)));
+                let anonymous = anonymous_label(self.config, s);
+                if let Some(Anonymous::Forward(depth)) = anonymous {
+                    self.forward_tags.push((depth, self.positions()));
+                } else if let Some(Anonymous::Backward(depth)) = anonymous {
+                    self.backward_tags.push((depth, self.positions()));
+                } else {
+                    let name = qualify(&self.config.namespace_separator, &self.namespace, s);
+                    if !tc.redefinable && self.tags.contains_key(&name) {
+                        return Err(format!("Attempted to create duplicate tag \"{}\" on line {}",
+                                           s,
+                                           line));
+                    }
+                    let positions = self.positions();
+                    for hook in &mut self.hooks {
+                        hook.tag(&TagEvent {
+                            name: &name,
+                            positions: &positions,
+                            redefinable: tc.redefinable,
+                            line: line,
+                        });
+                    }
+                    if tc.redefinable {
+                        self.sets.insert(name, (positions, true));
+                        return Ok(true);
+                    }
+                    self.tags.insert(name.clone(), positions);
+                    self.definitions.insert(name, (self.file, line));
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Emit the characters of a string capture.
+    fn emit_string(&mut self, text: &str, options: &StrCapture, line: usize) -> Result<(), String> {
+        let config = self.config;
+        let codes = match options.encoding {
+            Some(ref encoding) => {
+                let table = &config.encodings[encoding];
+                try!(text.chars()
+                    .map(|c| {
+                        table.chars().position(|t| t == c).map(|code| code as u64).ok_or_else(|| {
+                            format!("Error: Character '{}' on line {} isn't in encoding \"{}\".",
+                                    c,
+                                    line,
+                                    encoding)
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>())
+            }
+            None => text.chars().map(|c| c as u64).collect(),
+        };
+        if let Some(ref prefix) = options.length_prefix {
+            let segment = prefix.segment.unwrap_or(options.add_segment);
+            let bits = config.segment_widths[segment] * 8;
+            let total_bits = bits * prefix.words;
+            if total_bits < 64 && codes.len() as u64 >= 1 << total_bits {
+                return Err(format!("Error: The string on line {} is too long for its length \
+                                    prefix.",
+                                   line));
+            }
+            for word in (0..prefix.words).rev() {
+                let shift = word * bits;
+                self.segments[segment].push(if shift >= 64 {
+                    0
+                } else {
+                    (codes.len() as u64 >> shift) & (!0u64 >> (64 - bits))
+                });
+            }
+        }
+        let mut codes = codes;
+        if let Some(terminator) = options.terminate {
+            codes.push(terminator);
+        }
+        if let Some(ref pack) = options.pack {
+            let bits = config.segment_widths[options.add_segment] * 8 / pack.chars_per_word;
+            let mask = !0u64 >> (64 - bits);
+            codes = try!(codes.chunks(pack.chars_per_word)
+                .map(|chunk| {
+                    let mut word = 0;
+                    for i in 0..pack.chars_per_word {
+                        let code = chunk.get(i).cloned().unwrap_or(pack.pad);
+                        if code > mask {
+                            return Err(format!("Error: Character code {} on line {} doesn't fit \
+                                                in {} bits when packed.",
+                                               code,
+                                               line,
+                                               bits));
                         }
+                        let slot = match pack.order {
+                            PackOrder::HighFirst => pack.chars_per_word - 1 - i,
+                            PackOrder::LowFirst => i,
+                        };
+                        word |= code << (slot * bits);
+                    }
+                    Ok(word)
+                })
+                .collect::<Result<Vec<_>, _>>());
+        }
+        self.segments[options.add_segment].extend(codes);
+        Ok(())
+    }
+
+    /// Feed a captured number into the words a rule is about to emit, or fill with it.
+    fn apply_num_feedbacks(&mut self,
+                           rule: &Rule,
+                           val: u64,
+                           feedbacks: &[NumFeedback],
+                           line: usize,
+                           segvals: &mut Vec<Vec<u64>>)
+                           -> Result<(), String> {
+        let config = self.config;
+        for feedback in feedbacks {
+            let val = if feedback.bit {
+                if val >= 64 {
+                    return Err(format!("Error: Bit number {} on line {} isn't between 0 and 63.",
+                                       val as i64,
+                                       line));
+                }
+                1 << val
+            } else {
+                val
+            };
+            if let Some(ref name) = feedback.field {
+                let field = rule.bit_field(name).unwrap();
+                let val = if feedback.negate {
+                    (!val).wrapping_add(1)
+                } else {
+                    val
+                };
+                if !field.fits(val as i64) {
+                    return Err(format!("Error: Value {} on line {} doesn't fit in the {} bits of \
+                                        field \"{}\" of rule \"{}\".",
+                                       val as i64,
+                                       line,
+                                       field.bits(),
+                                       field.name,
+                                       rule.name()));
+                }
+                segvals[field.segment][field.index] |= field.place(val);
+                continue;
+            }
+            let shiftval = if feedback.width.is_some() {
+                let val = if feedback.negate {
+                    val.wrapping_neg()
+                } else {
+                    val
+                };
+                let val = try!(fit_width(val, feedback.width, feedback.overflow)
+                    .ok_or_else(|| too_wide(val, line, feedback.width)));
+                shift_left_or_right(val, feedback.shift)
+            } else {
+                let shiftval = shift_left_or_right(val, feedback.shift);
+                if feedback.negate {
+                    shiftval.wrapping_neg()
+                } else {
+                    shiftval
+                }
+            };
+            if feedback.fill {
+                let pattern = if feedback.segment_fill {
+                    config.segments[feedback.segment].fill_words()
+                } else {
+                    segvals[feedback.segment][feedback.index..feedback.index +
+                                                               feedback.pattern_words]
+                        .to_vec()
+                };
+                let fill_amount = (shiftval as isize).wrapping_add(feedback.fill_offset);
+                if fill_amount.is_negative() {
+                    return Err(String::from("Error: Got a negative fill amount!"));
+                } else if feedback.align {
+                    self.note_position_use("An align fill");
+                    let len = self.segment_len(feedback.segment);
+                    if len < fill_amount as usize {
+                        try!(check_fill(fill_amount as usize - len, line));
+                        pad(&mut self.segments[feedback.segment],
+                            fill_amount as usize - len,
+                            &pattern);
+                    }
+                } else {
+                    try!(check_fill(fill_amount as usize, line));
+                    pad(&mut self.segments[feedback.segment], fill_amount as usize, &pattern);
+                }
+                let pattern_words = if feedback.segment_fill {
+                    1
+                } else {
+                    feedback.pattern_words
+                };
+                for _ in 0..pattern_words {
+                    segvals[feedback.segment].pop();
+                }
+            } else if feedback.bit {
+                // Bits are set rather than added so repeated elements don't carry.
+                segvals[feedback.segment][feedback.index] |= shiftval;
+            } else {
+                let word = &mut segvals[feedback.segment][feedback.index];
+                *word = try!(combine_value(*word,
+                                           shiftval,
+                                           feedback.combine,
+                                           feedback.overflow,
+                                           feedback.width,
+                                           feedback.shift)
+                    .ok_or_else(|| {
+                        overflowed(shiftval, line, feedback.width, config, feedback.segment)
+                    }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Exchange segment 0 with another segment while a rule following the section is handled.
+    fn swap_segments(&mut self, section: usize) {
+        if section != 0 {
+            self.segments.swap(0, section);
+            self.bases.swap(0, section);
+            if let Some(ref mut stream) = self.stream {
+                stream.flushed.swap(0, section);
+            }
+        }
+    }
+
+    /// The symbols a rule requires which aren't defined.
+    fn missing_requirements<'b>(&self, rule: &'b Rule) -> Vec<&'b str> {
+        rule.requires
+            .iter()
+            .filter(|symbol| !self.sets.contains_key(&self.config.tag_key(symbol)))
+            .map(|symbol| &symbol[..])
+            .collect()
+    }
+
+    /// Try every rule in order, returning the index and groups of the rule that matched or where
+    /// the grammar rule which got the farthest stopped matching.
+    fn attempt_rules<'t>(&mut self,
+                         segment: &'t str)
+                         -> Result<(usize, Groups<'t>), Option<Failure>> {
+        let config = self.config;
+        let mut farthest: Option<Failure> = None;
+        for (rule_index, rule) in config.rules.iter().enumerate() {
+            let groups = if self.profile.is_empty() {
+                config.rule_groups(rule, segment)
+            } else {
+                let started = Instant::now();
+                let groups = config.rule_groups(rule, segment);
+                let elapsed = started.elapsed();
+                let profile = &mut self.profile[rule_index];
+                profile.attempts += 1;
+                profile.matches += groups.is_ok() as usize;
+                profile.nanoseconds += elapsed.as_secs() * 1_000_000_000 +
+                                       elapsed.subsec_nanos() as u64;
+                groups
+            };
+            let caps = match groups {
+                Ok(caps) => caps,
+                Err(failure) => {
+                    if failure.as_ref().map_or(0, |f| f.position) >
+                       farthest.as_ref().map_or(0, |f| f.position) {
+                        farthest = failure;
+                    }
+                    continue;
+                }
+            };
+            return Ok((rule_index, caps));
+        }
+        Err(farthest)
+    }
+
+    /// Emit the short encoding of an instruction and keep its long encoding in case its tag
+    /// references don't fit when linking.
+    fn apply_relaxable(&mut self,
+                       rule_index: usize,
+                       relax: &Relaxation,
+                       caps: &Groups,
+                       segment: &str,
+                       line: usize)
+                       -> Result<(), String> {
+        let config = self.config;
+        if self.stream.is_some() {
+            return Err(String::from("Error: Relaxable instructions can't be used in streaming \
+                                     mode."));
+        }
+        let long_rule = config.rules.iter().position(|r| r.name() == relax.rule).unwrap();
+        let long_caps = try!(config.rule_groups(&config.rules[long_rule], segment)
+            .map_err(|_| {
+                format!("Error: The long encoding \"{}\" doesn't match \"{}\" on line {}.",
+                        relax.rule,
+                        segment,
+                        line)
+            }));
+        let positions = self.positions();
+        let lengths = self.segments.iter().map(|words| words.len()).collect::<Vec<_>>();
+        let replacement_count = self.replacements.len();
+        try!(self.apply_rule(&config.rules[long_rule], &long_caps, segment, line));
+        let long = self.segments
+            .iter_mut()
+            .zip(&lengths)
+            .map(|(words, &length)| words.split_off(length))
+            .collect();
+        let long_replacements = self.replacements.split_off(replacement_count);
+        try!(self.apply_rule(&config.rules[rule_index], caps, segment, line));
+        let replacements = self.replacements.split_off(replacement_count);
+        self.relaxables.push(Relaxable {
+            rule: rule_index,
+            long_rule: long_rule,
+            positions: positions,
+            lengths: self.segments
+                .iter()
+                .zip(&lengths)
+                .map(|(words, &length)| words.len() - length)
+                .collect(),
+            long: long,
+            replacements: replacements,
+            long_replacements: long_replacements,
+            relaxed: false,
+            file: self.file,
+            line: line,
+        });
+        Ok(())
+    }
+
+    /// Handle the text of one capture of a rule.
+    fn apply_capture(&mut self,
+                     rule: &Rule,
+                     capture: &Capture,
+                     cap_string: &str,
+                     segment: &str,
+                     line: usize,
+                     section: usize,
+                     segvals: &mut Vec<Vec<u64>>)
+                     -> Result<(), String> {
+        use std::mem::transmute;
+        let config = self.config;
+        match *capture {
+            Capture::Tag { ref feedbacks } => {
+                let cap_string = &config.tag_key(cap_string);
+                // Redefinable symbols bind to their most recent definition right now.
+                let redefinable = lookup_tag(&self.sets,
+                                             &config.namespace_separator,
+                                             &self.namespace,
+                                             cap_string)
+                    .cloned();
+                for feedback in feedbacks {
+                    let reference = self.segment_len(feedback.from_segment);
+                    let banks = config.segments[swapped(feedback.from_segment,
+                                                        section)]
+                        .banks
+                        .as_ref();
+                    let pos_offset = if feedback.relative {
+                        feedback.offset.wrapping_sub(banks.map_or(reference, |b| {
+                            bank_address(b, feedback.bank, reference)
+                        }) as isize)
+                    } else {
+                        feedback.offset
+                    };
+                    // Constants can be added right away, but positions have to move
+                    // with their segment if this is merged after other inputs.
+                    if let Some((ref tag, false)) = redefinable {
+                        try!(check_target_alignment(cap_string,
+                                                    line,
+                                                    tag[feedback.from_segment],
+                                                    feedback.target_alignment));
+                        let position = (tag[feedback.from_segment] as isize)
+                            .wrapping_add(pos_offset) as u64;
+                        let position = try!(fit_width(position, feedback.width, feedback.overflow)
+                            .ok_or_else(|| too_wide(position, line, feedback.width)));
+                        let value = shift_left_or_right(position, feedback.shift);
+                        let word = &mut segvals[feedback.add_segment][feedback.add_index];
+                        *word = try!(combine_value(*word,
+                                                   value,
+                                                   feedback.combine,
+                                                   feedback.overflow,
+                                                   feedback.width,
+                                                   feedback.shift)
+                            .ok_or_else(|| {
+                                overflowed(value,
+                                           line,
+                                           feedback.width,
+                                           config,
+                                           feedback.add_segment)
+                            }));
+                        continue;
+                    }
+                    self.replacements.push(Replacement {
+                        line: line,
+                        shift: feedback.shift,
+                        add_segment: feedback.add_segment,
+                        index: self.segment_len(feedback.add_segment) +
+                               feedback.add_index,
+                        tag: String::from(cap_string),
+                        namespace: self.namespace.clone(),
+                        anonymous_index: match anonymous_label(config, cap_string) {
+                            Some(Anonymous::Forward(_)) => self.forward_tags.len(),
+                            Some(Anonymous::Backward(_)) => self.backward_tags.len(),
+                            None => 0,
+                        },
+                        pos_segment: feedback.from_segment,
+                        pos_offset: pos_offset,
+                        relative: feedback.relative,
+                        bound: redefinable.as_ref().map(|v| v.0.clone()),
+                        bank: feedback.bank,
+                        reference_bank: banks.map_or(0, |b| reference / b.size),
+                        reference: reference,
+                        target_alignment: feedback.target_alignment,
+                        width: feedback.width,
+                        combine: feedback.combine,
+                        overflow: feedback.overflow,
+                    });
+                }
+            }
+            Capture::Str(ref options) => {
+                let text = self.predefined_string(cap_string);
+                try!(self.emit_string(text.as_ref().map_or(cap_string, |t| &t[..]), options, line));
+            }
+            Capture::List { ref separator, ref prefix, ref element } => {
+                let items = cap_string.split(&separator[..])
+                    .map(str::trim)
+                    .filter(|i| !i.is_empty());
+                for item in items {
+                    let item = if item.starts_with(&prefix[..]) {
+                        &item[prefix.len()..]
+                    } else {
+                        item
+                    };
+                    try!(self.apply_capture(rule, element, item, segment, line, section, segvals));
+                }
+            }
+            Capture::Operand { ref name } => {
+                let (form, caps) = try!(config.operands[name]
+                    .iter()
+                    .filter_map(|f| f.regex.as_ref().unwrap().captures(cap_string).map(|c| (f, c)))
+                    .next()
+                    .ok_or_else(|| {
+                        format!("Error: \"{}\" in \"{}\" on line {} isn't a valid {} operand{}.",
+                                cap_string,
+                                segment,
+                                line,
+                                name,
+                                rule.syntax_note())
+                    }));
+                for (index, (words, values)) in segvals.iter_mut()
+                    .zip(&form.segment_values)
+                    .enumerate() {
+                    let target = swapped(index, section);
+                    let width = min(config.segment_widths[target] * 8, 64) as u32;
+                    for (word, &value) in words.iter_mut().zip(values) {
+                        *word = try!(combine_value(*word,
+                                                   value,
+                                                   Combine::Add,
+                                                   form.overflow,
+                                                   Some(width),
+                                                   0)
+                            .ok_or_else(|| overflowed(value, line, Some(width), config, target)));
                     }
                 }
-                for (segvec, segment) in segvals.iter_mut().zip(self.segments.iter_mut()) {
-                    segment.append(segvec);
+                for (index, capture) in form.captures.iter().enumerate() {
+                    if let Some(text) = caps.at(index + 1) {
+                        try!(self.apply_capture(rule,
+                                                capture,
+                                                text,
+                                                segment,
+                                                line,
+                                                section,
+                                                segvals));
+                    }
+                }
+            }
+            Capture::Plugin { ref name, ref args } => {
+                let plugin = try!(self.plugins.get(name).ok_or_else(|| {
+                    format!("Error: Capture plugin \"{}\" used on line {} was never registered.",
+                            name,
+                            line)
+                }));
+                try!(plugin.capture(cap_string, args.as_ref(), segvals)
+                    .map_err(|e| {
+                        format!("Error: Capture plugin \"{}\" failed on \"{}\" on line {}: {}{}",
+                                name,
+                                cap_string,
+                                line,
+                                e,
+                                rule.syntax_note())
+                    }));
+            }
+            Capture::Num { ref feedbacks, ref base, min, max } => {
+                let pval = try!(i64::from_str_radix(cap_string, *base)
+                    .or_else(|e| self.predefined(cap_string).ok_or(e))
+                    .map_err(|e| {
+                        format!("Error: Failed to parse captured string \"{}\" from \"{}\" on \
+                                 line {}: {}{}",
+                                cap_string,
+                                segment,
+                                line,
+                                e,
+                                rule.syntax_note())
+                    }));
+                let limit = match (min, max) {
+                    (Some(min), _) if pval < min => Some(("below the minimum", min)),
+                    (_, Some(max)) if pval > max => Some(("above the maximum", max)),
+                    _ => None,
+                };
+                if let Some((problem, limit)) = limit {
+                    return Err(format!("Error: Value {} in \"{}\" on line {} is {} of {} for \
+                                        rule \"{}\".",
+                                       pval,
+                                       segment,
+                                       line,
+                                       problem,
+                                       limit,
+                                       rule.name()));
+                }
+                let val: u64 = unsafe { transmute(pval) };
+                try!(self.apply_num_feedbacks(rule, val, feedbacks, line, segvals));
+            }
+            Capture::Fixed { integer_bits,
+                             fraction_bits,
+                             signed,
+                             rounding,
+                             ref feedbacks } => {
+                let val = try!(to_fixed(cap_string,
+                                        integer_bits,
+                                        fraction_bits,
+                                        signed,
+                                        rounding)
+                    .map_err(|e| {
+                        format!("Error: Failed to convert captured string \"{}\" from \"{}\" to \
+                                 fixed point on line {}: {}{}",
+                                cap_string,
+                                segment,
+                                line,
+                                e,
+                                rule.syntax_note())
+                    }));
+                try!(self.apply_num_feedbacks(rule, val, feedbacks, line, segvals));
+            }
+            Capture::Bcd { digits, ref feedbacks } => {
+                let val = try!(to_bcd(cap_string, digits.unwrap_or(16))
+                    .map_err(|e| {
+                        format!("Error: Failed to encode captured string \"{}\" from \"{}\" as \
+                                 BCD on line {}: {}{}",
+                                cap_string,
+                                segment,
+                                line,
+                                e,
+                                rule.syntax_note())
+                    }));
+                try!(self.apply_num_feedbacks(rule, val, feedbacks, line, segvals));
+            }
+            Capture::Float { width, ref feedbacks } => {
+                let invalid = |e: &fmt::Display| {
+                    format!("Error: Failed to parse captured float \"{}\" from \"{}\" on line \
+                            {}: {}{}",
+                           cap_string,
+                           segment,
+                           line,
+                           e,
+                           rule.syntax_note())
+                };
+                // Parse single precision floats directly so they are rounded once.
+                let (finite, val): (bool, u64) = if width == 32 {
+                    let pval = try!(cap_string.parse::<f32>().map_err(|e| invalid(&e)));
+                    let bits: u32 = unsafe { transmute(pval) };
+                    (pval.is_finite(), bits as u64)
+                } else {
+                    let pval = try!(cap_string.parse::<f64>().map_err(|e| invalid(&e)));
+                    (pval.is_finite(), unsafe { transmute(pval) })
+                };
+                if !finite {
+                    return Err(invalid(&format!("it isn't finite in {} bits", width)));
                 }
-                return true;
+                try!(self.apply_num_feedbacks(rule, val, feedbacks, line, segvals));
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the words of a rule which matched a statement.
+    fn apply_rule(&mut self,
+                  rule: &Rule,
+                  caps: &Groups,
+                  segment: &str,
+                  line: usize)
+                  -> Result<(), String> {
+        let config = self.config;
+        let section = if rule.follows_section {
+            self.section
+        } else {
+            0
+        };
+        for alignment in &rule.alignments {
+            let target = swapped(alignment.segment, section);
+            let misalignment = self.segment_len(target) % alignment.words;
+            if misalignment != 0 && alignment.strict {
+                return Err(format!("Error: \"{}\" on line {} must start at a multiple of {} in \
+                                    segment {}, but starts at {}.",
+                                   segment,
+                                   line,
+                                   alignment.words,
+                                   self.config.segment_name(target),
+                                   self.segment_len(target)));
+            }
+            pad(&mut self.segments[target],
+                (alignment.words - misalignment) % alignment.words,
+                &config.segments[target].fill_words());
+            let alignments = if alignment.strict {
+                &mut self.strict_alignments
+            } else {
+                &mut self.alignments
+            };
+            alignments[target] = max(alignments[target], alignment.words);
+        }
+        self.sync_padding();
+        // The rule emits to the active section as if it were segment 0, which is swapped back
+        // even if it fails so the words before it stay where they are.
+        let replacement_count = self.replacements.len();
+        self.swap_segments(section);
+        let emitted = self.emit_rule_values(rule, caps, segment, line, section);
+        self.swap_segments(section);
+        for r in &mut self.replacements[replacement_count..] {
+            r.add_segment = swapped(r.add_segment, section);
+            r.pos_segment = swapped(r.pos_segment, section);
+        }
+        emitted
+    }
+
+    /// Compute the words of a rule from its captures and emit them, with the section it emits to
+    /// as segment 0.
+    fn emit_rule_values(&mut self,
+                        rule: &Rule,
+                        caps: &Groups,
+                        segment: &str,
+                        line: usize,
+                        section: usize)
+                        -> Result<(), String> {
+        let config = self.config;
+        let mut segvals = rule.segment_values.clone();
+        for self_reference in &rule.self_references {
+            let from = self_reference.from_segment;
+            if self_reference.base != SelfReferenceBase::SegmentBase {
+                self.note_position_use("A self-reference");
             }
+            let position = match self_reference.base {
+                SelfReferenceBase::Start => self.segment_len(from),
+                SelfReferenceBase::End => self.segment_len(from) + segvals[from].len(),
+                SelfReferenceBase::Word(index) => self.segment_len(from) + index,
+                SelfReferenceBase::SegmentBase => {
+                    (config.segments[from].base_address / config.segment_widths[from] as u64) as
+                    usize
+                }
+            };
+            let position = match config.segments[swapped(from, section)].banks {
+                Some(ref banks) => bank_address(banks, self_reference.bank, position),
+                None => position,
+            };
+            let position = (position as isize).wrapping_add(self_reference.offset);
+            let position = try!(fit_width(position as u64,
+                                          self_reference.width,
+                                          self_reference.overflow)
+                .ok_or_else(|| too_wide(position as u64, line, self_reference.width)));
+            let value = shift_left_or_right(position, self_reference.shift);
+            let word = &mut segvals[self_reference.add_segment][self_reference.add_index];
+            *word = try!(combine_value(*word,
+                                       value,
+                                       self_reference.combine,
+                                       self_reference.overflow,
+                                       self_reference.width,
+                                       self_reference.shift)
+                .ok_or_else(|| {
+                    overflowed(value,
+                               line,
+                               self_reference.width,
+                               config,
+                               swapped(self_reference.add_segment, section))
+                }));
+        }
+        for (index, capture) in rule.captures.iter().enumerate() {
+            let cap_string = match caps.at(index + 1).or_else(|| rule.capture_default(index)) {
+                Some(cap_string) => cap_string,
+                None => continue,
+            };
+            try!(self.apply_capture(rule,
+                                    capture,
+                                    cap_string,
+                                    segment,
+                                    line,
+                                    section,
+                                    &mut segvals));
+        }
+        for computed in &rule.computed {
+            let value = {
+                let sets = &self.sets;
+                let namespace = &self.namespace;
+                let symbol = |symbol: &str| {
+                    lookup_tag(sets,
+                               &config.namespace_separator,
+                               namespace,
+                               &config.tag_key(symbol))
+                        .map(|v| v.0[0] as i64)
+                        .or_else(|| self.predefined(symbol))
+                };
+                let lookup = |name: &str| {
+                    if name.starts_with('$') {
+                        name[1..]
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|i| {
+                                caps.at(i).or_else(|| rule.capture_default(i.wrapping_sub(1)))
+                            })
+                            .and_then(|text| evaluate(text, &symbol).ok())
+                    } else {
+                        symbol(name)
+                    }
+                };
+                let call = |name: &str, args: &[i64]| {
+                    config.tables.get(name).map(|table| {
+                        if args.len() != 1 {
+                            return Err(format!("table \"{}\" takes one index", name));
+                        }
+                        table.get(args[0] as usize).cloned().ok_or_else(|| {
+                            format!("index {} is out of range of table \"{}\"",
+                                    args[0],
+                                    name)
+                        })
+                    })
+                };
+                try!(evaluate_with_functions(&computed.expression, &lookup, &call)
+                    .map_err(|e| {
+                        format!("Error: Failed to compute \"{}\" for \"{}\" on line {}: {}",
+                                computed.expression,
+                                segment,
+                                line,
+                                e)
+                    }))
+            };
+            let word = &mut segvals[computed.segment][computed.index];
+            *word = word.wrapping_add(value as u64);
+        }
+        for (segvec, segment) in segvals.iter_mut().zip(self.segments.iter_mut()) {
+            segment.append(segvec);
         }
-        false
+        Ok(())
     }
 }
@@ -0,0 +1,14 @@
+use serde_json::Value;
+
+/// A capture kind implemented outside of the assembler, used by `Plugin` captures with the name
+/// it is registered under.
+pub trait CapturePlugin {
+    /// Encode the text of a capture group into the words a rule is about to emit in each
+    /// segment. `args` are the arguments given to the capture in the config (if any). An error
+    /// is reported with the line the rule matched on.
+    fn capture(&self,
+               text: &str,
+               args: Option<&Value>,
+               values: &mut [Vec<u64>])
+               -> Result<(), String>;
+}
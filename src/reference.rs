@@ -0,0 +1,241 @@
+use super::config::{Capture, Combine, Config, NumFeedback, Rule};
+use std::io::Write;
+
+/// Formats an instruction set reference can be written in.
+#[derive(Debug, Clone, Copy)]
+pub enum ReferenceFormat {
+    Markdown,
+    Html,
+}
+
+/// A description of one capture group of a rule.
+struct Operand {
+    kind: String,
+    places: Vec<String>,
+}
+
+fn shifted(place: String, shift: i32) -> String {
+    if shift > 0 {
+        format!("{} << {}", place, shift)
+    } else if shift < 0 {
+        format!("{} >> {}", place, -shift)
+    } else {
+        place
+    }
+}
+
+fn combined(place: String, combine: Combine) -> String {
+    match combine {
+        Combine::Add => place,
+        Combine::Or => format!("{} (ORed)", place),
+        Combine::Xor => format!("{} (XORed)", place),
+        Combine::AndNot => format!("{} (clearing its bits)", place),
+        Combine::Replace => format!("{} (replacing it)", place),
+    }
+}
+
+fn num_places(feedbacks: &[NumFeedback]) -> Vec<String> {
+    feedbacks.iter()
+        .map(|f| {
+            let place = format!("segment {} word {}", f.segment, f.index);
+            let place = if let Some(ref field) = f.field {
+                format!("field {}", field)
+            } else if f.fill {
+                format!("fills segment {} from word {}", f.segment, f.index)
+            } else {
+                shifted(place, f.shift)
+            };
+            let place = if f.bit {
+                format!("{} (one bit per value)", place)
+            } else {
+                place
+            };
+            let place = match f.width {
+                Some(width) => format!("{} ({} bits)", place, width),
+                None => place,
+            };
+            let place = combined(place, f.combine);
+            if f.negate {
+                format!("{} (negated)", place)
+            } else {
+                place
+            }
+        })
+        .collect()
+}
+
+fn operand(capture: &Capture) -> Operand {
+    let (kind, places) = match *capture {
+        Capture::Tag { ref feedbacks } => {
+            (String::from("tag"),
+             feedbacks.iter()
+                .map(|f| {
+                    let place = combined(shifted(format!("segment {} word {}",
+                                                         f.add_segment,
+                                                         f.add_index),
+                                                 f.shift),
+                                         f.combine);
+                    let place = match f.width {
+                        Some(width) => format!("{} ({} bits)", place, width),
+                        None => place,
+                    };
+                    format!("{} ({} position in segment {}{})",
+                            place,
+                            if f.relative { "relative" } else { "absolute" },
+                            f.from_segment,
+                            f.target_alignment
+                                .map_or_else(String::new, |a| format!(", aligned to {}", a)))
+                })
+                .collect())
+        }
+        Capture::Str(ref options) => {
+            (options.encoding.as_ref().map_or_else(|| String::from("string"),
+                                                   |e| format!("string ({})", e)),
+             vec![format!("segment {}", options.add_segment)])
+        }
+        Capture::Num { base, ref feedbacks, min, max } => {
+            let range = match (min, max) {
+                (Some(min), Some(max)) => format!(" from {} to {}", min, max),
+                (Some(min), None) => format!(" of at least {}", min),
+                (None, Some(max)) => format!(" of at most {}", max),
+                (None, None) => String::new(),
+            };
+            (format!("base {} number{}", base, range), num_places(feedbacks))
+        }
+        Capture::Float { width, ref feedbacks } => {
+            (format!("{}-bit float", width), num_places(feedbacks))
+        }
+        Capture::Fixed { integer_bits, fraction_bits, signed, ref feedbacks, .. } => {
+            (format!("{}Q{}.{} fixed point",
+                     if signed { "signed " } else { "" },
+                     integer_bits,
+                     fraction_bits),
+             num_places(feedbacks))
+        }
+        Capture::Bcd { digits, ref feedbacks } => {
+            (format!("{}-digit BCD number", digits.unwrap_or(16)), num_places(feedbacks))
+        }
+        Capture::List { ref separator, ref element, .. } => {
+            let element = operand(element);
+            (format!("list of {} separated by \"{}\"", element.kind, separator), element.places)
+        }
+        Capture::Operand { ref name } => (format!("{} operand", name), Vec::new()),
+        Capture::Plugin { ref name, .. } => (format!("{} plugin", name), Vec::new()),
+    };
+    Operand {
+        kind: kind,
+        places: places,
+    }
+}
+
+/// The base words a rule emits to each segment in hex, as wide as the segment.
+fn encoding(config: &Config, rule: &Rule) -> Vec<(usize, String)> {
+    rule.segment_values
+        .iter()
+        .enumerate()
+        .filter(|&(_, words)| !words.is_empty())
+        .map(|(segment, words)| {
+            let digits = config.segment_widths[segment] * 2;
+            (segment,
+             words.iter()
+                .map(|w| format!("{:01$X}", w, digits))
+                .collect::<Vec<_>>()
+                .join(" "))
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn escape_markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+/// Write a reference of every rule in the config with how it is written, what its operands are
+/// and where they go, and the base words it emits to each segment.
+pub fn write_reference<W>(config: &Config, format: ReferenceFormat, w: &mut W)
+    where W: Write
+{
+    let title = format!("{} instruction set reference",
+                        config.name.as_ref().map_or("Unnamed", |n| &n[..]));
+    let mut out = String::new();
+    match format {
+        ReferenceFormat::Markdown => {
+            out.push_str(&format!("# {}\n\nConfig: {}\n\n", title, config.identity()));
+            if let Some(ref description) = config.description {
+                out.push_str(&format!("{}\n\n", description));
+            }
+        }
+        ReferenceFormat::Html => {
+            out.push_str(&format!("<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n\
+                                   <body>\n<h1>{0}</h1>\n",
+                                  escape_html(&title)));
+            out.push_str(&format!("<p>Config: {}</p>\n", escape_html(&config.identity())));
+            if let Some(ref description) = config.description {
+                out.push_str(&format!("<p>{}</p>\n", escape_html(description)));
+            }
+        }
+    }
+    for rule in &config.rules {
+        let syntax = rule.syntax.as_ref().map_or_else(|| rule.name(), |s| &s[..]);
+        let operands = rule.captures.iter().map(operand).collect::<Vec<_>>();
+        let encoding = encoding(config, rule);
+        match format {
+            ReferenceFormat::Markdown => {
+                out.push_str(&format!("## `{}`\n\n", syntax));
+                if let Some(ref doc) = rule.doc {
+                    out.push_str(&format!("{}\n\n", doc));
+                }
+                if !operands.is_empty() {
+                    out.push_str("| Operand | Kind | Placed in |\n|---|---|---|\n");
+                    for (index, operand) in operands.iter().enumerate() {
+                        out.push_str(&format!("| ${} | {} | {} |\n",
+                                              index + 1,
+                                              escape_markdown_cell(&operand.kind),
+                                              escape_markdown_cell(&operand.places.join(", "))));
+                    }
+                    out.push('\n');
+                }
+                for &(segment, ref words) in &encoding {
+                    out.push_str(&format!("- segment {}: `{}`\n", segment, words));
+                }
+                if !encoding.is_empty() {
+                    out.push('\n');
+                }
+            }
+            ReferenceFormat::Html => {
+                out.push_str(&format!("<h2><code>{}</code></h2>\n", escape_html(syntax)));
+                if let Some(ref doc) = rule.doc {
+                    out.push_str(&format!("<p>{}</p>\n", escape_html(doc)));
+                }
+                if !operands.is_empty() {
+                    out.push_str("<table>\n<tr><th>Operand</th><th>Kind</th><th>Placed in</th>\
+                                  </tr>\n");
+                    for (index, operand) in operands.iter().enumerate() {
+                        out.push_str(&format!("<tr><td>${}</td><td>{}</td><td>{}</td></tr>\n",
+                                              index + 1,
+                                              escape_html(&operand.kind),
+                                              escape_html(&operand.places.join(", "))));
+                    }
+                    out.push_str("</table>\n");
+                }
+                if !encoding.is_empty() {
+                    out.push_str("<ul>\n");
+                    for &(segment, ref words) in &encoding {
+                        out.push_str(&format!("<li>segment {}: <code>{}</code></li>\n",
+                                              segment,
+                                              words));
+                    }
+                    out.push_str("</ul>\n");
+                }
+            }
+        }
+    }
+    if let ReferenceFormat::Html = format {
+        out.push_str("</body>\n</html>\n");
+    }
+    w.write_all(out.as_bytes())
+        .unwrap_or_else(|e| panic!("Error: Failed to write the reference: {}", e));
+}
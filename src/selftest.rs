@@ -0,0 +1,28 @@
+use super::config::Config;
+use super::testing::snapshot;
+
+/// Run every test embedded in the config, printing the result of each, and return the amount
+/// that failed.
+pub fn run_tests(config: &Config) -> usize {
+    let mut failures = 0;
+    for test in &config.tests {
+        let result = snapshot(config, &test.source);
+        if !result.diagnostics.is_empty() {
+            failures += 1;
+            println!("test {} ... FAILED", test.name);
+            for diagnostic in &result.diagnostics {
+                println!("  {}", diagnostic);
+            }
+        } else if result.segments != test.segments {
+            failures += 1;
+            println!("test {} ... FAILED\n  expected: {:?}\n  got:      {:?}",
+                     test.name,
+                     test.segments,
+                     result.segments);
+        } else {
+            println!("test {} ... ok", test.name);
+        }
+    }
+    println!("{} passed, {} failed", config.tests.len() - failures, failures);
+    failures
+}
@@ -0,0 +1,356 @@
+use super::config::{Config, Capture};
+use super::expr::evaluate_with_functions;
+use super::hooks::{ParseHook, RuleEvent};
+use super::parse::Parser;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+
+/// A rule which emitted words into the program segment while parsing.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub rule: usize,
+    /// The statement the rule matched, so a rule it was relaxed to can match it too.
+    pub text: String,
+    pub captures: Vec<Option<String>>,
+    pub file: String,
+    pub line: usize,
+}
+
+/// A parse hook which records every instruction for the simulator.
+pub struct Recorder {
+    program_segment: usize,
+    records: Rc<RefCell<Vec<Record>>>,
+}
+
+impl Recorder {
+    pub fn new(config: &Config, records: Rc<RefCell<Vec<Record>>>) -> Recorder {
+        Recorder {
+            program_segment: config.machine.as_ref().map_or(0, |m| m.program_segment),
+            records: records,
+        }
+    }
+}
+
+impl ParseHook for Recorder {
+    fn rule(&mut self, event: &RuleEvent) {
+        if !event.values[self.program_segment].is_empty() {
+            self.records.borrow_mut().push(Record {
+                rule: event.index,
+                text: event.text.to_string(),
+                captures: event.captures.iter().map(|c| c.map(str::to_string)).collect(),
+                file: event.file.to_string(),
+                line: event.line,
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Target {
+    Pc,
+    Register(String),
+    Memory(String),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Assign(Target, String),
+    Print(String),
+    Halt,
+}
+
+/// Parse semantics like `r($1) = r($2) + $3; pc = $4 if r($1)`.
+fn parse_semantics(semantics: &str) -> Result<Vec<(Statement, Option<String>)>, String> {
+    let mut statements = Vec::new();
+    for text in semantics.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let (body, condition) = match text.find(" if ") {
+            Some(i) => (text[..i].trim(), Some(text[i + 4..].trim().to_string())),
+            None => (text, None),
+        };
+        let statement = if body == "halt" {
+            Statement::Halt
+        } else if body.starts_with("print ") {
+            Statement::Print(body[6..].to_string())
+        } else {
+            let chars = body.char_indices().collect::<Vec<_>>();
+            let assignment = (0..chars.len()).find(|&i| {
+                chars[i].1 == '=' && chars.get(i + 1).map_or(true, |c| c.1 != '=') &&
+                (i == 0 || !"=!<>".contains(chars[i - 1].1))
+            });
+            let i = try!(assignment.map(|i| chars[i].0)
+                .ok_or_else(|| format!("\"{}\" is not an assignment, print, or halt", body)));
+            let target = body[..i].trim();
+            let inner = |prefix: &str| {
+                if target.starts_with(prefix) && target.ends_with(')') {
+                    Some(target[prefix.len()..target.len() - 1].to_string())
+                } else {
+                    None
+                }
+            };
+            let target = if target == "pc" {
+                Target::Pc
+            } else if let Some(index) = inner("r(") {
+                Target::Register(index)
+            } else if let Some(index) = inner("m(") {
+                Target::Memory(index)
+            } else {
+                return Err(format!("\"{}\" can't be assigned to", target));
+            };
+            Statement::Assign(target, body[i + 1..].trim().to_string())
+        };
+        statements.push((statement, condition));
+    }
+    Ok(statements)
+}
+
+#[derive(Clone, Debug)]
+struct Instruction {
+    rule: usize,
+    operands: Vec<i64>,
+    next: usize,
+    file: String,
+    line: usize,
+    semantics: Rc<Vec<(Statement, Option<String>)>>,
+}
+
+/// Runs assembled programs using the machine and rule semantics in the config.
+pub struct Simulator {
+    instructions: BTreeMap<usize, Instruction>,
+    pub registers: Vec<i64>,
+    pub memory: Vec<i64>,
+    pub pc: usize,
+    pub halted: bool,
+    pub steps: usize,
+    /// Values printed by instructions since this was last emptied.
+    pub output: Vec<i64>,
+}
+
+impl Simulator {
+    /// Build a simulator for a linked program from the instructions recorded while parsing,
+    /// placed where the linked source map says their words ended up.
+    pub fn new(config: &Config,
+               parser: &Parser,
+               records: Vec<Record>)
+               -> Result<Simulator, String> {
+        let machine = try!(config.machine
+            .as_ref()
+            .ok_or_else(|| String::from("the config has no machine to simulate")));
+        let segment = machine.program_segment;
+        if config.peepholes.iter().any(|p| p.segment == segment) {
+            return Err(String::from("peepholes rewrite the program segment, so the rewritten \
+                                     words have no semantics"));
+        }
+        // The records of each line in the order they were parsed, which is the order their
+        // words are in even if they were placed elsewhere.
+        let mut lines = HashMap::new();
+        for record in records {
+            lines.entry((record.file.clone(), record.line))
+                .or_insert_with(VecDeque::new)
+                .push_back(record);
+        }
+        let sources = parser.sources(segment);
+        let mut semantics = BTreeMap::new();
+        let mut instructions = BTreeMap::new();
+        let mut position = 0;
+        while position < sources.len() {
+            let source = sources[position];
+            let next = position + 1 +
+                       sources[position + 1..]
+                .iter()
+                .take_while(|s| !s.start && s.file == source.file && s.line == source.line)
+                .count();
+            let start = position;
+            position = next;
+            let index = match source.rule {
+                Some(index) if source.start => index,
+                _ => continue,
+            };
+            let record = match lines.get_mut(&(parser.file_name(source.file).to_string(),
+                                               source.line))
+                .and_then(|records| records.pop_front()) {
+                Some(record) => record,
+                None => continue,
+            };
+            let rule = &config.rules[index];
+            if !semantics.contains_key(&index) {
+                let parsed = try!(parse_semantics(rule.semantics.as_ref().map_or("", |s| &s[..]))
+                    .map_err(|e| format!("rule \"{}\" has invalid semantics: {}", rule.name(), e)));
+                semantics.insert(index, Rc::new(parsed));
+            }
+            // A relaxed instruction has the captures of the rule it was relaxed to.
+            let captures = if index == record.rule {
+                record.captures.clone()
+            } else {
+                let groups = try!(config.rule_groups(rule, &record.text).map_err(|_| {
+                    format!("rule \"{}\" doesn't match \"{}\" on line {}, which was relaxed \
+                             to it",
+                            rule.name(),
+                            record.text,
+                            record.line)
+                }));
+                (1..groups.len())
+                    .map(|i| groups.at(i).or_else(|| rule.capture_default(i - 1)).map(String::from))
+                    .collect()
+            };
+            let mut operands = Vec::new();
+            for (capture, text) in rule.captures.iter().zip(&captures) {
+                // A capture which wasn't given adds nothing to the words, like a value of 0.
+                let text = match *text {
+                    Some(ref text) => text,
+                    None => {
+                        operands.push(0);
+                        continue;
+                    }
+                };
+                operands.push(match *capture {
+                    Capture::Num { base, .. } => {
+                        try!(i64::from_str_radix(text, base).map_err(|e| {
+                            format!("invalid number \"{}\" on line {}: {}", text, record.line, e)
+                        }))
+                    }
+                    Capture::Tag { .. } => {
+                        try!(parser.tag_positions(text)
+                            .map(|p| p[segment] as i64)
+                            .ok_or_else(|| {
+                                format!("tag \"{}\" on line {} can't be simulated (only global \
+                                         and qualified tags can)",
+                                        text,
+                                        record.line)
+                            }))
+                    }
+                    _ => 0,
+                });
+            }
+            instructions.insert(start,
+                                Instruction {
+                                    rule: index,
+                                    operands: operands,
+                                    next: next,
+                                    file: record.file,
+                                    line: record.line,
+                                    semantics: semantics[&index].clone(),
+                                });
+        }
+        let mut memory = machine.memory_segment
+            .map_or_else(Vec::new,
+                         |s| parser.segments()[s].iter().map(|&w| w as i64).collect());
+        let memory_size = machine.memory_size.unwrap_or(memory.len());
+        memory.resize(memory_size, 0);
+        let pc = parser.entry_address(segment)
+            .map_or(0, |a| a as usize / config.segment_widths[segment]);
+        Ok(Simulator {
+            instructions: instructions,
+            registers: vec![0; machine.registers],
+            memory: memory,
+            pc: pc,
+            halted: false,
+            steps: 0,
+            output: Vec::new(),
+        })
+    }
+
+    /// The line of the source of the instruction at a position.
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.instructions.get(&pc).map(|i| i.line)
+    }
+
+    /// The input file of the instruction at a position.
+    pub fn file(&self, pc: usize) -> Option<&str> {
+        self.instructions.get(&pc).map(|i| &i.file[..])
+    }
+
+    /// The positions of every instruction from a line of an input file.
+    pub fn positions_of_line(&self, file: &str, line: usize) -> Vec<usize> {
+        self.instructions
+            .iter()
+            .filter(|&(_, i)| i.file == file && i.line == line)
+            .map(|(&pc, _)| pc)
+            .collect()
+    }
+
+    /// The index of the rule of the instruction at a position.
+    pub fn rule(&self, pc: usize) -> Option<usize> {
+        self.instructions.get(&pc).map(|i| i.rule)
+    }
+
+    fn eval(&self, expression: &str, instruction: &Instruction) -> Result<i64, String> {
+        let lookup = |symbol: &str| {
+            if symbol.starts_with('$') {
+                symbol[1..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| i.checked_sub(1))
+                    .and_then(|i| instruction.operands.get(i).cloned())
+            } else if symbol == "pc" {
+                Some(self.pc as i64)
+            } else if symbol == "next" {
+                Some(instruction.next as i64)
+            } else {
+                None
+            }
+        };
+        let call = |name: &str, args: &[i64]| {
+            let storage = match name {
+                "r" => &self.registers,
+                "m" => &self.memory,
+                _ => return None,
+            };
+            Some(if args.len() != 1 {
+                Err(format!("{}() takes one index", name))
+            } else {
+                storage.get(args[0] as usize)
+                    .cloned()
+                    .ok_or_else(|| format!("{}({}) is out of range", name, args[0]))
+            })
+        };
+        evaluate_with_functions(expression, &lookup, &call)
+    }
+
+    /// Run the instruction at the program counter.
+    pub fn step(&mut self) -> Result<(), String> {
+        if self.halted {
+            return Ok(());
+        }
+        let instruction = try!(self.instructions
+            .get(&self.pc)
+            .cloned()
+            .ok_or_else(|| format!("there is no instruction at position {}", self.pc)));
+        let mut pc = instruction.next;
+        for &(ref statement, ref condition) in instruction.semantics.iter() {
+            if let Some(ref condition) = *condition {
+                if try!(self.eval(condition, &instruction)) == 0 {
+                    continue;
+                }
+            }
+            match *statement {
+                Statement::Halt => self.halted = true,
+                Statement::Print(ref expression) => {
+                    let value = try!(self.eval(expression, &instruction));
+                    self.output.push(value);
+                }
+                Statement::Assign(ref target, ref expression) => {
+                    let value = try!(self.eval(expression, &instruction));
+                    match *target {
+                        Target::Pc => pc = value as usize,
+                        Target::Register(ref index) => {
+                            let index = try!(self.eval(index, &instruction)) as usize;
+                            *try!(self.registers
+                                .get_mut(index)
+                                .ok_or_else(|| format!("r({}) is out of range", index))) = value;
+                        }
+                        Target::Memory(ref index) => {
+                            let index = try!(self.eval(index, &instruction)) as usize;
+                            *try!(self.memory
+                                .get_mut(index)
+                                .ok_or_else(|| format!("m({}) is out of range", index))) = value;
+                        }
+                    }
+                }
+            }
+        }
+        self.pc = pc;
+        self.steps += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use super::parse::OutputFormat;
+use byteorder::{ByteOrder, LittleEndian, BigEndian};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Writes words of each segment to their output files while parsing so that whole segments
+/// don't have to be kept in memory. Words that were already written are patched in place when
+/// linking.
+pub struct Stream {
+    format: OutputFormat,
+    files: Vec<File>,
+    widths: Vec<usize>,
+    /// The amount of words of each segment that have been written.
+    pub flushed: Vec<usize>,
+    /// The amount of words a segment buffers before they are written.
+    pub threshold: usize,
+}
+
+impl Stream {
+    pub fn new(format: OutputFormat,
+               files: Vec<File>,
+               widths: Vec<usize>,
+               threshold: usize)
+               -> Stream {
+        match format {
+            OutputFormat::LittleEndian | OutputFormat::BigEndian | OutputFormat::HexList => {}
+            _ => {
+                panic!("Error: Only the little-endian, big-endian, and hex-list formats can be \
+                        streamed.")
+            }
+        }
+        let flushed = vec![0; files.len()];
+        Stream {
+            format: format,
+            files: files,
+            widths: widths,
+            flushed: flushed,
+            threshold: threshold,
+        }
+    }
+
+    /// The amount of bytes a word of a segment takes in the output file.
+    fn word_size(&self, segment: usize) -> usize {
+        match self.format {
+            OutputFormat::HexList => self.widths[segment] * 2 + 1,
+            _ => self.widths[segment],
+        }
+    }
+
+    fn encode(&self, segment: usize, val: u64) -> Vec<u8> {
+        let width = self.widths[segment];
+        let mut bytes = [0u8; 8];
+        match self.format {
+            OutputFormat::LittleEndian => {
+                LittleEndian::write_u64(&mut bytes, val);
+                bytes[0..width].to_vec()
+            }
+            OutputFormat::BigEndian => {
+                BigEndian::write_u64(&mut bytes, val);
+                bytes[(8 - width)..8].to_vec()
+            }
+            _ => {
+                BigEndian::write_u64(&mut bytes, val);
+                let mut line = bytes[(8 - width)..8]
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>();
+                line.push('\n');
+                line.into_bytes()
+            }
+        }
+    }
+
+    fn decode(&self, segment: usize, data: &[u8]) -> u64 {
+        let width = self.widths[segment];
+        match self.format {
+            OutputFormat::LittleEndian => LittleEndian::read_uint(data, width),
+            OutputFormat::BigEndian => BigEndian::read_uint(data, width),
+            _ => {
+                let digits = String::from_utf8_lossy(&data[..width * 2]).into_owned();
+                u64::from_str_radix(&digits, 16).unwrap_or_else(|e| {
+                    panic!("Error: Streamed output of segment {} is corrupted: {}",
+                           segment,
+                           e)
+                })
+            }
+        }
+    }
+
+    /// Write words to the end of a segment's file.
+    pub fn write(&mut self, segment: usize, words: &[u64]) {
+        let data = words.iter().flat_map(|&val| self.encode(segment, val)).collect::<Vec<_>>();
+        self.files[segment].write_all(&data).unwrap_or_else(|e| {
+            panic!("Error: Writing to output file for segment {} failed: {}",
+                   segment,
+                   e);
+        });
+        self.flushed[segment] += words.len();
+    }
+
+    /// Add a value to a word which was already written.
+    pub fn add(&mut self, segment: usize, index: usize, value: u64) {
+        self.patch(segment, index, |word| word.wrapping_add(value));
+    }
+
+    /// Replace a word which was already written with a function of it.
+    pub fn patch<F>(&mut self, segment: usize, index: usize, f: F)
+        where F: FnOnce(u64) -> u64
+    {
+        let size = self.word_size(segment);
+        let position = (index * size) as u64;
+        let mut data = vec![0u8; size];
+        self.files[segment]
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| self.files[segment].read_exact(&mut data))
+            .unwrap_or_else(|e| {
+                panic!("Error: Reading back output file for segment {} failed: {}",
+                       segment,
+                       e);
+            });
+        let patched = self.encode(segment, f(self.decode(segment, &data)));
+        self.files[segment]
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| self.files[segment].write_all(&patched))
+            .and_then(|_| self.files[segment].seek(SeekFrom::End(0)))
+            .unwrap_or_else(|e| {
+                panic!("Error: Patching output file for segment {} failed: {}",
+                       segment,
+                       e);
+            });
+    }
+}
@@ -0,0 +1,56 @@
+//! Support for golden tests of architecture configs.
+//!
+//! ```ignore
+//! let config = Config::new_from_str(include_str!("u0-32.json"));
+//! assert_eq!(snapshot(&config, "WORD_BITS\n").to_string(),
+//!            include_str!("word_bits.snap"));
+//! ```
+
+use super::config::Config;
+use super::parse::{Parser, Diagnostic};
+use std::fmt;
+
+/// The result of assembling and linking a source, which can be compared against an expected
+/// result or rendered to text for a golden file.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The words of every segment (empty if assembly failed).
+    pub segments: Vec<Vec<u64>>,
+    /// The errors that stopped assembly.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for Snapshot {
+    /// Renders each segment as lines of hex words followed by the diagnostics.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, segment) in self.segments.iter().enumerate() {
+            try!(writeln!(f, "segment {}:", index));
+            for word in segment {
+                try!(writeln!(f, "    {:x}", word));
+            }
+        }
+        for diagnostic in &self.diagnostics {
+            try!(writeln!(f, "{}", diagnostic));
+        }
+        Ok(())
+    }
+}
+
+/// Assemble and link a source with a config.
+pub fn snapshot(config: &Config, source: &str) -> Snapshot {
+    let mut parser = Parser::new(config);
+    match parser.try_parse_str(source).and_then(|_| parser.try_link()) {
+        Ok(()) => {
+            Snapshot {
+                segments: parser.segments().to_vec(),
+                diagnostics: Vec::new(),
+            }
+        }
+        Err(diagnostics) => {
+            Snapshot {
+                segments: Vec::new(),
+                diagnostics: diagnostics,
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+extern crate uasm;
+
+use uasm::config::Config;
+use uasm::parse::Parser;
+
+/// A config with 32-bit words where `j:TAG` emits 0x100 plus the position of a tag.
+const CONFIG: &'static str = r#"{
+    "segment_widths": [4],
+    "split_whitespace": true,
+    "tag_creates": [{"regex_string": "^([-+]+)$"}],
+    "rules": [
+        {"regex_string": "^nop$", "segment_values": [[0]], "captures": []},
+        {"regex_string": "^j:(.*)$", "segment_values": [[256]], "captures": [
+            {"Tag": {"feedbacks": [{"from_segment": 0, "add_segment": 0, "add_index": 0}]}}
+        ]}
+    ]
+}"#;
+
+fn assemble(config: &Config, source: &str) -> Vec<u64> {
+    let mut parser = Parser::new(config);
+    parser.parse(source.as_bytes());
+    parser.link();
+    parser.segments()[0].clone()
+}
+
+#[test]
+fn references_resolve_to_the_nearest_label() {
+    let config = Config::new_from_str(CONFIG);
+    let source = "-\nj:-\n-\nj:-\nj:+\nj:++\n+\nj:-\n++\nj:+\n+\nnop\n";
+    assert_eq!(assemble(&config, source),
+               vec![0x100, 0x101, 0x104, 0x105, 0x101, 0x106, 0]);
+}
+
+#[test]
+fn labels_of_different_depths_are_separate() {
+    let config = Config::new_from_str(CONFIG);
+    let source = "--\nnop\n-\nj:--\nj:-\nj:++\nj:+\n+\nnop\n++\nnop\n";
+    assert_eq!(assemble(&config, source),
+               vec![0, 0x100, 0x101, 0x106, 0x105, 0, 0]);
+}
+
+#[test]
+fn references_resolve_across_a_merged_input() {
+    let config = Config::new_from_str(CONFIG);
+    let mut parser = Parser::new(&config);
+    parser.parse("nop\n-\nnop\nj:+\n".as_bytes());
+    let mut other = Parser::new(&config);
+    other.parse("+\nj:-\nj:+\n+\nnop\n".as_bytes());
+    parser.merge(other.into_parsed());
+    parser.link();
+    assert_eq!(parser.segments()[0], vec![0, 0, 0x103, 0x101, 0x105, 0]);
+}
@@ -0,0 +1,45 @@
+extern crate uasm;
+extern crate serde_json;
+
+use uasm::config::Config;
+use uasm::migrate::migrate_config;
+use serde_json::Value;
+
+/// A config written for the first version of the layout, which named the tag rules
+/// `tag_creates`.
+const OLD_CONFIG: &'static str = r#"{
+    "segment_widths": [4],
+    "split_whitespace": true,
+    "tag_creates": [{"regex_string": "^:(.*)$"}],
+    "rules": [{"regex_string": "^nop$", "segment_values": [[0]], "captures": []}]
+}"#;
+
+#[test]
+fn old_names_are_read_in_older_versions() {
+    let config = Config::new_from_str(OLD_CONFIG);
+    assert_eq!(config.config_version, 1);
+    assert_eq!(config.tag_rules.len(), 1);
+}
+
+#[test]
+fn old_names_are_rejected_in_the_version_replacing_them() {
+    let json = OLD_CONFIG.replace("\"segment_widths\"",
+                                  "\"config_version\": 2, \"segment_widths\"");
+    assert_eq!(Config::try_from_str(&json).err().unwrap(),
+               "Error: Invalid config: field \"tag_creates\" was replaced by \"tag_rules\" in \
+                version 2 of the config layout.");
+}
+
+#[test]
+fn migrating_renames_fields_and_pins_built_in_behavior() {
+    let mut json: Value = serde_json::from_str(OLD_CONFIG).unwrap();
+    let changes = migrate_config(&mut json).unwrap();
+    assert_eq!(changes,
+               vec!["renamed \"tag_creates\" to \"tag_rules\"",
+                    "set \"line_comments\" to the built-in [\"#\"]",
+                    "set \"config_version\" to 2"]);
+    let config = Config::new_from_str(&serde_json::to_string(&json).unwrap());
+    assert_eq!(config.config_version, 2);
+    assert_eq!(config.tag_rules.len(), 1);
+    assert_eq!(migrate_config(&mut json).unwrap(), Vec::<String>::new());
+}
@@ -0,0 +1,264 @@
+extern crate uasm;
+
+use uasm::config::Config;
+use uasm::parse::Parser;
+use uasm::testing::snapshot;
+
+/// A 16-bit instruction set using most of what rules can do. Jumps relax from an 8-bit relative
+/// offset to an absolute word, `ldi` and `add` are laid out as bit fields, and `push x; pop x` is
+/// removed by a peephole.
+const CONFIG: &'static str = r##"{
+    "name": "golden16",
+    "version": "1.0",
+    "segment_widths": [2],
+    "split_whitespace": false,
+    "line_comments": [";"],
+    "tag_rules": [{"regex_string": "^([a-z_]+):$"}],
+    "directives": [
+        {"regex_string": "^\\.set ([a-z_]+), (.*)$", "directive": "Set"},
+        {"regex_string": "^\\.fill ([^,]*), (.*)$", "directive": "Repeat"},
+        {"regex_string": "^\\.space ([^,]*)(?:, (.*))?$", "directive": "Space"},
+        {"regex_string": "^\\.assert ([^,]*)(?:, \"(.*)\")?$", "directive": "Assert"}
+    ],
+    "productions": {"register": "[0-7]"},
+    "operands": {
+        "source": [
+            {"regex_string": "#([0-9]+)", "segment_values": [[256]], "captures": [
+                {"Num": {"base": 10, "feedbacks": [{"width": 8}]}}]},
+            {"regex_string": "r([0-7])", "captures": [
+                {"Num": {"base": 10, "feedbacks": [{}]}}]},
+            {"regex_string": "all", "segment_values": [[65535]], "overflow": "Error"}
+        ]
+    },
+    "peepholes": [{
+        "segment": 0,
+        "pattern": [{"value": 12288, "mask": 61440}, {"value": 16384, "mask": 61440, "same_as": 0}],
+        "replacement": []
+    }],
+    "rules": [
+        {"name": "nop", "regex_string": "^nop$", "segment_values": [[0]], "captures": []},
+        {"name": "halt", "regex_string": "^halt$", "segment_values": [[1]], "captures": []},
+        {"name": "jmp", "regex_string": "^jmp ([a-z_]+)$", "segment_values": [[4096]],
+         "captures": [{"Tag": {"feedbacks": [
+             {"from_segment": 0, "add_segment": 0, "add_index": 0, "relative": true,
+              "width": 8}]}}],
+         "relax": {"rule": "jmpl", "min": -4, "max": 3}},
+        {"name": "jmpl", "regex_string": "^jmp ([a-z_]+)$", "segment_values": [[8192, 0]],
+         "captures": [{"Tag": {"feedbacks": [
+             {"from_segment": 0, "add_segment": 0, "add_index": 1}]}}]},
+        {"name": "push", "regex_string": "^push r([0-7])$", "segment_values": [[12288]],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{}]}}]},
+        {"name": "pop", "regex_string": "^pop r([0-7])$", "segment_values": [[16384]],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{}]}}]},
+        {"name": "ldi", "regex_string": "^ldi r([0-7]), ([a-z0-9_-]+)$", "segment_values": [[0]],
+         "layout": [{"fields": "op[15:12]=5, rd[11:9], imm[8:0]"}],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{"field": "rd"}]}},
+                      {"Num": {"base": 10, "feedbacks": [{"field": "imm"}]}}]},
+        {"name": "add", "grammar": "\"add\" _ \"r\" {register} _ \",\" _ \"r\" {register}",
+         "segment_values": [[0]],
+         "layout": [{"fields": "op[15:12]=6, rd[11:9], rs[8:6]"}],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{"field": "rd"}]}},
+                      {"Num": {"base": 10, "feedbacks": [{"field": "rs"}]}}]},
+        {"name": "mov", "regex_string": "^mov (.*)$", "segment_values": [[28672]],
+         "captures": [{"Operand": {"name": "source"}}]},
+        {"name": "scaled", "regex_string": "^scaled ([0-9]+)$", "segment_values": [[0]],
+         "captures": [{"Num": {"base": 10, "feedbacks": []}}],
+         "computed": [{"expression": "$1 * 3 + (1 << 4)", "segment": 0, "index": 0}]},
+        {"name": "here", "regex_string": "^here$", "segment_values": [[36864]], "captures": [],
+         "self_references": [{"from_segment": 0, "add_segment": 0, "add_index": 0, "width": 8,
+                              "overflow": "Error"}]},
+        {"name": "aligned", "regex_string": "^aligned$", "segment_values": [[40960]],
+         "captures": [], "alignments": [{"segment": 0, "words": 4}]}
+    ]
+}"##;
+
+fn check(source: &str, golden: &str) {
+    let config = Config::new_from_str(CONFIG);
+    assert_eq!(snapshot(&config, source).to_string(), golden);
+}
+
+#[test]
+fn jumps_relax_when_their_target_is_out_of_range() {
+    check("start:\njmp near\nnop\nnear:\njmp far\n.space 6\nfar:\njmp start\nhalt\n",
+          include_str!("golden/relaxation.snap"));
+}
+
+#[test]
+fn relaxation_bounds_must_be_in_order() {
+    let config = CONFIG.replace("\"min\": -4, \"max\": 3", "\"min\": 3, \"max\": -4");
+    assert_eq!(Config::try_from_str(&config).err().unwrap(),
+               "Error: Rule \"^jmp ([a-z_]+)$\" relaxes with a minimum 3 greater than its maximum \
+                -4.");
+}
+
+#[test]
+fn unreferenced_tags_are_removed_with_their_words() {
+    let config = Config::new_from_str(CONFIG);
+    let mut parser = Parser::new(&config);
+    parser.try_parse_str("jmp main\nunused:\nalias:\nnop\nnop\nmain:\nhalt\n").unwrap();
+    assert_eq!(parser.remove_unreferenced(0, &[]).unwrap(),
+               vec![(String::from("alias"), 2), (String::from("unused"), 2)]);
+    parser.try_link().unwrap();
+    assert_eq!(parser.segments()[0], vec![0x1001, 1]);
+}
+
+#[test]
+fn peepholes_remove_words_and_move_tags() {
+    check("push r2\npop r2\npush r1\npop r3\njmp end\nend:\nhalt\n",
+          include_str!("golden/peepholes.snap"));
+}
+
+#[test]
+fn bit_fields_are_set_by_captures() {
+    check("ldi r3, 511\nldi r7, -1\nadd r1, r2\nadd  r4 ,  r7\n",
+          include_str!("golden/bit_fields.snap"));
+}
+
+#[test]
+fn bit_fields_reject_values_which_dont_fit() {
+    check("ldi r1, 512\n", include_str!("golden/bit_field_overflow.snap"));
+}
+
+#[test]
+fn expressions_are_evaluated_in_directives_and_computed_values() {
+    check(".set count, 2\n.fill count + 1, 0x10 | 2\nscaled 5\nend:\n.assert end == 4\n",
+          include_str!("golden/expressions.snap"));
+}
+
+#[test]
+fn failed_assertions_are_reported() {
+    check("nop\nend:\n.assert end == 2, \"too short\"\n",
+          include_str!("golden/assertion.snap"));
+}
+
+#[test]
+fn operands_add_their_values_to_the_rule() {
+    check("mov #5\nmov r3\n", include_str!("golden/operands.snap"));
+}
+
+#[test]
+fn operand_values_which_overflow_the_word_are_reported() {
+    check("mov all\n", include_str!("golden/operand_overflow.snap"));
+}
+
+#[test]
+fn self_references_report_positions_which_dont_fit() {
+    let mut source = String::from("here\n");
+    for _ in 0..255 {
+        source.push_str("nop\n");
+    }
+    source.push_str("here\n");
+    check(&source, include_str!("golden/self_reference_overflow.snap"));
+}
+
+#[test]
+fn aligned_rules_are_padded() {
+    check("nop\naligned\nhalt\n", include_str!("golden/alignment.snap"));
+}
+
+#[test]
+fn alignment_padding_belongs_to_no_rule() {
+    let config = Config::new_from_str(CONFIG);
+    let mut parser = Parser::new(&config);
+    parser.try_parse_str("nop\naligned\n").unwrap();
+    let sources = parser.sources(0);
+    assert_eq!(sources.len(), 5);
+    for padding in &sources[1..4] {
+        assert_eq!((padding.rule, padding.start), (None, false));
+    }
+    assert_eq!((sources[4].rule, sources[4].start), (Some(11), true));
+}
+
+/// A config with 64-bit words, whose bit fields and shifts can be as wide as the words.
+const WIDE_CONFIG: &'static str = r##"{
+    "segment_widths": [8],
+    "split_whitespace": false,
+    "tag_rules": [],
+    "rules": [
+        {"regex_string": "^wide (-?[0-9]+)$", "segment_values": [[0]],
+         "layout": [{"fields": "flag[63:63]=1, value[62:0]"}],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{"field": "value"}]}}]},
+        {"regex_string": "^out ([0-9]+)$", "segment_values": [[1, 2]],
+         "captures": [{"Num": {"base": 10, "feedbacks": [
+             {"index": 0, "shift": 64}, {"index": 1, "shift": -64}]}}]}
+    ]
+}"##;
+
+#[test]
+fn bit_fields_can_hold_63_bits() {
+    let config = Config::new_from_str(WIDE_CONFIG);
+    let source = "wide 4611686018427387904\nwide -1\nwide 9223372036854775807\n";
+    assert_eq!(snapshot(&config, source).to_string(),
+               include_str!("golden/wide_fields.snap"));
+}
+
+#[test]
+fn values_shifted_by_the_word_size_or_more_are_shifted_out() {
+    let config = Config::new_from_str(WIDE_CONFIG);
+    assert_eq!(snapshot(&config, "out 5\n").to_string(),
+               include_str!("golden/wide_shifts.snap"));
+}
+
+/// A config with a segment split into regions and one split into banks of 4 words seen at 0x10.
+const REGIONS_CONFIG: &'static str = r##"{
+    "segment_widths": [1, 1],
+    "segments": [
+        {"name": "rom", "fill": 255, "regions": [
+            {"name": "vectors", "start": 0, "size": 2},
+            {"name": "main", "start": 4}
+        ]},
+        {"name": "paged", "banks": {"size": 4, "window": 16}}
+    ],
+    "split_whitespace": false,
+    "tag_rules": [{"regex_string": "^([a-z_]+):$"}],
+    "directives": [
+        {"regex_string": "^\\.region ([a-z]+)$", "directive": "Region"},
+        {"regex_string": "^\\.bank (.*)$", "directive": "Bank", "segment": 1}
+    ],
+    "rules": [
+        {"regex_string": "^byte ([0-9]+)$", "segment_values": [[0], []], "captures": [
+            {"Num": {"base": 10, "feedbacks": [{}]}}]},
+        {"regex_string": "^data ([0-9]+)$", "segment_values": [[], [0]], "captures": [
+            {"Num": {"base": 10, "feedbacks": [{"segment": 1}]}}]},
+        {"regex_string": "^far ([a-z_]+)$", "segment_values": [[], [128, 0]], "captures": [
+            {"Tag": {"feedbacks": [
+                {"from_segment": 1, "add_segment": 1, "add_index": 0, "bank": "Bank"},
+                {"from_segment": 1, "add_segment": 1, "add_index": 1, "bank": "Far"}]}}]},
+        {"regex_string": "^near ([a-z_]+)$", "segment_values": [[], [64]], "captures": [
+            {"Tag": {"feedbacks": [
+                {"from_segment": 1, "add_segment": 1, "add_index": 0, "bank": "Near"}]}}]}
+    ]
+}"##;
+
+#[test]
+fn regions_are_placed_at_their_start() {
+    let config = Config::new_from_str(REGIONS_CONFIG);
+    let source = ".region main\nbyte 1\n.region vectors\nbyte 2\n.region main\nbyte 3\n";
+    assert_eq!(snapshot(&config, source).to_string(),
+               include_str!("golden/regions.snap"));
+}
+
+#[test]
+fn regions_reject_words_past_their_size() {
+    let config = Config::new_from_str(REGIONS_CONFIG);
+    let source = ".region vectors\nbyte 1\nbyte 2\nbyte 3\n";
+    assert_eq!(snapshot(&config, source).to_string(),
+               include_str!("golden/region_overflow.snap"));
+}
+
+#[test]
+fn banks_resolve_tags_to_their_bank_and_window_address() {
+    let config = Config::new_from_str(REGIONS_CONFIG);
+    let source = ".bank 0\nfar target\nnear local\nlocal:\ndata 7\n\
+                  .bank 2\ndata 8\ntarget:\ndata 9\n";
+    assert_eq!(snapshot(&config, source).to_string(),
+               include_str!("golden/banks.snap"));
+}
+
+#[test]
+fn near_references_reject_tags_in_other_banks() {
+    let config = Config::new_from_str(REGIONS_CONFIG);
+    let source = ".bank 0\nnear target\n.bank 1\ntarget:\ndata 9\n";
+    assert_eq!(snapshot(&config, source).to_string(),
+               include_str!("golden/near_bank.snap"));
+}
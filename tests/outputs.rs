@@ -0,0 +1,150 @@
+extern crate uasm;
+
+use uasm::config::Config;
+use uasm::parse::{OutputFormat, Parser, SymbolFormat, XrefOrder};
+use uasm::stream::Stream;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Read;
+
+/// A config with 16-bit code loaded at 0x100 and byte data loaded at 0x2000, where `jmp` refers
+/// to a tag which may come later.
+const CONFIG: &'static str = r##"{
+    "name": "outputs",
+    "version": "2.1",
+    "segment_widths": [2, 1],
+    "segments": [
+        {"name": "code", "base_address": 256},
+        {"name": "data", "base_address": 8192}
+    ],
+    "split_whitespace": false,
+    "entry": "start",
+    "tag_rules": [{"regex_string": "^([a-z_]+):$"}],
+    "rules": [
+        {"regex_string": "^nop$", "segment_values": [[0], []], "captures": []},
+        {"regex_string": "^jmp ([a-z_]+)$", "segment_values": [[4096], []], "captures": [
+            {"Tag": {"feedbacks": [{"from_segment": 0, "add_segment": 0, "add_index": 0}]}}]},
+        {"regex_string": "^byte ([0-9]+)$", "segment_values": [[], [0]], "captures": [
+            {"Num": {"base": 10, "feedbacks": [{"segment": 1}]}}]}
+    ]
+}"##;
+
+const SOURCE: &'static str = "nop\nstart:\njmp end\nbyte 17\nnop\nend:\njmp start\nbyte 34\n";
+
+fn assemble(config: &Config) -> Parser {
+    let mut parser = Parser::new(config);
+    parser.try_parse_str(SOURCE).unwrap();
+    parser.try_link().unwrap();
+    parser
+}
+
+fn output(parser: &Parser, format: OutputFormat, segment: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    parser.output(format, segment, &mut bytes);
+    bytes
+}
+
+fn text<F: Fn(&mut Vec<u8>)>(write: F) -> String {
+    let mut bytes = Vec::new();
+    write(&mut bytes);
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn intel_hex_and_s_records() {
+    let config = Config::new_from_str(CONFIG);
+    let parser = assemble(&config);
+    assert_eq!(String::from_utf8(output(&parser, OutputFormat::IntelHex, 0)).unwrap(),
+               include_str!("golden/code.hex"));
+    assert_eq!(String::from_utf8(output(&parser, OutputFormat::SRecord, 0)).unwrap(),
+               include_str!("golden/code.srec"));
+}
+
+#[test]
+fn combined_images_place_segments_at_their_base_address() {
+    let config = Config::new_from_str(CONFIG);
+    let parser = assemble(&config);
+    assert_eq!(text(|w| parser.output_combined(OutputFormat::IntelHex, w)),
+               include_str!("golden/combined.hex"));
+    assert_eq!(text(|w| parser.output_combined(OutputFormat::SRecord, w)),
+               include_str!("golden/combined.srec"));
+}
+
+#[test]
+fn elf_files_load_at_the_base_address() {
+    let config = Config::new_from_str(CONFIG);
+    let parser = assemble(&config);
+    let elf = output(&parser, OutputFormat::Elf, 0);
+    let word = |offset: usize| {
+        elf[offset..offset + 4].iter().fold(0, |word, &byte| word << 8 | byte as u32)
+    };
+    assert_eq!(&elf[..6], &[0x7F, b'E', b'L', b'F', 1, 2]);
+    // The entry point and the virtual and physical addresses of the one program header.
+    assert_eq!(word(24), 0x102);
+    assert_eq!(word(52 + 8), 0x100);
+    assert_eq!(word(52 + 12), 0x100);
+    // The words follow the program header.
+    assert_eq!(&elf[84..92], &[0, 0, 0x10, 3, 0, 0, 0x10, 1]);
+}
+
+#[test]
+fn symbols_are_listed_at_their_byte_address() {
+    let config = Config::new_from_str(CONFIG);
+    let parser = assemble(&config);
+    assert_eq!(text(|w| parser.write_symbols(SymbolFormat::Vice, 0, w)),
+               include_str!("golden/symbols.vice"));
+    assert_eq!(text(|w| parser.write_symbols(SymbolFormat::Mame, 1, w)),
+               include_str!("golden/symbols.mame"));
+}
+
+#[test]
+fn cross_references_list_definitions_and_uses() {
+    let config = Config::new_from_str(CONFIG);
+    let parser = assemble(&config);
+    assert_eq!(text(|w| parser.write_xref(XrefOrder::Address, 0, w)),
+               include_str!("golden/xref.txt"));
+}
+
+#[test]
+fn streamed_outputs_match_outputs_kept_in_memory() {
+    let config = Config::new_from_str(CONFIG);
+    let expected = assemble(&config);
+    let names = (0..2)
+        .map(|segment| env::temp_dir().join(format!("uasm-stream-test-{}.bin", segment)))
+        .collect::<Vec<_>>();
+    let files = names.iter()
+        .map(|name| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(name)
+                .unwrap()
+        })
+        .collect();
+    let mut parser = Parser::new(&config);
+    // A threshold of one word writes the jump before its tag is defined, so it is patched.
+    parser.stream_to(Stream::new(OutputFormat::BigEndian, files, vec![2, 1], 1));
+    parser.try_parse_str(SOURCE).unwrap();
+    parser.try_link().unwrap();
+    parser.finish_stream();
+    for (segment, name) in names.iter().enumerate() {
+        let mut streamed = Vec::new();
+        fs::File::open(name).unwrap().read_to_end(&mut streamed).unwrap();
+        fs::remove_file(name).unwrap();
+        assert_eq!(streamed, output(&expected, OutputFormat::BigEndian, segment));
+    }
+}
+
+#[test]
+#[should_panic(expected = "Mirrored segments can't be written in streaming mode")]
+fn mirrored_segments_cant_be_streamed() {
+    let config = Config::new_from_str(&CONFIG.replace("\"base_address\": 8192",
+                                                      "\"base_address\": 8192, \"mirrors\": 2"));
+    let name = env::temp_dir().join("uasm-stream-test-mirrored.bin");
+    let file = OpenOptions::new().read(true).write(true).create(true).open(&name).unwrap();
+    fs::remove_file(&name).unwrap();
+    let mut parser = Parser::new(&config);
+    parser.stream_to(Stream::new(OutputFormat::BigEndian, vec![file], vec![2], 1));
+}
@@ -0,0 +1,57 @@
+extern crate uasm;
+
+use uasm::config::Config;
+use uasm::parse::Parser;
+use uasm::sim::{Recorder, Simulator};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A machine whose short jump sets register 1 and whose long jump (which it relaxes to) sets
+/// register 0 to the position after it, so the output shows which one ran and where it was.
+const CONFIG: &'static str = r##"{
+    "segment_widths": [2],
+    "split_whitespace": false,
+    "tag_rules": [{"regex_string": "^([a-z]+):$"}],
+    "directives": [{"regex_string": "^\\.space ([^,]*)(?:,(.*))?$", "directive": "Space"}],
+    "machine": {"registers": 2},
+    "rules": [
+        {"name": "jmp", "regex_string": "^jmp ([a-z]+)$", "segment_values": [[256]],
+         "captures": [{"Tag": {"feedbacks": [
+             {"from_segment": 0, "add_segment": 0, "add_index": 0, "relative": true}]}}],
+         "relax": {"rule": "jmpl", "min": -4, "max": 4}, "semantics": "r(1) = 99; pc = $1"},
+        {"name": "jmpl", "regex_string": "^jmp ([a-z]+)$", "segment_values": [[512, 0]],
+         "captures": [{"Tag": {"feedbacks": [
+             {"from_segment": 0, "add_segment": 0, "add_index": 1}]}}],
+         "semantics": "r(0) = next; pc = $1"},
+        {"name": "print", "regex_string": "^print ([0-9]+)$", "segment_values": [[768]],
+         "captures": [{"Num": {"base": 10, "feedbacks": [{}]}}],
+         "semantics": "print $1; print r(0); print r(1)"},
+        {"name": "halt", "regex_string": "^halt$", "segment_values": [[1]], "captures": [],
+         "semantics": "halt"}
+    ]
+}"##;
+
+fn run(source: &str) -> Vec<i64> {
+    let config = Config::new_from_str(CONFIG);
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let mut parser = Parser::new(&config);
+    parser.add_hook(Box::new(Recorder::new(&config, records.clone())));
+    parser.try_parse_str(source).unwrap();
+    parser.try_link().unwrap();
+    let records = records.borrow().clone();
+    let mut simulator = Simulator::new(&config, &parser, records).unwrap();
+    while !simulator.halted {
+        simulator.step().unwrap();
+    }
+    simulator.output
+}
+
+#[test]
+fn short_jumps_run_their_own_semantics() {
+    assert_eq!(run("jmp far\n.space 2\nfar:\nprint 7\nhalt\n"), vec![7, 0, 99]);
+}
+
+#[test]
+fn relaxed_jumps_run_the_long_rule_at_its_linked_position() {
+    assert_eq!(run("jmp far\n.space 8\nfar:\nprint 7\nhalt\n"), vec![7, 2, 0]);
+}
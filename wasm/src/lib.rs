@@ -0,0 +1,91 @@
+//! The assembler compiled to WebAssembly, for assembling in memory from JavaScript (see
+//! `js/uasm.js`). This is its own crate so only it is built as a cdylib.
+extern crate uasm;
+extern crate serde_json;
+
+use uasm::config::Config;
+use uasm::testing::{snapshot, Snapshot};
+use uasm::parse::Diagnostic;
+use serde_json::to_vec;
+use std::cell::RefCell;
+use std::mem;
+use std::slice;
+use std::str;
+
+thread_local! {
+    // The JSON of the last result, which stays until the next call.
+    static RESULT: RefCell<Vec<u8>> = RefCell::new(Vec::new())
+}
+
+fn set_result(result: &Snapshot) {
+    let json = to_vec(result).unwrap();
+    RESULT.with(|r| *r.borrow_mut() = json);
+}
+
+fn error(message: String) -> Snapshot {
+    Snapshot {
+        segments: Vec::new(),
+        diagnostics: vec![Diagnostic {
+                              line: 0,
+                              message: message,
+                          }],
+    }
+}
+
+unsafe fn input<'b>(ptr: *const u8, len: usize) -> Result<&'b str, String> {
+    str::from_utf8(slice::from_raw_parts(ptr, len))
+        .map_err(|e| format!("Error: Input is not valid UTF-8: {}", e))
+}
+
+unsafe fn assemble(config: *const u8,
+                   config_len: usize,
+                   source: *const u8,
+                   source_len: usize)
+                   -> Result<Snapshot, String> {
+    let config = try!(Config::try_from_str(try!(input(config, config_len))));
+    Ok(snapshot(&config, try!(input(source, source_len))))
+}
+
+/// Allocate a buffer in the module's memory for passing in a config or source.
+#[no_mangle]
+pub extern "C" fn uasm_alloc(len: usize) -> *mut u8 {
+    let mut buffer = Vec::with_capacity(len);
+    let ptr = buffer.as_mut_ptr();
+    mem::forget(buffer);
+    ptr
+}
+
+/// Free a buffer from `uasm_alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn uasm_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// Assemble a source with a JSON config, both UTF-8 in memory. The result is left as JSON with
+/// the segments and diagnostics for `uasm_result_ptr` and `uasm_result_len`, and whether the
+/// source assembled without diagnostics is returned.
+///
+/// Invalid inputs, configs and sources are all reported in the result. A trap is a bug in the
+/// assembler, after which the module must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn uasm_assemble(config: *const u8,
+                                       config_len: usize,
+                                       source: *const u8,
+                                       source_len: usize)
+                                       -> bool {
+    let result = assemble(config, config_len, source, source_len).unwrap_or_else(error);
+    set_result(&result);
+    result.diagnostics.is_empty()
+}
+
+/// The location of the last result.
+#[no_mangle]
+pub extern "C" fn uasm_result_ptr() -> *const u8 {
+    RESULT.with(|r| r.borrow().as_ptr())
+}
+
+/// The length of the last result in bytes.
+#[no_mangle]
+pub extern "C" fn uasm_result_len() -> usize {
+    RESULT.with(|r| r.borrow().len())
+}